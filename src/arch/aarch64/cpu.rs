@@ -1,11 +1,15 @@
 use crate::{
-    arch::{mm::new_s2_memory_set, sysreg::write_sysreg},
+    arch::{
+        mm::new_s2_memory_set,
+        sysreg::{read_sysreg, write_sysreg},
+    },
     consts::{PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
+    device::irqchip::gicv3::gicv3_vcpu_init,
     memory::{
         addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
         MemoryRegion, VirtAddr, PARKING_INST_PAGE,
     },
-    percpu::this_cpu_data,
+    percpu::{this_cpu_data, CpuSet},
 };
 use aarch64_cpu::registers::{
     Readable, Writeable, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2, VTCR_EL2,
@@ -61,9 +65,21 @@ impl ArchCpu {
         regs.clear();
         regs.usr[0] = dtb as _; // dtb addr
         self.reset_vm_regs();
+        write_sysreg!(VMPIDR_EL2, self.vmpidr());
+        gicv3_vcpu_init();
         self.activate_vmm();
     }
 
+    // The guest sees its zone-relative vcpu id as affinity, the physical one on a cpu
+    // without a zone.
+    fn vmpidr(&self) -> u64 {
+        let vcpu_id = this_cpu_data()
+            .zone
+            .as_ref()
+            .and_then(|zone| cpuid_to_vcpuid(&zone.read().cpu_set, self.cpuid));
+        cpuid_to_mpidr(vcpu_id.unwrap_or(self.cpuid))
+    }
+
     fn activate_vmm(&self) {
         VTCR_EL2.write(
             VTCR_EL2::TG0::Granule4KB
@@ -148,6 +164,12 @@ impl ArchCpu {
         this_cpu_data().activate_gpm();
         self.reset(this_cpu_data().cpu_on_entry, this_cpu_data().dtb_ipa);
         self.psci_on = true;
+        debug!(
+            "cpu {} runs its vcpu, mpidr {:#x}, vmpidr {:#x}",
+            self.cpuid,
+            read_mpidr(),
+            read_vmpidr()
+        );
         unsafe {
             vmreturn(self.guest_reg() as *mut _ as usize);
         }
@@ -185,12 +207,49 @@ impl ArchCpu {
     }
 }
 
+const MPIDR_AFF_MASK: u64 = 0xff00ffffff;
+const MPIDR_MT: u64 = 1 << 24;
+const MPIDR_RES1: u64 = 1 << 31;
+
 pub fn mpidr_to_cpuid(mpidr: u64) -> u64 {
-    mpidr & 0xff00ffffff
+    mpidr & MPIDR_AFF_MASK
+}
+
+/// MPIDR of the cpu with id `cpu_id`, as in the cpu nodes of a device tree: the affinity, the
+/// MT bit of this system and RES1. Vcpus get the same format with their vcpu id, see
+/// `cpuid_to_vcpuid`.
+pub fn cpuid_to_mpidr(cpu_id: usize) -> u64 {
+    (cpu_id as u64 & MPIDR_AFF_MASK) | (MPIDR_EL1.get() & MPIDR_MT) | MPIDR_RES1
+}
+
+/// MPIDR of this cpu in the format of `cpuid_to_mpidr`.
+pub fn read_mpidr() -> u64 {
+    cpuid_to_mpidr(this_cpu_id())
+}
+
+/// MPIDR_EL1 as the guest on this cpu reads it.
+pub fn read_vmpidr() -> u64 {
+    read_sysreg!(VMPIDR_EL2)
+}
+
+/// Id of the vcpu on physical cpu `cpu_id`: vcpus of a zone are numbered from 0 in the order
+/// of the cpus in its `cpu_set`, so vcpu 0 of every zone has affinity 0.
+pub fn cpuid_to_vcpuid(cpu_set: &CpuSet, cpu_id: usize) -> Option<usize> {
+    cpu_set.iter().position(|cpu| cpu == cpu_id)
+}
+
+/// Physical cpu running vcpu `vcpu_id` of the zone on `cpu_set`.
+pub fn vcpuid_to_cpuid(cpu_set: &CpuSet, vcpu_id: usize) -> Option<usize> {
+    cpu_set.iter().nth(vcpu_id)
 }
 
 pub fn this_cpu_id() -> usize {
-    mpidr_to_cpuid(MPIDR_EL1.get()) as _
+    // a test picks the cpu it runs on in the mocked MPIDR_EL1
+    #[cfg(test)]
+    let mpidr = read_sysreg!(mpidr_el1);
+    #[cfg(not(test))]
+    let mpidr = MPIDR_EL1.get();
+    mpidr_to_cpuid(mpidr) as _
 }
 
 pub unsafe fn enable_mmu() {
@@ -5,7 +5,7 @@ use crate::consts::PER_CPU_SIZE;
 global_asm!(include_str!("boot_pt.S"));
 
 #[naked]
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 #[link_section = ".text.entry"]
 pub unsafe extern "C" fn arch_entry() -> i32 {
     unsafe {
@@ -40,7 +40,7 @@ pub unsafe extern "C" fn arch_entry() -> i32 {
 }
 
 #[naked]
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 #[link_section = ".trampoline"]
 pub unsafe extern "C" fn shutdown_el2(_gu_regs: usize) -> i32 {
     core::arch::asm!(
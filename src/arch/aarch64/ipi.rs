@@ -1,13 +1,113 @@
-use super::sysreg::write_sysreg;
+use super::{
+    cpu::{this_cpu_id, vcpuid_to_cpuid},
+    sysreg::write_sysreg,
+};
+use crate::{consts::MAX_CPU_NUM, device::irqchip::gicv3::hv_ipi_fiq, percpu::CpuSet};
+
+const SGIR_TARGET_LIST_MASK: u64 = 0xffff;
+const SGIR_AFF1_SHIFT: u64 = 16;
+const SGIR_INTID_SHIFT: u64 = 24;
+const SGIR_AFF2_SHIFT: u64 = 32;
+const SGIR_IRM: u64 = 1 << 40;
+const SGIR_RS_SHIFT: u64 = 44;
+const SGIR_AFF3_SHIFT: u64 = 48;
+
+/// Affinity fields of `cpu_id` as in MPIDR_EL1, see `mpidr_to_cpuid`.
+pub fn cpuid_to_affinity(cpu_id: usize) -> (u64, u64, u64, u64) {
+    let id = cpu_id as u64;
+    (id >> 32 & 0xff, id >> 16 & 0xff, id >> 8 & 0xff, id & 0xff)
+}
+
+// ICC_SGI1R_EL1 targeting the cpus in `target_list` of the 16 cpus with Aff0 from `rs * 16`
+// in cluster `aff3.aff2.aff1`.
+fn sgir_value(sgi_id: u8, (aff3, aff2, aff1): (u64, u64, u64), rs: u64, target_list: u64) -> u64 {
+    aff3 << SGIR_AFF3_SHIFT
+        | rs << SGIR_RS_SHIFT
+        | aff2 << SGIR_AFF2_SHIFT
+        | (sgi_id as u64 & 0xf) << SGIR_INTID_SHIFT
+        | aff1 << SGIR_AFF1_SHIFT
+        | target_list & SGIR_TARGET_LIST_MASK
+}
+
+/// Physical cpus a guest write of ICC_SGI1R_EL1 (or SGI0R, ASGI1R) `value` targets. Its
+/// affinities are vcpu ids of the zone on `cpu_set`, the ones which aren't are dropped. IRM
+/// targets all cpus of the zone but this one.
+pub fn sgir_targets(value: u64, cpu_set: &CpuSet) -> CpuSet {
+    let mut targets = CpuSet::new(MAX_CPU_NUM, 0);
+    if value & SGIR_IRM != 0 {
+        cpu_set
+            .iter_except(this_cpu_id())
+            .for_each(|cpu| targets.set_bit(cpu));
+        return targets;
+    }
+    let cluster = (value >> SGIR_AFF3_SHIFT & 0xff) << 32
+        | (value >> SGIR_AFF2_SHIFT & 0xff) << 16
+        | (value >> SGIR_AFF1_SHIFT & 0xff) << 8;
+    let first_aff0 = (value >> SGIR_RS_SHIFT & 0xf) * 16;
+    for bit in (0..16).filter(|bit| value & SGIR_TARGET_LIST_MASK & 1 << bit != 0) {
+        let vcpu_id = (cluster | (first_aff0 + bit)) as usize;
+        if let Some(cpu) = vcpuid_to_cpuid(cpu_set, vcpu_id) {
+            targets.set_bit(cpu);
+        }
+    }
+    targets
+}
+
+fn write_sgi1r(value: u64) {
+    write_sysreg!(icc_sgi1r_el1, value);
+}
+
+fn write_sgi0r(value: u64) {
+    write_sysreg!(icc_sgi0r_el1, value);
+}
+
+/// Send group 1 SGI `sgi_id` to the cpus in `targets`. All cpus but this one are reached in
+/// one broadcast, otherwise there is one write for each group of 16 cpus sharing a cluster.
+pub fn send_sgi(sgi_id: u8, targets: CpuSet) {
+    send_sgi_with(write_sgi1r, sgi_id, targets);
+}
+
+/// Like `send_sgi`, for a group 0 SGI.
+pub fn send_sgi0(sgi_id: u8, targets: CpuSet) {
+    send_sgi_with(write_sgi0r, sgi_id, targets);
+}
+
+fn send_sgi_with(write_sgir: fn(u64), sgi_id: u8, targets: CpuSet) {
+    let this_cpu = this_cpu_id();
+    let is_broadcast = (0..MAX_CPU_NUM).all(|cpu| targets.contains_cpu(cpu) != (cpu == this_cpu));
+    if is_broadcast {
+        write_sgir(SGIR_IRM | (sgi_id as u64 & 0xf) << SGIR_INTID_SHIFT);
+        return;
+    }
+
+    // targets are ordered by cpu id, so cpus of one write come one after another
+    let mut pending: Option<((u64, u64, u64), u64, u64)> = None;
+    for cpu in targets.iter() {
+        let (aff3, aff2, aff1, aff0) = cpuid_to_affinity(cpu);
+        let (cluster, rs) = ((aff3, aff2, aff1), aff0 / 16);
+        match &mut pending {
+            Some((c, r, list)) if *c == cluster && *r == rs => *list |= 1 << (aff0 % 16),
+            _ => {
+                if let Some((c, r, list)) = pending {
+                    write_sgir(sgir_value(sgi_id, c, r, list));
+                }
+                pending = Some((cluster, rs, 1 << (aff0 % 16)));
+            }
+        }
+    }
+    if let Some((c, r, list)) = pending {
+        write_sgir(sgir_value(sgi_id, c, r, list));
+    }
+}
 
 pub fn arch_send_event(cpu_id: u64, sgi_num: u64) {
-    let aff3: u64 = 0 << 48;
-    let aff2: u64 = 0 << 32;
-    let aff1: u64 = 0 << 16;
-    let irm: u64 = 0 << 40;
-    let sgi_id: u64 = sgi_num << 24;
-    let target_list: u64 = 1 << cpu_id;
-    let val: u64 = aff1 | aff2 | aff3 | irm | sgi_id | target_list;
-    write_sysreg!(icc_sgi1r_el1, val);
-    debug!("write sgi sys value = {:#x}", val);
+    let mut targets = CpuSet::new(MAX_CPU_NUM, 0);
+    targets.set_bit(cpu_id as _);
+    // the hypervisor IPI is a group 0 irq with hv_ipi_fiq
+    if hv_ipi_fiq() {
+        send_sgi0(sgi_num as _, targets);
+    } else {
+        send_sgi(sgi_num as _, targets);
+    }
+    debug!("send sgi {} to cpu {}", sgi_num, cpu_id);
 }
@@ -122,6 +122,15 @@ pub fn setup_parange() {
     wait_for(|| PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) < MAX_CPU_NUM as _);
 }
 
+/// `setup_parange` of all cpus of a test machine at once, for the physical address size of the
+/// mocked ID_AA64MMFR0_EL1.
+#[cfg(test)]
+pub fn setup_parange_for_tests() {
+    let mut p = MIN_PARANGE.write();
+    *p = p.min(read_sysreg!(id_aa64mmfr0_el1) & 0xf);
+    PARANGE_OK_CPUS.store(MAX_CPU_NUM as _, core::sync::atomic::Ordering::SeqCst);
+}
+
 pub fn get_parange() -> u64 {
     assert!(PARANGE_OK_CPUS.load(core::sync::atomic::Ordering::SeqCst) == MAX_CPU_NUM as _);
     *MIN_PARANGE.read()
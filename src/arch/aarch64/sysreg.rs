@@ -6,6 +6,7 @@
 /// use crate::arch::sysreg::write_sysreg;
 /// unsafe {write_sysreg!(icc_sgi1r_el1, val);}
 /// let intid = unsafe { read_sysreg!(icc_iar1_el1) } as u32;
+#[cfg(not(any(test, feature = "sysreg_mock")))]
 macro_rules! read_sysreg {
     ($name:ident) => {
         {
@@ -19,9 +20,16 @@ macro_rules! read_sysreg {
         }
     }
 }
+#[cfg(any(test, feature = "sysreg_mock"))]
+macro_rules! read_sysreg {
+    ($name:ident) => {
+        $crate::arch::sysreg::mock::read(::core::stringify!($name))
+    };
+}
 pub(crate) use read_sysreg;
 
 /// Writes the given value to the given aarch64 system register.
+#[cfg(not(any(test, feature = "sysreg_mock")))]
 macro_rules! write_sysreg {
     ($name:ident, $value:expr) => {
         {
@@ -34,8 +42,134 @@ macro_rules! write_sysreg {
         }
     }
 }
+#[cfg(any(test, feature = "sysreg_mock"))]
+macro_rules! write_sysreg {
+    ($name:ident, $value:expr) => {
+        $crate::arch::sysreg::mock::write(::core::stringify!($name), $value)
+    };
+}
 pub(crate) use write_sysreg;
 
+/// Register file the sysreg macros use instead of the hardware with the `sysreg_mock` feature
+/// and in the unit tests, so the GIC code can run without a GIC. Registers never written read
+/// as 0. There are no side effects, e.g. reading ICC_IAR1_EL1 doesn't acknowledge anything.
+///
+/// The hypervisor's cpus share one register file. Under `cargo test`, which runs the tests on
+/// threads of an aarch64 Linux host (`--target aarch64-unknown-linux-gnu`, e.g. under
+/// qemu-user), each thread has its own and also logs the writes, see `writes`.
+#[cfg(any(test, feature = "sysreg_mock"))]
+pub mod mock {
+    use alloc::vec::Vec;
+
+    #[derive(Default)]
+    struct RegFile {
+        values: Vec<(&'static str, u64)>,
+        #[cfg(test)]
+        writes: Vec<(&'static str, u64)>,
+    }
+
+    #[cfg(not(test))]
+    static REGS: spin::Mutex<RegFile> = spin::Mutex::new(RegFile { values: Vec::new() });
+
+    #[cfg(not(test))]
+    fn with_regs<R>(f: impl FnOnce(&mut RegFile) -> R) -> R {
+        f(&mut REGS.lock())
+    }
+
+    #[cfg(test)]
+    std::thread_local! {
+        static REGS: core::cell::RefCell<RegFile> = Default::default();
+    }
+
+    #[cfg(test)]
+    fn with_regs<R>(f: impl FnOnce(&mut RegFile) -> R) -> R {
+        REGS.with(|regs| f(&mut regs.borrow_mut()))
+    }
+
+    /// Register names are matched regardless of case, like the assembler does.
+    pub fn read(name: &'static str) -> u64 {
+        with_regs(|regs| {
+            regs.values
+                .iter()
+                .find(|(reg, _)| reg.eq_ignore_ascii_case(name))
+                .map_or(0, |&(_, value)| value)
+        })
+    }
+
+    pub fn write(name: &'static str, value: u64) {
+        with_regs(|regs| {
+            #[cfg(test)]
+            regs.writes.push((name, value));
+            match regs.values.iter_mut().find(|(reg, _)| reg.eq_ignore_ascii_case(name)) {
+                Some((_, reg_value)) => *reg_value = value,
+                None => regs.values.push((name, value)),
+            }
+        })
+    }
+
+    /// Forget every register value, and the logged writes.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn reset() {
+        with_regs(|regs| *regs = RegFile::default());
+    }
+
+    /// The writes since the last `reset`, oldest first, with the register names as written
+    /// at the call site.
+    #[cfg(test)]
+    pub fn writes() -> Vec<(&'static str, u64)> {
+        with_regs(|regs| regs.writes.clone())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn unwritten_registers_read_zero() {
+            reset();
+            assert_eq!(read("ich_hcr_el2"), 0);
+        }
+
+        #[test]
+        fn names_match_regardless_of_case() {
+            reset();
+            write("ICH_VMCR_EL2", 0x1234);
+            assert_eq!(read("ich_vmcr_el2"), 0x1234);
+            write("ich_vmcr_el2", 0x5678);
+            assert_eq!(read("ICH_VMCR_EL2"), 0x5678);
+        }
+
+        #[test]
+        fn writes_are_logged_in_order() {
+            reset();
+            crate::arch::sysreg::write_sysreg!(ich_hcr_el2, 1);
+            crate::arch::sysreg::write_sysreg!(ich_lr0_el2, 2);
+            crate::arch::sysreg::write_sysreg!(ich_hcr_el2, 0);
+            assert_eq!(
+                writes(),
+                [("ich_hcr_el2", 1), ("ich_lr0_el2", 2), ("ich_hcr_el2", 0)]
+            );
+            assert_eq!(crate::arch::sysreg::read_sysreg!(ich_lr0_el2), 2);
+            reset();
+            assert!(writes().is_empty());
+            assert_eq!(read("ich_lr0_el2"), 0);
+        }
+
+        #[test]
+        fn threads_have_their_own_registers() {
+            reset();
+            write("icc_pmr_el1", 0xf0);
+            std::thread::spawn(|| {
+                assert_eq!(read("icc_pmr_el1"), 0);
+                write("icc_pmr_el1", 0x80);
+            })
+            .join()
+            .unwrap();
+            assert_eq!(read("icc_pmr_el1"), 0xf0);
+        }
+    }
+}
+
 macro_rules! smc_arg1 {
     ($value:expr) => {{
         let mut v: i64 = $value;
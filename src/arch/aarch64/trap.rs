@@ -3,16 +3,24 @@ use core::arch::global_asm;
 
 use crate::{
     arch::{
-        cpu::mpidr_to_cpuid,
+        cpu::{mpidr_to_cpuid, vcpuid_to_cpuid},
+        ipi::{send_sgi, sgir_targets},
         sysreg::{read_sysreg, write_sysreg},
     },
-    device::irqchip::gicv3::gicv3_handle_irq_el1,
+    device::irqchip::gicv3::{
+        cpu_online, gicv3_handle_fiq_el1, gicv3_handle_irq_el1, inject_sgi0, read_vapr, read_vbpr,
+        read_vctlr, read_vgrpen, read_vhppir, read_vpmr, read_vrpr, virtual_acknowledge_group,
+        virtual_acknowledge_nmi, virtual_deactivate, virtual_eoi, watchdog, write_vapr, write_vbpr,
+        write_vctlr, write_vgrpen, write_vpmr, IrqGroup, GUEST_ICC_SRE,
+    },
     event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
     hypercall::{HyperCall, SGI_IPI_ID},
     memory::{mmio_handle_access, MMIOAccess},
     percpu::{get_cpu_data, this_cpu_data, this_zone, PerCpu},
     zone::{is_this_root_zone, remove_zone},
 };
+#[cfg(feature = "gicd_write_combine")]
+use crate::device::irqchip::gicv3::gicd_wc;
 
 use super::cpu::GeneralRegisters;
 
@@ -29,6 +37,7 @@ pub mod ExceptionType {
     pub const EXIT_REASON_EL2_IRQ: u64 = 0x1;
     pub const EXIT_REASON_EL1_ABORT: u64 = 0x2;
     pub const EXIT_REASON_EL1_IRQ: u64 = 0x3;
+    pub const EXIT_REASON_EL1_FIQ: u64 = 0x4;
 }
 const SMC_TYPE_MASK: u64 = 0x3F000000;
 #[allow(non_snake_case)]
@@ -37,8 +46,116 @@ pub mod SmcType {
     pub const STANDARD_SC: u64 = 0x4000000;
 }
 
+// ESR_EL2.ISS of a trapped MSR/MRS, without Rt and the direction
+const fn sysreg_iss(op0: u64, op1: u64, crn: u64, crm: u64, op2: u64) -> u64 {
+    op0 << 20 | op2 << 17 | op1 << 14 | crn << 10 | crm << 1
+}
+const SYSREG_ISS_MASK: u64 = sysreg_iss(0b11, 0b111, 0xf, 0xf, 0b111);
+const ICC_SGI1R_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 11, 5);
+const ICC_ASGI1R_EL1_ISS: u64 = sysreg_iss(3, 1, 12, 11, 6);
+const ICC_SGI0R_EL1_ISS: u64 = sysreg_iss(3, 2, 12, 11, 7);
+const ICC_PMR_EL1_ISS: u64 = sysreg_iss(3, 0, 4, 6, 0);
+const ICC_RPR_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 11, 3);
+const ICC_SRE_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 5);
+const ICC_CTLR_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 4);
+const ICC_DIR_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 11, 1);
+const ICC_IAR0_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 8, 0);
+const ICC_EOIR0_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 8, 1);
+const ICC_HPPIR0_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 8, 2);
+const ICC_BPR0_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 8, 3);
+const ICC_IGRPEN0_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 6);
+const ICC_IAR1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 0);
+const ICC_NMIAR1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 9, 5);
+const ICC_EOIR1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 1);
+const ICC_HPPIR1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 2);
+const ICC_BPR1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 3);
+const ICC_IGRPEN1_EL1_ISS: u64 = sysreg_iss(3, 0, 12, 12, 7);
+
+// ICC_AP0R<n>_EL1 are op2 4 to 7 of CRm 8, ICC_AP1R<n>_EL1 op2 0 to 3 of CRm 9
+const fn icc_apr_iss(group: IrqGroup, n: u64) -> u64 {
+    match group {
+        IrqGroup::Group0 => sysreg_iss(3, 0, 12, 8, 4 + n),
+        IrqGroup::Group1 => sysreg_iss(3, 0, 12, 9, n),
+    }
+}
+
+// The ICC_* registers of one group, trapped with ICH_HCR_EL2.TALL0 or TALL1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupReg {
+    Iar,
+    /// ICC_NMIAR1_EL1, group 1 only.
+    Nmiar,
+    Eoir,
+    Hppir,
+    Bpr,
+    Apr(usize),
+    Igrpen,
+}
+
+// The group register the ISS `sysreg` encodes, along with its group.
+fn group_reg(sysreg: u64) -> Option<(IrqGroup, GroupReg)> {
+    let reg = match sysreg {
+        ICC_IAR0_EL1_ISS => (IrqGroup::Group0, GroupReg::Iar),
+        ICC_EOIR0_EL1_ISS => (IrqGroup::Group0, GroupReg::Eoir),
+        ICC_HPPIR0_EL1_ISS => (IrqGroup::Group0, GroupReg::Hppir),
+        ICC_BPR0_EL1_ISS => (IrqGroup::Group0, GroupReg::Bpr),
+        ICC_IGRPEN0_EL1_ISS => (IrqGroup::Group0, GroupReg::Igrpen),
+        ICC_IAR1_EL1_ISS => (IrqGroup::Group1, GroupReg::Iar),
+        ICC_NMIAR1_EL1_ISS => (IrqGroup::Group1, GroupReg::Nmiar),
+        ICC_EOIR1_EL1_ISS => (IrqGroup::Group1, GroupReg::Eoir),
+        ICC_HPPIR1_EL1_ISS => (IrqGroup::Group1, GroupReg::Hppir),
+        ICC_BPR1_EL1_ISS => (IrqGroup::Group1, GroupReg::Bpr),
+        ICC_IGRPEN1_EL1_ISS => (IrqGroup::Group1, GroupReg::Igrpen),
+        _ => {
+            let group = |i| [IrqGroup::Group0, IrqGroup::Group1][i / 4];
+            let n = (0..8).find(|&i| sysreg == icc_apr_iss(group(i), i as u64 % 4))?;
+            (group(n), GroupReg::Apr(n % 4))
+        }
+    };
+    Some(reg)
+}
+
+// Emulate a guest access of the group register `reg` of `group` from the virtual interface of
+// this cpu, writes of `val`. Returns what a read gets, writes to read-only registers are
+// ignored and reads of write-only ones get 0.
+fn access_group_reg(group: IrqGroup, reg: GroupReg, is_read: bool, val: u64) -> u64 {
+    let cpu = this_cpu_data().id;
+    match (reg, is_read) {
+        (GroupReg::Iar, true) => virtual_acknowledge_group(cpu, group) as _,
+        (GroupReg::Nmiar, true) => virtual_acknowledge_nmi(cpu) as _,
+        (GroupReg::Eoir, false) => {
+            virtual_eoi((val & 0xff_ffff) as _, group);
+            0
+        }
+        (GroupReg::Hppir, true) => read_vhppir(group),
+        (GroupReg::Bpr, true) => read_vbpr(group),
+        (GroupReg::Bpr, false) => {
+            write_vbpr(group, val);
+            0
+        }
+        (GroupReg::Apr(n), true) => read_vapr(group, n),
+        (GroupReg::Apr(n), false) => {
+            write_vapr(group, n, val);
+            0
+        }
+        // the enables live in ICH_VMCR_EL2.VENG<n>
+        (GroupReg::Igrpen, true) => read_vgrpen(group) as _,
+        (GroupReg::Igrpen, false) => {
+            write_vgrpen(group, val & 1 != 0);
+            0
+        }
+        (reg, is_read) => {
+            let access = if is_read { "read" } else { "write" };
+            warn!("ignore guest {} of {:?} register {:?}", access, group, reg);
+            0
+        }
+    }
+}
+
 const PSCI_VERSION_1_1: u64 = 0x10001;
 const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
+const PSCI_INVALID_PARAMETERS: u64 = -2i64 as u64;
+const PSCI_INTERNAL_FAILURE: u64 = -6i64 as u64;
 const ARM_SMCCC_VERSION_1_0: u64 = 0x10000;
 
 extern "C" {
@@ -86,13 +203,22 @@ pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
     let mpidr = MPIDR_EL1.get();
     let _cpu_id = mpidr_to_cpuid(mpidr);
     trace!("cpu exit, exit_reson:{:#x?}", regs.exit_reason);
+    // only a data abort may be another buffered distributor write
+    #[cfg(feature = "gicd_write_combine")]
+    if regs.exit_reason != ExceptionType::EXIT_REASON_EL1_ABORT {
+        gicd_wc::flush();
+    }
     match regs.exit_reason as u64 {
         ExceptionType::EXIT_REASON_EL1_IRQ => irqchip_handle_irq1(),
         ExceptionType::EXIT_REASON_EL1_ABORT => arch_handle_trap_el1(regs),
         ExceptionType::EXIT_REASON_EL2_ABORT => arch_handle_trap_el2(regs),
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
+        ExceptionType::EXIT_REASON_EL1_FIQ => irqchip_handle_fiq1(),
         _ => arch_dump_exit(regs.exit_reason),
     }
+    #[cfg(feature = "gicd_write_combine")]
+    gicd_wc::exit_done();
+    watchdog::kick();
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
@@ -101,6 +227,11 @@ fn irqchip_handle_irq1() {
     gicv3_handle_irq_el1();
 }
 
+fn irqchip_handle_fiq1() {
+    trace!("fiq from el1");
+    gicv3_handle_fiq_el1();
+}
+
 fn irqchip_handle_irq2() {
     error!("irq not handle from el2");
     loop {}
@@ -186,10 +317,11 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
     let sas = iss >> 22 & 0x3;
 
     let size = 1 << sas;
-    let hpfar = read_sysreg!(HPFAR_EL2);
     let far = read_sysreg!(FAR_EL2);
-    let address = (far & 0xfff) | (hpfar << 8);
+    let address = dabt_fault_ipa(iss, far);
 
+    // MMIO handlers work on the data as it appears on the bus, in little-endian byte order
+    let big_endian = guest_data_big_endian();
     let mut mmio_access = MMIOAccess {
         address: address as _,
         size,
@@ -197,7 +329,7 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
         value: if srt == 31 {
             0
         } else {
-            regs.usr[srt as usize] as _
+            to_bus_order(regs.usr[srt as usize] as _, size, big_endian)
         },
     };
 
@@ -206,6 +338,7 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
     match mmio_handle_access(&mut mmio_access) {
         Ok(_) => {
             if !is_write && srt != 31 {
+                mmio_access.value = to_bus_order(mmio_access.value, size, big_endian);
                 if sse {
                     mmio_access.value =
                         ((mmio_access.value << (32 - 8 * size)) as i32) as usize >> (32 - 8 * size);
@@ -221,22 +354,124 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
     arch_skip_instruction(regs);
 }
 
+const SCTLR_EL1_E0E: u64 = 1 << 24;
+const SCTLR_EL1_EE: u64 = 1 << 25;
+const SPSR_EL2_M_EL0T: u64 = 0b0000;
+
+// Whether the guest's trapped data access was big-endian, from SCTLR_EL1.EE, or SCTLR_EL1.E0E
+// for accesses made at EL0.
+fn guest_data_big_endian() -> bool {
+    let sctlr = read_sysreg!(SCTLR_EL1);
+    if read_sysreg!(SPSR_EL2) & 0xf == SPSR_EL2_M_EL0T {
+        sctlr & SCTLR_EL1_E0E != 0
+    } else {
+        sctlr & SCTLR_EL1_EE != 0
+    }
+}
+
+// Swap the `size` bytes of register data from or to bus order, a no-op for little-endian guests.
+fn to_bus_order(value: usize, size: usize, big_endian: bool) -> usize {
+    if !big_endian {
+        return value;
+    }
+    match size {
+        2 => (value as u16).swap_bytes() as usize,
+        4 => (value as u32).swap_bytes() as usize,
+        8 => (value as u64).swap_bytes() as usize,
+        _ => value,
+    }
+}
+
+// DFSC of a permission fault, any level
+const DFSC_PERMISSION_FAULT: u64 = 0b00_1100;
+const DFSC_FAULT_TYPE_MASK: u64 = 0b11_1100;
+const ISS_S1PTW: u64 = 1 << 7;
+
+// HPFAR_EL2 isn't guaranteed to be valid for stage 2 permission faults, such as writes to the
+// read-only GICD pages of a zone. Those are translated from FAR_EL2 with the guest's stage 1.
+// The AT result lands in the guest's PAR_EL1, which is restored afterwards.
+fn dabt_fault_ipa(iss: u64, far: u64) -> u64 {
+    if iss & DFSC_FAULT_TYPE_MASK == DFSC_PERMISSION_FAULT && iss & ISS_S1PTW == 0 {
+        let guest_par = read_sysreg!(PAR_EL1);
+        unsafe {
+            core::arch::asm!("at s1e1r, {}", "isb", in(reg) far);
+        }
+        let par = read_sysreg!(PAR_EL1);
+        write_sysreg!(PAR_EL1, guest_par);
+        if par & 1 == 0 {
+            return (par & 0xffff_ffff_f000) | (far & 0xfff);
+        }
+        warn!("failed to translate fault address {:#x}, par {:#x}", far, par);
+    }
+    let hpfar = read_sysreg!(HPFAR_EL2);
+    (far & 0xfff) | (hpfar << 8)
+}
+
 fn handle_sysreg(regs: &mut GeneralRegisters) {
     //TODO check sysreg type
     //send sgi
     trace!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
-    let rt = (ESR_EL2.get() >> 5) & 0x1f;
-    let val = regs.usr[rt as usize];
+    let rt = ((ESR_EL2.get() >> 5) & 0x1f) as usize;
+    // Rt 31 is xzr
+    let val = if rt < 31 { regs.usr[rt] } else { 0 };
     trace!("esr_el2 rt{}: {:#x?}", rt, val);
     let sgi_id: u64 = (val & (0xf << 24)) >> 24;
-    if !this_cpu_data().arch_cpu.psci_on {
+    let sysreg = ESR_EL2.read(ESR_EL2::ISS) & SYSREG_ISS_MASK;
+    let is_read = ESR_EL2.get() & 1 != 0;
+    if sysreg == ICC_PMR_EL1_ISS {
+        // only trapped with ICH_HCR_EL2.TC, the guest's mask lives in ICH_VMCR_EL2.VPMR
+        if !is_read {
+            write_vpmr(val);
+        } else if rt < 31 {
+            regs.usr[rt] = read_vpmr();
+        }
+    } else if sysreg == ICC_RPR_EL1_ISS {
+        // trapped along with ICC_PMR_EL1
+        if is_read && rt < 31 {
+            regs.usr[rt] = read_vrpr();
+        }
+    } else if sysreg == ICC_SRE_EL1_ISS {
+        // writes are ignored, the guest must stay on system register access
+        if !is_read {
+            if val & GUEST_ICC_SRE != GUEST_ICC_SRE {
+                warn!("ignore guest icc_sre_el1 write {:#x}", val);
+            }
+        } else if rt < 31 {
+            regs.usr[rt] = GUEST_ICC_SRE;
+        }
+    } else if sysreg == ICC_CTLR_EL1_ISS {
+        // only trapped with ICH_HCR_EL2.TC, the guest's EOImode decides what its EOIs do
+        if !is_read {
+            write_vctlr(val);
+        } else if rt < 31 {
+            regs.usr[rt] = read_vctlr();
+        }
+    } else if sysreg == ICC_DIR_EL1_ISS {
+        virtual_deactivate((val & 0xff_ffff) as _);
+    } else if let Some((group, reg)) = group_reg(sysreg) {
+        // only trapped with ICH_HCR_EL2.TALL0/TALL1, see `Vgicv3::group_traps`
+        let read = access_group_reg(group, reg, is_read, val);
+        if is_read && rt < 31 {
+            regs.usr[rt] = read;
+        }
+    } else if !this_cpu_data().arch_cpu.psci_on {
         warn!("skip send sgi {:#x?}", sgi_id);
-    } else {
+    } else if sysreg == ICC_SGI1R_EL1_ISS || sysreg == ICC_ASGI1R_EL1_ISS {
+        // both generate group 1 SGIs from the guest's point of view
         trace!("send sgi {:#x?}", sgi_id);
-        write_sysreg!(icc_sgi1r_el1, val);
+        send_sgi(sgi_id as _, sgir_targets(val, &this_zone().read().cpu_set));
+    } else if sysreg == ICC_SGI0R_EL1_ISS {
+        // a physical group 0 SGI would go to the secure world, inject them directly
+        trace!("send group 0 sgi {:#x?}", sgi_id);
+        let targets = sgir_targets(val, &this_zone().read().cpu_set);
+        for cpu in targets.iter() {
+            inject_sgi0(cpu, sgi_id as _);
+        }
+    } else {
+        warn!("unhandled sysreg access, iss = {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
     }
 
-    arch_skip_instruction(regs); //skip sgi write
+    arch_skip_instruction(regs); //skip sysreg access
 }
 
 fn handle_hvc(regs: &mut GeneralRegisters) {
@@ -300,9 +535,20 @@ fn psci_emulate_features_info(code: u64) -> u64 {
     }
 }
 
+// Physical cpu of the vcpu with MPIDR `mpidr` in this zone.
+fn psci_target_cpu(mpidr: u64) -> Option<usize> {
+    vcpuid_to_cpuid(&this_zone().read().cpu_set, mpidr_to_cpuid(mpidr) as _)
+}
+
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
-    let cpu = mpidr_to_cpuid(regs.usr[1]);
+    let Some(cpu) = psci_target_cpu(regs.usr[1]) else {
+        warn!("psci: no vcpu with mpidr {:#x} to wake up", regs.usr[1]);
+        return PSCI_INVALID_PARAMETERS;
+    };
+    if !cpu_online(cpu) {
+        warn!("psci: cpu {} is offline, its gic init failed", cpu);
+        return PSCI_INTERNAL_FAILURE;
+    }
     info!("psci: try to wake up cpu {}", cpu);
 
     let target_data = get_cpu_data(cpu as _);
@@ -339,7 +585,10 @@ fn handle_psci_smc(
             todo!();
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.psci_on as _
+            match psci_target_cpu(arg0) {
+                Some(cpu) => !get_cpu_data(cpu).arch_cpu.psci_on as _,
+                None => PSCI_INVALID_PARAMETERS,
+            }
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
@@ -353,10 +602,12 @@ fn handle_psci_smc(
                 let target_cpu = get_cpu_data(cpu_id);
                 let _lock = target_cpu.ctrl_lock.lock();
                 target_cpu.zone = None;
+                target_cpu.zone_id = None;
                 send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
             }
 
             this_cpu_data().zone = None;
+            this_cpu_data().zone_id = None;
             drop(zone);
             remove_zone(zone_id);
 
@@ -412,7 +663,7 @@ fn arch_dump_exit(reason: u64) {
 }
 
 #[naked]
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn vmreturn(_gu_regs: usize) -> ! {
     core::arch::asm!(
         "
@@ -441,3 +692,32 @@ pub unsafe extern "C" fn vmreturn(_gu_regs: usize) -> ! {
         options(noreturn),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every register ICH_HCR_EL2.TALL0/TALL1 traps is one of the group registers: CRm 8 holds
+    // those of group 0, CRm 9 the group 1 active priorities and ICC_NMIAR1_EL1, CRm 12 the
+    // rest of group 1 along with ICC_IGRPEN0_EL1.
+    #[test]
+    fn all_registers_of_the_trapped_groups_are_emulated() {
+        let group = |crm, op2| group_reg(sysreg_iss(3, 0, 12, crm, op2)).map(|(group, _)| group);
+        for op2 in 0..8 {
+            assert_eq!(group(8, op2), Some(IrqGroup::Group0));
+        }
+        for op2 in [0, 1, 2, 3, 5] {
+            assert_eq!(group(9, op2), Some(IrqGroup::Group1));
+        }
+        for op2 in [0, 1, 2, 3, 7] {
+            assert_eq!(group(12, op2), Some(IrqGroup::Group1));
+        }
+        assert_eq!(group(12, 6), Some(IrqGroup::Group0));
+        assert_eq!(
+            group_reg(sysreg_iss(3, 0, 12, 8, 7)),
+            Some((IrqGroup::Group0, GroupReg::Apr(3)))
+        );
+        assert_eq!(group_reg(ICC_CTLR_EL1_ISS), None);
+        assert_eq!(group_reg(ICC_DIR_EL1_ISS), None);
+    }
+}
@@ -4,11 +4,13 @@ use alloc::vec::Vec;
 
 use crate::{
     config::*,
-    device::virtio_trampoline::{mmio_virtio_handler, VIRTIO_BRIDGE},
+    device::irqchip::gicv3::{audit::MAX_AUDIT_ENTRIES, is_ppi, is_spi},
     error::HvResult,
     memory::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion},
     zone::Zone,
 };
+#[cfg(feature = "virtio")]
+use crate::device::virtio_trampoline::{mmio_virtio_handler, VIRTIO_BRIDGE};
 
 impl Zone {
     pub fn pt_init(&mut self, mem_regions: &[HvConfigMemoryRegion]) -> HvResult {
@@ -28,6 +30,7 @@ impl Zone {
                         flags,
                     ))?
                 }
+                #[cfg(feature = "virtio")]
                 MEM_TYPE_VIRTIO => {
                     self.mmio_region_register(
                         mem_region.physical_start as _,
@@ -36,6 +39,10 @@ impl Zone {
                         mem_region.physical_start as _,
                     );
                 }
+                #[cfg(not(feature = "virtio"))]
+                MEM_TYPE_VIRTIO => {
+                    panic!("virtio memory region, but virtio support is not built in")
+                }
                 _ => {
                     panic!("Unsupported memory type: {}", mem_region.mem_type)
                 }
@@ -54,8 +61,110 @@ impl Zone {
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct HvArchZoneConfig {
+    /// Layout of the rest of the struct, must be `HvArchZoneConfig::VERSION`.
+    pub version: u32,
+    /// `size_of::<HvArchZoneConfig>()` as the config's producer built it.
+    pub size: u32,
     pub gicd_base: usize,
     pub gicr_base: usize,
     pub gicd_size: usize,
     pub gicr_size: usize,
+    /// Max number of virtual irqs pending or active in a vcpu's list registers before
+    /// further injections are deferred, 0 means no limit.
+    pub irq_depth_limit: usize,
+    /// Bitmap of the 4K pages of the GICD the zone may read directly, writes to them still
+    /// trap. Pages whose reads the hypervisor emulates are always fully trapped.
+    pub gicd_read_passthrough: usize,
+    /// Max number of times one SPI may fire within `irq_storm_window_us` before it is masked
+    /// for `irq_storm_cooldown_us`, 0 turns the storm breaker off.
+    pub irq_storm_limit: usize,
+    pub irq_storm_window_us: usize,
+    pub irq_storm_cooldown_us: usize,
+    /// Bitmap of the irqs injected as virtual-only irqs, without the LR HW bit. The hypervisor
+    /// deactivates the physical irq once the guest deactivated the virtual one.
+    pub hw_unmapped_irqs: [u32; 32],
+    /// Priority the zone's SPIs get when it is created, instead of the reset value 0, the
+    /// highest one. The guest may still change it.
+    pub default_spi_priority: u8,
+    /// Guest physical base of the zone's emulated ITS frames, 0 if it has none.
+    pub gits_base: usize,
+    /// A vcpu running this long without an exit gets `watchdog_irq` injected as a group 0
+    /// irq, 0 turns the watchdog off.
+    pub watchdog_timeout_us: usize,
+    pub watchdog_irq: usize,
+    /// Priority bits of the zone's virtual cpu interfaces, 5 to 8 clamped to what the hardware
+    /// implements, 0 for all of them.
+    pub virtual_pri_bits: usize,
+    /// Apply a guest's GICD_IPRIORITYR writes to the irqs already pending in list registers as
+    /// well. The architecture only has a new priority apply to later assertions, but some
+    /// guests raise the priority of a pending irq and expect it to take effect. Any value but
+    /// 0 turns it on.
+    pub update_pending_priority: usize,
+    /// Injections kept in the zone's irq audit ring for the guest to fetch, 0 turns the
+    /// audit off. At most `MAX_AUDIT_ENTRIES`.
+    pub irq_audit_entries: usize,
+    /// What happens to SPIs arriving for a vcpu which is off, see `OfflineIrqPolicy`: 0 holds
+    /// them pending, 1 drops them with a warning, 2 hands them to an online vcpu of the zone.
+    pub offline_irq_policy: usize,
+    /// Trap the guest's ICC_* registers of group 0 with bit 0 and those of group 1 with bit 1
+    /// (ICH_HCR_EL2.TALL0/TALL1), which the hypervisor emulates from the list registers. For
+    /// paravirtualized and nested guests whose acknowledges it has to see, at the cost of an
+    /// exit for each acknowledge and EOI.
+    pub trap_group_regs: usize,
+}
+
+impl HvArchZoneConfig {
+    /// Bumped whenever a field is added, removed or changes its meaning.
+    pub const VERSION: u32 = 3;
+
+    /// Rejects a config built against a different layout of this struct, whose fields would
+    /// otherwise be read from the wrong offsets.
+    pub fn validate(&self) -> HvResult {
+        if self.version != Self::VERSION || self.size as usize != core::mem::size_of::<Self>() {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "arch zone config version {} size {}, expected version {} size {}",
+                    self.version,
+                    self.size,
+                    Self::VERSION,
+                    core::mem::size_of::<Self>()
+                )
+            );
+        }
+        // a virtual cpu interface implements at least 5 priority bits
+        if self.virtual_pri_bits != 0 && !(5..=8).contains(&self.virtual_pri_bits) {
+            return hv_result_err!(
+                EINVAL,
+                format!("virtual_pri_bits {} not in 5..=8", self.virtual_pri_bits)
+            );
+        }
+        if self.irq_audit_entries > MAX_AUDIT_ENTRIES {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "irq_audit_entries {} above the limit of {}",
+                    self.irq_audit_entries, MAX_AUDIT_ENTRIES
+                )
+            );
+        }
+        if self.trap_group_regs & !0b11 != 0 {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "trap_group_regs {:#x} has bits beyond group 1",
+                    self.trap_group_regs
+                )
+            );
+        }
+        // SGIs are sent by vcpus, the watchdog irq is a PPI or SPI the guest has a handler for
+        let irq = self.watchdog_irq as u32;
+        if self.watchdog_timeout_us != 0 && !is_ppi(irq) && !is_spi(irq) {
+            return hv_result_err!(
+                EINVAL,
+                format!("watchdog_irq {} neither a PPI nor an SPI", irq)
+            );
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,135 @@
+//! Log of every irq injected into a zone, for the guest's own security monitor. Unlike the
+//! stats it keeps each injection, in a ring of the zone's `irq_audit_entries` most recent
+//! ones. The guest fetches and clears it with the `HvIrqAudit` hypercall. Zones without
+//! `irq_audit_entries` don't pay more than a check of the config.
+use alloc::collections::VecDeque;
+use core::mem::size_of;
+
+use super::{
+    guest_mem::{as_bytes, copy_to_guest},
+    is_sgi,
+};
+use crate::{
+    arch::aarch64::sysreg::read_sysreg,
+    error::HvResult,
+    percpu::{this_cpu_data, this_zone},
+};
+
+/// Most entries `irq_audit_entries` may ask for, the ring is allocated up front from the
+/// hypervisor's heap.
+pub const MAX_AUDIT_ENTRIES: usize = 4096;
+
+/// Where an audited irq came from.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditSource {
+    /// A physical irq forwarded to the zone.
+    Hardware = 0,
+    /// An irq of an emulated device, e.g. virtio.
+    Virtual = 1,
+    Sgi = 2,
+}
+
+/// One injection, as the guest finds it in its buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvAuditEntry {
+    /// CNTPCT_EL0 at the injection.
+    pub timestamp: u64,
+    pub irq: u32,
+    pub cpu: u32,
+    /// `AuditSource`
+    pub source: u32,
+    /// Whether the LR links the irq to its physical one (HW bit).
+    pub hw_mapped: u32,
+}
+
+/// Start of the guest's buffer, followed by `entries` `HvAuditEntry`s, oldest first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvAuditHeader {
+    pub entries: u64,
+    /// Injections lost since the last fetch, because the ring was full.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditRing {
+    entries: VecDeque<HvAuditEntry>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl AuditRing {
+    /// A ring of `capacity` entries, 0 turns auditing off.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, entry: HvAuditEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// Log the injection of `irq_id` on this cpu into the ring of its zone, if it keeps one.
+pub fn record(irq_id: usize, is_hardware: bool) {
+    let Some(zone) = &this_cpu_data().zone else {
+        return;
+    };
+    let zone = zone.read();
+    if !zone.vgicv3.audit_enabled {
+        return;
+    }
+    let hw_mapped = is_hardware && !is_sgi(irq_id as _) && zone.vgicv3.is_hw_mapped(irq_id);
+    let source = if is_sgi(irq_id as _) {
+        AuditSource::Sgi
+    } else if is_hardware {
+        AuditSource::Hardware
+    } else {
+        AuditSource::Virtual
+    };
+    zone.vgicv3.audit.lock().push(HvAuditEntry {
+        timestamp: read_sysreg!(cntpct_el0),
+        irq: irq_id as _,
+        cpu: this_cpu_data().id as _,
+        source: source as _,
+        hw_mapped: hw_mapped as _,
+    });
+}
+
+/// Move the audit ring of the calling zone to its `size` bytes at guest physical `buf`, as
+/// many entries as fit, and clear it. Returns the bytes written.
+pub fn fetch_and_clear(buf: usize, size: usize) -> HvResult<usize> {
+    if buf % 8 != 0 || size < size_of::<HvAuditHeader>() {
+        return hv_result_err!(EINVAL, "irq audit buffer too small or misaligned");
+    }
+    let zone = this_zone();
+    let zone = zone.read();
+    if !zone.vgicv3.audit_enabled {
+        return hv_result_err!(ENODEV, "irq audit not enabled for this zone");
+    }
+    let mut ring = zone.vgicv3.audit.lock();
+    let capacity = (size - size_of::<HvAuditHeader>()) / size_of::<HvAuditEntry>();
+    let count = ring.entries.len().min(capacity);
+    let mut gpa = buf + size_of::<HvAuditHeader>();
+    for entry in ring.entries.iter().take(count) {
+        copy_to_guest(&zone.gpm, gpa, as_bytes(entry))?;
+        gpa += size_of::<HvAuditEntry>();
+    }
+    let header = HvAuditHeader {
+        entries: count as _,
+        dropped: ring.dropped + (ring.entries.len() - count) as u64,
+    };
+    copy_to_guest(&zone.gpm, buf, as_bytes(&header))?;
+    ring.entries.clear();
+    ring.dropped = 0;
+    Ok(size_of::<HvAuditHeader>() + count * size_of::<HvAuditEntry>())
+}
@@ -0,0 +1,108 @@
+//! Cost of the virtual interrupt injection and physical deactivation paths, measured with
+//! CNTPCT_EL0. The GIC system registers are the ones of the register mock (the bench feature
+//! turns on sysreg_mock), so the numbers are those of the code paths themselves and don't
+//! depend on the list registers a cpu has. Runs once on the primary cpu before any zone is
+//! started, the mock is reset again afterwards.
+use aarch64_cpu::registers::{Readable, CNTFRQ_EL0, CNTPCT_EL0};
+use core::arch::asm;
+
+use crate::{
+    arch::aarch64::sysreg::{mock, write_sysreg},
+    percpu::this_cpu_data,
+};
+
+use super::{
+    cpu_state::this_gic, deactivate_irq, gicv3_clear_pending_irqs, lr_count, pending,
+    write_irq_to_lr, write_lr, IrqGroup,
+};
+
+const BENCH_ROUNDS: u64 = 1000;
+// a virtual SPI no guest sees while the bench runs
+const BENCH_IRQ: usize = 100;
+// ICH_VTR_EL2 of the mocked cpu interface: 16 list registers, 5 preemption and priority bits
+const BENCH_VTR: u64 = 15 | 4 << 26 | 4 << 29;
+
+// The timer is the hardware's, only the GIC registers are mocked.
+fn ticks() -> u64 {
+    unsafe { asm!("isb") };
+    CNTPCT_EL0.get()
+}
+
+// Time `op` over BENCH_ROUNDS rounds, `setup` runs before every round and isn't counted.
+fn measure(name: &str, mut setup: impl FnMut(), mut op: impl FnMut()) {
+    let mut total = 0;
+    for _ in 0..BENCH_ROUNDS {
+        setup();
+        let start = ticks();
+        op();
+        total += ticks() - start;
+    }
+    let freq = CNTFRQ_EL0.get();
+    let per_op = total / BENCH_ROUNDS;
+    info!(
+        "bench {}: {} ticks/op ({} ns/op)",
+        name,
+        per_op,
+        per_op * 1_000_000_000 / freq
+    );
+}
+
+// The mock doesn't track which list registers are empty, ICH_ELRSR_EL2 is set along with
+// them.
+fn set_free_lrs(mask: u64) {
+    write_sysreg!(ich_elrsr_el2, mask);
+}
+
+fn all_lrs() -> u64 {
+    (1 << lr_count()) - 1
+}
+
+fn clear_lrs() {
+    gicv3_clear_pending_irqs();
+    set_free_lrs(all_lrs());
+}
+
+// Every list register holds another irq of the highest priority, so injection scans the
+// whole set, finds nothing to evict and defers the irq.
+fn fill_lrs() {
+    pending::clear(this_cpu_data().id);
+    for i in 0..lr_count() {
+        write_lr(i, (BENCH_IRQ + 1 + i) as u64 | 1 << 60 | 1 << 62);
+    }
+    set_free_lrs(0);
+}
+
+fn inject() {
+    write_irq_to_lr(BENCH_IRQ, false, IrqGroup::Group1, false)
+}
+
+pub fn run() {
+    mock::reset();
+    write_sysreg!(ich_vtr_el2, BENCH_VTR);
+    this_cpu_data().gic.init();
+    info!(
+        "bench: {} rounds, {} mocked list registers",
+        BENCH_ROUNDS,
+        this_gic().vtr.list_regs
+    );
+
+    measure("inject, free lr", clear_lrs, inject);
+    measure("inject, lrs full", fill_lrs, inject);
+    measure(
+        "inject, coalesced",
+        || {
+            clear_lrs();
+            inject();
+            set_free_lrs(all_lrs() & !1);
+        },
+        inject,
+    );
+    measure(
+        "deactivate",
+        || {},
+        || deactivate_irq(BENCH_IRQ, IrqGroup::Group1),
+    );
+
+    pending::clear(this_cpu_data().id);
+    mock::reset();
+}
@@ -0,0 +1,179 @@
+//! GIC state kept for each cpu in its `PerCpu`: what ICH_VTR_EL2 says about the virtual CPU
+//! interface, read once by `gicc_init`, and the cpu's idle notifier.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use spin::Mutex;
+
+use super::{load::IrqLoad, pv::PvState};
+
+use crate::{
+    arch::aarch64::sysreg::read_sysreg,
+    percpu::{get_cpu_data, this_cpu_data},
+};
+
+#[derive(Debug, Default)]
+pub(super) struct IdleNotifier {
+    pub callback: Option<fn(usize)>,
+    // whether the callback already ran for the current idle period
+    pub idle: bool,
+}
+
+/// The fields of ICH_VTR_EL2, as counts instead of the minus-one encodings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VtrFields {
+    /// Number of list registers, ListRegs + 1.
+    pub list_regs: usize,
+    /// PREbits + 1, the priority bits which decide about preemption.
+    pub preemption_bits: usize,
+    /// PRIbits + 1, the priority bits of virtual irqs.
+    pub priority_bits: usize,
+    /// Bits of the vINTIDs the virtual interface supports, 16 or 24.
+    pub id_bits: usize,
+    /// The virtual interface supports local generation of SErrors.
+    pub seis: bool,
+    /// Non-zero affinity 3 values are supported.
+    pub a3v: bool,
+}
+
+impl VtrFields {
+    pub fn decode(vtr: u64) -> Self {
+        Self {
+            list_regs: (vtr & 0x1f) as usize + 1,
+            preemption_bits: ((vtr >> 26) & 0x7) as usize + 1,
+            priority_bits: ((vtr >> 29) & 0x7) as usize + 1,
+            id_bits: if (vtr >> 23) & 0x7 == 1 { 24 } else { 16 },
+            seis: vtr & (1 << 22) != 0,
+            a3v: vtr & (1 << 21) != 0,
+        }
+    }
+
+    /// The fields of this cpu's ICH_VTR_EL2.
+    pub fn read() -> Self {
+        Self::decode(read_sysreg!(ich_vtr_el2))
+    }
+}
+
+#[derive(Debug)]
+pub struct PerCpuGic {
+    pub vtr: VtrFields,
+    /// Number of ICH_AP<n>R<m>_EL2 registers of each group: one for each 32 preemption
+    /// levels, which are 2^vtr.preemption_bits.
+    pub apr_num: usize,
+    pub(super) idle: Mutex<IdleNotifier>,
+    /// Shared page of a paravirtualized vcpu, see `pv`.
+    pub(super) pv: PvState,
+    /// Irq rate estimate of the vcpu, see `load`.
+    pub(super) load: IrqLoad,
+    /// (irq, priority) of the irqs whose priority is raised, see `boost_irq_priority`.
+    pub(super) boosts: Mutex<Vec<(usize, u8)>>,
+    /// Watchdog timeout of the vcpu in timer ticks, 0 if it has none, see `watchdog`.
+    pub(super) watchdog_ticks: AtomicU64,
+    /// When the watchdog of the vcpu runs out, as armed by the last exit, 0 if it isn't.
+    pub(super) watchdog_deadline: AtomicU64,
+    /// When the first storm cooldown this cpu started ends, 0 if none is running.
+    pub(super) storm_deadline: AtomicU64,
+    /// The cpu's GIC init failed, it stays offline, see `percpu_init`.
+    pub(super) offline: AtomicBool,
+    /// Timer ticks the cpu's GIC init took.
+    pub(super) init_ticks: AtomicU64,
+}
+
+impl PerCpuGic {
+    /// Nothing is known before `init` runs on the cpu itself.
+    pub const fn new() -> Self {
+        Self {
+            vtr: VtrFields {
+                list_regs: 0,
+                preemption_bits: 0,
+                priority_bits: 0,
+                id_bits: 0,
+                seis: false,
+                a3v: false,
+            },
+            apr_num: 0,
+            idle: Mutex::new(IdleNotifier {
+                callback: None,
+                idle: false,
+            }),
+            pv: PvState::new(),
+            load: IrqLoad::new(),
+            boosts: Mutex::new(Vec::new()),
+            watchdog_ticks: AtomicU64::new(0),
+            watchdog_deadline: AtomicU64::new(0),
+            storm_deadline: AtomicU64::new(0),
+            offline: AtomicBool::new(false),
+            init_ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Take the values of this cpu's ICH_VTR_EL2.
+    pub fn init(&mut self) {
+        self.vtr = VtrFields::read();
+        // at least 5 on hardware, a register mock may read 0
+        self.apr_num = 1 << self.vtr.preemption_bits.saturating_sub(5);
+    }
+}
+
+/// The GIC state of this cpu.
+pub fn this_gic() -> &'static PerCpuGic {
+    &this_cpu_data().gic
+}
+
+/// The GIC state of `cpu`, only its idle notifier may be used from another cpu.
+pub fn cpu_gic(cpu: usize) -> &'static PerCpuGic {
+    &get_cpu_data(cpu).gic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::aarch64::sysreg::{mock, write_sysreg};
+
+    // ICH_VTR_EL2 encodes the counts minus one, IDbits 0b001 means 24 bit vINTIDs.
+    #[test]
+    fn vtr_fields_are_decoded_as_counts() {
+        let vtr = 15 | 1 << 21 | 1 << 22 | 1 << 23 | 4 << 26 | 6 << 29;
+        let fields = VtrFields {
+            list_regs: 16,
+            preemption_bits: 5,
+            priority_bits: 7,
+            id_bits: 24,
+            seis: true,
+            a3v: true,
+        };
+        assert_eq!(VtrFields::decode(vtr), fields);
+        let minimal = VtrFields {
+            list_regs: 1,
+            preemption_bits: 1,
+            priority_bits: 1,
+            id_bits: 16,
+            ..Default::default()
+        };
+        assert_eq!(VtrFields::decode(0), minimal);
+    }
+
+    // Every test thread has its own mocked registers, like every cpu has its own virtual
+    // interface: what one cpu reads from its ICH_VTR_EL2 or changes in its state doesn't show
+    // on another.
+    #[test]
+    fn cpus_keep_their_own_gic_state() {
+        let cpu = |vtr: u64| {
+            std::thread::spawn(move || {
+                mock::reset();
+                write_sysreg!(ich_vtr_el2, vtr);
+                let mut gic = PerCpuGic::new();
+                gic.init();
+                gic
+            })
+        };
+        let (a, b) = (cpu(3 | 4 << 26), cpu(15 | 6 << 26));
+        let (a, b) = (a.join().unwrap(), b.join().unwrap());
+        assert_eq!((a.vtr.list_regs, a.apr_num), (4, 1));
+        assert_eq!((b.vtr.list_regs, b.apr_num), (16, 4));
+
+        a.boosts.lock().push((40, 0x20));
+        a.offline.store(true, core::sync::atomic::Ordering::Relaxed);
+        assert!(b.boosts.lock().is_empty());
+        assert!(!b.offline.load(core::sync::atomic::Ordering::Relaxed));
+    }
+}
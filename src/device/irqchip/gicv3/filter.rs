@@ -0,0 +1,79 @@
+//! Filters run on every injection before it reaches a list register or the pending queue, to
+//! drop or remap irqs centrally, e.g. while testing. Without a registered filter an injection
+//! only pays for one atomic load.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{deactivate, irq_lock};
+
+/// What a filter does with an injection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Pass,
+    Drop,
+    /// Inject this irq instead, the filters after this one see the new irq.
+    Remap(usize),
+}
+
+/// Called with the irq id, whether it is a hardware irq and the cpu it is injected on. It runs
+/// with the filter list locked and must not register or remove filters.
+pub type InjectFilter = fn(usize, bool, usize) -> FilterAction;
+
+static FILTERS: Mutex<Vec<(usize, InjectFilter)>> = Mutex::new(Vec::new());
+// whether FILTERS has any, so injections without filters don't take the lock
+static HAS_FILTERS: AtomicBool = AtomicBool::new(false);
+static NEXT_FILTER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Add `filter` behind the registered ones, returns the id to remove it with.
+pub fn register_inject_filter(filter: InjectFilter) -> usize {
+    let id = NEXT_FILTER_ID.fetch_add(1, Ordering::Relaxed);
+    let mut filters = FILTERS.lock();
+    filters.push((id, filter));
+    HAS_FILTERS.store(true, Ordering::Release);
+    id
+}
+
+pub fn unregister_inject_filter(id: usize) {
+    let mut filters = FILTERS.lock();
+    filters.retain(|&(filter_id, _)| filter_id != id);
+    HAS_FILTERS.store(!filters.is_empty(), Ordering::Release);
+}
+
+/// Run the filters on the injection of `irq_id` on `cpu`. Returns the irq to inject and
+/// whether it still is the hardware irq, `None` if it is dropped.
+///
+/// A dropped or remapped hardware irq is deactivated right away, the guest never sees it to
+/// deactivate it. A level-triggered one fires again as long as its device asserts it, so
+/// remapping is meant for edge-triggered irqs.
+pub fn run(irq_id: usize, is_hardware: bool, cpu: usize) -> Option<(usize, bool)> {
+    if !HAS_FILTERS.load(Ordering::Acquire) {
+        return Some((irq_id, is_hardware));
+    }
+    let mut irq = irq_id;
+    for &(_, filter) in FILTERS.lock().iter() {
+        match filter(irq, is_hardware && irq == irq_id, cpu) {
+            FilterAction::Pass => {}
+            FilterAction::Drop => {
+                trace!("inject filter drops irq {}", irq);
+                release_hw_irq(irq_id, is_hardware);
+                return None;
+            }
+            FilterAction::Remap(new_irq) => {
+                trace!("inject filter remaps irq {} to {}", irq, new_irq);
+                irq = new_irq;
+            }
+        }
+    }
+    if irq != irq_id {
+        release_hw_irq(irq_id, is_hardware);
+    }
+    Some((irq, is_hardware && irq == irq_id))
+}
+
+fn release_hw_irq(irq_id: usize, is_hardware: bool) {
+    if is_hardware {
+        let _guard = irq_lock::lock(irq_id);
+        deactivate(irq_id);
+    }
+}
@@ -7,17 +7,36 @@
 //! # Glossary
 //!   - SPI - Shared Peripheral Interrupt.
 #![allow(dead_code)]
+use alloc::string::String;
+use core::fmt::Write;
 use spin::Mutex;
 
-use super::host_gicd_base;
+use super::{host_gicd_base, GICV3_ESPI_BASE};
+use crate::{
+    arch::cpu::{cpuid_to_vcpuid, mpidr_to_cpuid},
+    zone::Zone,
+};
 
 pub static GICD_LOCK: Mutex<()> = Mutex::new(());
 
 pub const GICD_CTLR: usize = 0x0000;
 pub const GICD_CTLR_ARE_NS: usize = 1 << 5;
+/// EnableGrp1A in the non-secure view with two security states, EnableGrp1 with DS set.
 pub const GICD_CTLR_GRP1NS_ENA: usize = 1 << 1;
+/// EnableGrp0 with DS set, RES0 in the non-secure view with two security states.
+pub const GICD_CTLR_GRP0_ENA: usize = 1 << 0;
+/// Single security state, the non-secure world owns group 0 as well.
+pub const GICD_CTLR_DS: usize = 1 << 6;
+pub const GICD_CTLR_RWP: usize = 1 << 31;
 
 pub const GICD_TYPER: usize = 0x0004;
+pub const GICD_TYPER_ITLINES_MASK: usize = 0x1f;
+pub const GICD_TYPER_CPUNUM_SHIFT: usize = 5;
+pub const GICD_TYPER_CPUNUM_MASK: usize = 0x7 << GICD_TYPER_CPUNUM_SHIFT;
+pub const GICD_TYPER_ESPI: usize = 1 << 8;
+pub const GICD_TYPER_NMI: usize = 1 << 9;
+pub const GICD_TYPER_LPIS: usize = 1 << 17;
+pub const GICD_TYPER_ESPI_RANGE_SHIFT: usize = 27;
 pub const GICD_IIDR: usize = 0x0008;
 pub const GICD_IGROUPR: usize = 0x0080;
 pub const GICD_ISENABLER: usize = 0x0100;
@@ -34,15 +53,201 @@ pub const GICD_SGIR: usize = 0x0f00;
 pub const GICD_CPENDSGIR: usize = 0x0f10;
 pub const GICD_SPENDSGIR: usize = 0x0f20;
 pub const GICD_IROUTER: usize = 0x6000;
+pub const GICD_IROUTER_IRM: u64 = 1 << 31;
+
+// GICv3.1 extended SPI range registers
+pub const GICD_IGROUPRNE: usize = 0x1000;
+pub const GICD_ISENABLERNE: usize = 0x1200;
+pub const GICD_ICENABLERNE: usize = 0x1400;
+pub const GICD_ISPENDRNE: usize = 0x1600;
+pub const GICD_ICPENDRNE: usize = 0x1800;
+pub const GICD_ISACTIVERNE: usize = 0x1a00;
+pub const GICD_ICACTIVERNE: usize = 0x1c00;
+pub const GICD_IPRIORITYRNE: usize = 0x2000;
+pub const GICD_ICFGRNE: usize = 0x3000;
+pub const GICD_IGRPMODRNE: usize = 0x3400;
+pub const GICD_NSACRNE: usize = 0x3600;
+pub const GICD_IROUTERNE: usize = 0x8000;
 
 pub const GICDV3_CIDR0: usize = 0xfff0;
 pub const GICDV3_PIDR0: usize = 0xffe0;
 pub const GICDV3_PIDR2: usize = 0xffe8;
 pub const GICDV3_PIDR4: usize = 0xffd0;
 
+/// The GICD is mapped to zones in 4K pages, one bit per page in the pass-through bitmaps.
+pub const GICD_PAGE_SIZE: usize = 0x1000;
+/// Pages whose reads are emulated or filtered per zone, they can never be read directly. Page 0
+/// holds CTLR, TYPER and IIDR next to the per-irq registers, pages 6 and 7 hold IROUTER, pages
+/// 1 to 3 and 8 to 9 the same for extended SPIs.
+pub const GICD_READ_EMULATED_PAGES: usize =
+    1 << 0 | 1 << 1 | 1 << 2 | 1 << 3 | 1 << 6 | 1 << 7 | 1 << 8 | 1 << 9;
+
+/// Number of GICv3.1 extended SPIs implemented by the distributor at `gicd_base`.
+pub fn read_espi_num(gicd_base: usize) -> usize {
+    let typer = unsafe { ((gicd_base + GICD_TYPER) as *const u32).read_volatile() } as usize;
+    if typer & GICD_TYPER_ESPI == 0 {
+        return 0;
+    }
+    32 * (((typer >> GICD_TYPER_ESPI_RANGE_SHIFT) & 0x1f) + 1)
+}
+
+/// Whether the distributor at `gicd_base` supports the GICv3.3 NMI property of irqs.
+pub fn read_nmi_support(gicd_base: usize) -> bool {
+    let typer = unsafe { ((gicd_base + GICD_TYPER) as *const u32).read_volatile() } as usize;
+    typer & GICD_TYPER_NMI != 0
+}
+
+/// Whether the distributor at `gicd_base` runs with a single security state, GICD_CTLR.DS.
+pub fn read_single_security(gicd_base: usize) -> bool {
+    let ctlr = unsafe { ((gicd_base + GICD_CTLR) as *const u32).read_volatile() } as usize;
+    ctlr & GICD_CTLR_DS != 0
+}
+
 pub fn enable_gic_are_ns() {
     unsafe {
         ((host_gicd_base() + GICD_CTLR) as *mut u32)
             .write_volatile(GICD_CTLR_ARE_NS as u32 | GICD_CTLR_GRP1NS_ENA as u32);
     }
 }
+
+// (first offset, end, register size, name) of the GICD registers, for tracing accesses
+const GICD_REG_NAMES: &[(usize, usize, usize, &str)] = &[
+    (GICD_CTLR, 0x0004, 4, "CTLR"),
+    (GICD_TYPER, 0x0008, 4, "TYPER"),
+    (GICD_IIDR, 0x000c, 4, "IIDR"),
+    (0x000c, 0x0010, 4, "TYPER2"),
+    (0x0010, 0x0014, 4, "STATUSR"),
+    (0x0040, 0x0044, 4, "SETSPI_NSR"),
+    (0x0048, 0x004c, 4, "CLRSPI_NSR"),
+    (0x0050, 0x0054, 4, "SETSPI_SR"),
+    (0x0058, 0x005c, 4, "CLRSPI_SR"),
+    (GICD_IGROUPR, GICD_ISENABLER, 4, "IGROUPR"),
+    (GICD_ISENABLER, GICD_ICENABLER, 4, "ISENABLER"),
+    (GICD_ICENABLER, GICD_ISPENDR, 4, "ICENABLER"),
+    (GICD_ISPENDR, GICD_ICPENDR, 4, "ISPENDR"),
+    (GICD_ICPENDR, GICD_ISACTIVER, 4, "ICPENDR"),
+    (GICD_ISACTIVER, GICD_ICACTIVER, 4, "ISACTIVER"),
+    (GICD_ICACTIVER, GICD_IPRIORITYR, 4, "ICACTIVER"),
+    (GICD_IPRIORITYR, GICD_ITARGETSR, 4, "IPRIORITYR"),
+    (GICD_ITARGETSR, GICD_ICFGR, 4, "ITARGETSR"),
+    (GICD_ICFGR, 0x0d00, 4, "ICFGR"),
+    (0x0d00, GICD_NSACR, 4, "IGRPMODR"),
+    (GICD_NSACR, GICD_SGIR, 4, "NSACR"),
+    (GICD_SGIR, 0x0f04, 4, "SGIR"),
+    (GICD_CPENDSGIR, GICD_SPENDSGIR, 4, "CPENDSGIR"),
+    (GICD_SPENDSGIR, 0x0f30, 4, "SPENDSGIR"),
+    (0x0f80, GICD_IGROUPRNE, 4, "INMIR"),
+    (GICD_IGROUPRNE, 0x1080, 4, "IGROUPRnE"),
+    (GICD_ISENABLERNE, 0x1280, 4, "ISENABLERnE"),
+    (GICD_ICENABLERNE, 0x1480, 4, "ICENABLERnE"),
+    (GICD_ISPENDRNE, 0x1680, 4, "ISPENDRnE"),
+    (GICD_ICPENDRNE, 0x1880, 4, "ICPENDRnE"),
+    (GICD_ISACTIVERNE, 0x1a80, 4, "ISACTIVERnE"),
+    (GICD_ICACTIVERNE, 0x1c80, 4, "ICACTIVERnE"),
+    (GICD_IPRIORITYRNE, 0x2400, 4, "IPRIORITYRnE"),
+    (GICD_ICFGRNE, 0x3100, 4, "ICFGRnE"),
+    (GICD_IGRPMODRNE, 0x3480, 4, "IGRPMODRnE"),
+    (GICD_NSACRNE, 0x3700, 4, "NSACRnE"),
+    (0x3b00, 0x3b80, 4, "INMIRnE"),
+    (GICD_IROUTER, 0x7fe0, 8, "IROUTER"),
+    (GICD_IROUTERNE, 0xa000, 8, "IROUTERnE"),
+    (GICDV3_PIDR4, 0x10000, 4, "ID"),
+];
+
+/// Name and index of the GICD register at `offset`, "ID" for the identification registers
+/// and `None` for reserved or implementation defined offsets.
+pub fn gicd_reg_name(offset: usize) -> Option<(&'static str, usize)> {
+    GICD_REG_NAMES
+        .iter()
+        .find(|&&(first, end, _, _)| (first..end).contains(&offset))
+        .map(|&(first, _, size, name)| (name, (offset - first) / size))
+}
+
+// Until the distributor performed the writes to GICD_CTLR and GICD_ICENABLER<n>.
+fn wait_rwp(gicd_base: usize) {
+    let ctlr = (gicd_base + GICD_CTLR) as *const u32;
+    while unsafe { ctlr.read_volatile() } as usize & GICD_CTLR_RWP != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Leave the distributor at `gicd_base` the way the next boot stage expects it: both groups
+/// disabled and every SPI and extended SPI disabled, not pending and not active. ARE_NS stays
+/// set, clearing it again is not allowed on all implementations.
+pub fn reset_distributor(gicd_base: usize) {
+    let write =
+        |reg: usize, val: u32| unsafe { ((gicd_base + reg) as *mut u32).write_volatile(val) };
+    write(GICD_CTLR, GICD_CTLR_ARE_NS as u32);
+    wait_rwp(gicd_base);
+    let typer = unsafe { ((gicd_base + GICD_TYPER) as *const u32).read_volatile() } as usize;
+    // register 0 holds the banked SGIs and PPIs, the redistributors have those
+    let spi_regs = (1..(typer & GICD_TYPER_ITLINES_MASK) + 2).map(|n| n * 4);
+    for offset in spi_regs {
+        write(GICD_ICENABLER + offset, u32::MAX);
+        write(GICD_ICPENDR + offset, u32::MAX);
+        write(GICD_ICACTIVER + offset, u32::MAX);
+    }
+    for offset in (0..read_espi_num(gicd_base) / 32).map(|n| n * 4) {
+        write(GICD_ICENABLERNE + offset, u32::MAX);
+        write(GICD_ICPENDRNE + offset, u32::MAX);
+        write(GICD_ICACTIVERNE + offset, u32::MAX);
+    }
+    wait_rwp(gicd_base);
+}
+
+// interrupt specifier of the arm,gic-v3 binding: <type number flags>
+const DT_GIC_SPI: u32 = 0;
+const DT_GIC_ESPI: u32 = 2;
+const DT_IRQ_TYPE_EDGE_RISING: u32 = 1;
+const DT_IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// The SPIs of `zone` as the `interrupts` property of a device tree would encode them, for
+/// cross-checking the zone config against the guest's device tree. Trigger and routing come
+/// from the distributor, each specifier is followed by the irq id and the vcpu it is routed
+/// to, "any" for 1 of N routing.
+pub fn dump_dt_fragment(zone: &Zone) -> String {
+    let gicd_base = host_gicd_base();
+    let spis = (32..1020).chain(GICV3_ESPI_BASE as usize..GICV3_ESPI_BASE as usize + 1024);
+    let mut out = String::new();
+    let _ = writeln!(out, "/* zone {} */", zone.id);
+    let _ = writeln!(out, "interrupts =");
+    let mut first = true;
+    for irq in spis.filter(|&irq| zone.irq_in_zone(irq as u32)) {
+        let (dt_type, number, icfgr, irouter) = if irq >= GICV3_ESPI_BASE as usize {
+            let n = irq - GICV3_ESPI_BASE as usize;
+            (DT_GIC_ESPI, n, GICD_ICFGRNE + n / 16 * 4, GICD_IROUTERNE + n * 8)
+        } else {
+            (DT_GIC_SPI, irq - 32, GICD_ICFGR + irq / 16 * 4, GICD_IROUTER + irq * 8)
+        };
+        let (cfg, route) = unsafe {
+            (
+                ((gicd_base + icfgr) as *const u32).read_volatile(),
+                ((gicd_base + irouter) as *const u64).read_volatile(),
+            )
+        };
+        let edge = cfg & (2 << (irq % 16 * 2)) != 0;
+        let (flags, trigger) = if edge {
+            (DT_IRQ_TYPE_EDGE_RISING, "edge")
+        } else {
+            (DT_IRQ_TYPE_LEVEL_HIGH, "level")
+        };
+        let target = if route & GICD_IROUTER_IRM != 0 {
+            String::from("any")
+        } else {
+            let cpu = mpidr_to_cpuid(route) as usize;
+            match cpuid_to_vcpuid(&zone.cpu_set, cpu) {
+                Some(vcpu) => format!("vcpu {}", vcpu),
+                None => format!("cpu {} outside the zone", cpu),
+            }
+        };
+        let sep = if first { ' ' } else { ',' };
+        first = false;
+        let _ = writeln!(
+            out,
+            "\t{} <{} {} {}> /* irq {}, {}, {} */",
+            sep, dt_type, number, flags, irq, trigger, target
+        );
+    }
+    let _ = writeln!(out, "\t;");
+    out
+}
@@ -0,0 +1,142 @@
+//! Write combining of guest distributor configuration. Writes to the registers which only
+//! matter once an irq is enabled (priority, routing, trigger mode) are buffered per zone and
+//! performed in one batch, a later write to the same register replaces the buffered one. A
+//! write which only overlaps a buffered one, e.g. a byte of a buffered IPRIORITYR word, is
+//! performed after it and buffered once the buffer is flushed, to keep their order.
+//!
+//! Any other distributor access, reads included, flushes the buffer first, so the guest never
+//! observes a buffered write as missing. A GICD_CTLR read is the exception, it reports the
+//! buffered writes through RWP before they are performed. Writes are only held across a run
+//! of exits which each buffer one: every other exit flushes them, an exit which isn't a data
+//! abort before it is handled and any other once it is, see `exit_done`.
+//!
+//! The writes trapped and performed are reported when the guest enables the distributor,
+//! which is where a Linux guest's GIC init ends.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::{
+    gicd::{GICD_ICFGR, GICD_IPRIORITYR, GICD_IROUTER},
+    vgic::{reg_range, vgicv3_dist_access},
+};
+use crate::{consts::MAX_CPU_NUM, memory::MMIOAccess, percpu::this_cpu_data};
+
+const GICD_WC_ENTRIES: usize = 32;
+
+// What a cpu has buffered: nothing, a write in the exit being handled, or writes of earlier
+// exits. Kept per cpu so exits which don't touch the distributor don't take the zone lock.
+const NOTHING_BUFFERED: u8 = 0;
+const BUFFERED_THIS_EXIT: u8 = 1;
+const BUFFERED_EARLIER: u8 = 2;
+#[allow(clippy::declare_interior_mutable_const)]
+const NOTHING: AtomicU8 = AtomicU8::new(NOTHING_BUFFERED);
+static CPU_BUFFERED: [AtomicU8; MAX_CPU_NUM] = [NOTHING; MAX_CPU_NUM];
+
+#[derive(Debug, Default)]
+pub struct GicdWriteBuffer {
+    writes: Vec<MMIOAccess>,
+    // guest writes trapped and writes done to the hardware, to see how much is saved
+    trapped: usize,
+    performed: usize,
+}
+
+fn is_combinable(reg: usize) -> bool {
+    reg_range(GICD_IPRIORITYR, 255, 4).contains(&reg)
+        || reg_range(GICD_IROUTER, 1024, 8).contains(&reg)
+        || reg_range(GICD_ICFGR, 64, 4).contains(&reg)
+}
+
+fn overlaps(a: &MMIOAccess, b: &MMIOAccess) -> bool {
+    a.address < b.address + b.size && b.address < a.address + a.size
+}
+
+// Buffer `mmio` in `buffer` unless it overlaps a buffered write without replacing it.
+fn try_buffer(buffer: &mut GicdWriteBuffer, mmio: &MMIOAccess) -> bool {
+    if let Some(buffered) = buffer
+        .writes
+        .iter_mut()
+        .find(|w| w.address == mmio.address && w.size == mmio.size)
+    {
+        buffered.value = mmio.value;
+    } else if buffer.writes.iter().any(|w| overlaps(w, mmio)) {
+        return false;
+    } else {
+        buffer.writes.push(*mmio);
+    }
+    true
+}
+
+/// Buffer `mmio` if it is a combinable write, returns whether it was taken.
+pub fn buffer_write(mmio: &MMIOAccess) -> bool {
+    if !mmio.is_write || !is_combinable(mmio.address) {
+        return false;
+    }
+    let Some(zone) = &this_cpu_data().zone else {
+        return false;
+    };
+    let buffered = {
+        let mut zone_w = zone.write();
+        let buffer = &mut zone_w.vgicv3.write_buffer;
+        buffer.trapped += 1;
+        try_buffer(buffer, mmio)
+    };
+    if !buffered {
+        flush();
+        zone.write().vgicv3.write_buffer.writes.push(*mmio);
+    }
+    CPU_BUFFERED[this_cpu_data().id].store(BUFFERED_THIS_EXIT, Ordering::Relaxed);
+    if zone.read().vgicv3.write_buffer.writes.len() >= GICD_WC_ENTRIES {
+        flush();
+    }
+    true
+}
+
+/// End of an exit of this cpu: flush its zone's buffer, unless the exit buffered a write or
+/// this cpu has none buffered.
+pub fn exit_done() {
+    let state = &CPU_BUFFERED[this_cpu_data().id];
+    match state.load(Ordering::Relaxed) {
+        NOTHING_BUFFERED => {}
+        BUFFERED_THIS_EXIT => state.store(BUFFERED_EARLIER, Ordering::Relaxed),
+        _ => flush(),
+    }
+}
+
+/// Whether this cpu's zone has writes waiting in its buffer.
+pub fn has_buffered_writes() -> bool {
+    match &this_cpu_data().zone {
+        Some(zone) => !zone.read().vgicv3.write_buffer.writes.is_empty(),
+        None => false,
+    }
+}
+
+/// Perform the buffered writes of this cpu's zone.
+pub fn flush() {
+    CPU_BUFFERED[this_cpu_data().id].store(NOTHING_BUFFERED, Ordering::Relaxed);
+    let Some(zone) = &this_cpu_data().zone else {
+        return;
+    };
+    let writes = core::mem::take(&mut zone.write().vgicv3.write_buffer.writes);
+    if writes.is_empty() {
+        return;
+    }
+    for mut mmio in writes.iter().copied() {
+        if let Err(e) = vgicv3_dist_access(&mut mmio) {
+            warn!("buffered gicd write {:#x?} failed: {:?}", mmio, e);
+        }
+    }
+    zone.write().vgicv3.write_buffer.performed += writes.len();
+}
+
+/// Report the writes trapped and performed so far for this cpu's zone.
+pub fn report() {
+    let Some(zone) = &this_cpu_data().zone else {
+        return;
+    };
+    let zone_r = zone.read();
+    let buffer = &zone_r.vgicv3.write_buffer;
+    info!(
+        "zone {}: gicd write combining: {} writes trapped, {} performed",
+        zone_r.id, buffer.trapped, buffer.performed
+    );
+}
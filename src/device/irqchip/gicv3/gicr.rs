@@ -4,21 +4,27 @@
 
 //! GICC Driver - GIC CPU interface.
 
-use crate::{arch::cpu::this_cpu_id, hypercall::SGI_IPI_ID};
+use crate::{
+    arch::{cpu::this_cpu_id, ipi::cpuid_to_affinity},
+    error::HvResult,
+    hypercall::SGI_IPI_ID,
+};
 
 use super::{
     gicd::{
         GICD_ICACTIVER, GICD_ICENABLER, GICD_ICFGR, GICD_ICPENDR, GICD_IGROUPR, GICD_IPRIORITYR,
         GICD_ISACTIVER, GICD_ISENABLER, GICD_ISPENDR,
     },
-    host_gicr_base,
+    host_gicr_base, hv_ipi_fiq, HV_TIMER_IRQ, MAINTENANCE_IRQ, PER_GICR_SIZE,
 };
 
 pub const GICR_CTLR: usize = 0x0000;
+pub const GICR_CTLR_RWP: usize = 1 << 3;
 pub const GICR_IIDR: usize = 0x0004;
 pub const GICR_TYPER: usize = 0x0008;
 pub const GICR_STATUSR: usize = 0x0010;
 pub const GICR_WAKER: usize = 0x0014;
+pub const GICR_PWRR: usize = 0x0024;
 pub const GICR_SYNCR: usize = 0x00c0;
 pub const GICR_PIDR2: usize = 0xffe8;
 pub const GICR_SGI_BASE: usize = 0x10000;
@@ -32,18 +38,120 @@ pub const GICR_ISACTIVER: usize = GICD_ISACTIVER;
 pub const GICR_ICACTIVER: usize = GICD_ICACTIVER;
 pub const GICR_IPRIORITYR: usize = GICD_IPRIORITYR;
 pub const GICR_ICFGR: usize = GICD_ICFGR;
+/// Int_config value of an edge-triggered irq, two bits per irq in GICR_ICFGR<n>.
+pub const GICR_ICFGR_EDGE: u32 = 0b10;
+/// GICR_ICFGR0 as it always reads, every SGI edge-triggered.
+pub const GICR_ICFGR0_SGI_EDGE: usize = 0xaaaa_aaaa;
+pub const GICR_TYPER_PLPIS: usize = 1 << 0;
+pub const GICR_TYPER_VLPIS: usize = 1 << 1;
+pub const GICR_TYPER_DIRECTLPI: usize = 1 << 3;
 pub const GICR_TYPER_LAST: usize = 1 << 4;
+pub const GICR_TYPER_DPGS: usize = 1 << 5;
+pub const GICR_TYPER_RVPEID: usize = 1 << 7;
+/// GICR_TYPER bits advertising LPI support, which zones don't get.
+pub const GICR_TYPER_LPI_MASK: usize = GICR_TYPER_PLPIS
+    | GICR_TYPER_VLPIS
+    | GICR_TYPER_DIRECTLPI
+    | GICR_TYPER_DPGS
+    | GICR_TYPER_RVPEID;
+pub const GICR_TYPER_PPINUM_SHIFT: usize = 27;
+pub const GICR_TYPER_PROC_NUM_SHIFT: usize = 8;
+pub const GICR_TYPER_PROC_NUM_MASK: usize = 0xffff << GICR_TYPER_PROC_NUM_SHIFT;
+pub const GICR_TYPER_AFFINITY_SHIFT: usize = 32;
+pub const GICR_TYPER_AFFINITY_MASK: usize = 0xffff_ffff << GICR_TYPER_AFFINITY_SHIFT;
+pub const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+pub const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+pub const GICR_PWRR_RDPD: u32 = 1 << 0;
+pub const GICR_PWRR_RDGPD: u32 = 1 << 2;
+pub const GICR_PWRR_RDGPO: u32 = 1 << 3;
+
+/// Check that the redistributor frame of `cpu` lies within the `gicr_size` bytes of frames and
+/// belongs to that cpu. Only the cpu itself looks at its frame, which may not be accessible
+/// before the cpu powered it on.
+pub fn check_gicr_frame(cpu: usize, gicr_size: usize) -> HvResult {
+    if (cpu + 1) * PER_GICR_SIZE > gicr_size {
+        return hv_result_err!(
+            ENODEV,
+            format!(
+                "no redistributor frame for cpu {} within {:#x} bytes, check gicr_size",
+                cpu, gicr_size
+            )
+        );
+    }
+    let typer = unsafe { ((host_gicr_base(cpu) + GICR_TYPER) as *const u64).read_volatile() };
+    let (aff3, aff2, aff1, aff0) = cpuid_to_affinity(cpu);
+    let affinity = aff3 << 24 | aff2 << 16 | aff1 << 8 | aff0;
+    if typer >> GICR_TYPER_AFFINITY_SHIFT != affinity {
+        return hv_result_err!(
+            ENODEV,
+            format!(
+                "redistributor frame {} has affinity {:#x}, not the cpu's {:#x}, check gicr_base",
+                cpu,
+                typer >> GICR_TYPER_AFFINITY_SHIFT,
+                affinity
+            )
+        );
+    }
+    Ok(())
+}
+
+pub fn read_gicr_icfgr1(cpu: usize) -> u32 {
+    let icfgr1 = host_gicr_base(cpu) + GICR_SGI_BASE + GICR_ICFGR + 4;
+    unsafe { (icfgr1 as *const u32).read_volatile() }
+}
+
+/// Number of GICv3.1 extended PPIs implemented by the redistributor at `gicr_base`.
+pub fn read_eppi_num(gicr_base: usize) -> usize {
+    let typer = unsafe { ((gicr_base + GICR_TYPER) as *const u64).read_volatile() };
+    match (typer >> GICR_TYPER_PPINUM_SHIFT) & 0x1f {
+        1 => 32,
+        2 => 64,
+        _ => 0,
+    }
+}
+
+/// GIC-600 power-up handshake, its redistributors may come out of reset powered down and
+/// accesses to them hang until GICR_PWRR.RDPD is cleared.
+pub fn gicr_power_on(cpu: usize) {
+    let pwrr = (host_gicr_base(cpu) + GICR_PWRR) as *mut u32;
+    unsafe {
+        loop {
+            // wait until the redistributor group isn't powering down anymore
+            while pwrr.read_volatile() & (GICR_PWRR_RDGPD | GICR_PWRR_RDGPO) == GICR_PWRR_RDGPD {}
+            pwrr.write_volatile(0);
+            if pwrr.read_volatile() & GICR_PWRR_RDPD == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Whether the redistributor of `cpu` forwards irqs to its cpu interface.
+pub fn redist_awake(cpu: usize) -> bool {
+    let waker = unsafe { ((host_gicr_base(cpu) + GICR_WAKER) as *const u32).read_volatile() };
+    waker & (GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP) == 0
+}
+
+pub fn wake_redist(cpu: usize) {
+    let waker = (host_gicr_base(cpu) + GICR_WAKER) as *mut u32;
+    unsafe {
+        waker.write_volatile(waker.read_volatile() & !GICR_WAKER_PROCESSOR_SLEEP);
+        while waker.read_volatile() & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+    }
+}
 
 pub fn enable_ipi() {
     let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
 
     unsafe {
-        let gicr_waker = (base + GICR_WAKER) as *mut u32;
-        gicr_waker.write_volatile(gicr_waker.read_volatile() & !0x02);
-        while gicr_waker.read_volatile() & 0x04 != 0 {}
-
+        // group 0 with hv_ipi_fiq, so the guest masking its irqs can't hold it back
         let gicr_igroupr0 = (base + GICR_IGROUPR) as *mut u32;
-        gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << SGI_IPI_ID));
+        let igroupr0 = gicr_igroupr0.read_volatile();
+        if hv_ipi_fiq() {
+            gicr_igroupr0.write_volatile(igroupr0 & !(1 << SGI_IPI_ID));
+        } else {
+            gicr_igroupr0.write_volatile(igroupr0 | (1 << SGI_IPI_ID));
+        }
 
         let gicr_isenabler0 = (base + GICR_ISENABLER) as *mut u32;
         gicr_isenabler0.write_volatile(1 << SGI_IPI_ID);
@@ -60,3 +168,47 @@ pub fn enable_ipi() {
         }
     }
 }
+
+/// Route PPI `irq` of this cpu to the hypervisor as a group 1 irq of `priority` and enable it,
+/// for the PPIs which are never forwarded to guests.
+pub fn enable_hv_ppi(irq: usize, priority: u8) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+    unsafe {
+        let igroupr0 = (base + GICR_IGROUPR) as *mut u32;
+        igroupr0.write_volatile(igroupr0.read_volatile() | 1 << irq);
+        ((base + GICR_IPRIORITYR + irq) as *mut u8).write_volatile(priority);
+        ((base + GICR_ISENABLER) as *mut u32).write_volatile(1 << irq);
+    }
+}
+
+// The banked SGIs/PPIs a zone may use: all but the hypervisor's IPI, maintenance irq and EL2
+// timer, which per-cpu init enabled once for all zones.
+const GUEST_BANKED_IRQS: u32 =
+    !(1 << SGI_IPI_ID | 1 << MAINTENANCE_IRQ as u32 | 1 << HV_TIMER_IRQ as u32);
+
+/// Disable and clear all banked SGIs/PPIs of `cpu` except the hypervisor's own ones.
+pub fn reset_banked_irqs(cpu: usize) {
+    let base = host_gicr_base(cpu) + GICR_SGI_BASE;
+    let mask = GUEST_BANKED_IRQS;
+
+    unsafe {
+        ((base + GICR_ICENABLER) as *mut u32).write_volatile(mask);
+        ((base + GICR_ICPENDR) as *mut u32).write_volatile(mask);
+        ((base + GICR_ICACTIVER) as *mut u32).write_volatile(mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A zone teardown must leave the PPIs the hypervisor itself takes enabled, nothing enables
+    // them again for the next zone.
+    #[test]
+    fn banked_irq_reset_keeps_the_hypervisor_irqs() {
+        for irq in [SGI_IPI_ID as usize, MAINTENANCE_IRQ, HV_TIMER_IRQ] {
+            assert_eq!(GUEST_BANKED_IRQS & 1 << irq, 0);
+        }
+        assert_eq!(GUEST_BANKED_IRQS.count_ones(), 29);
+    }
+}
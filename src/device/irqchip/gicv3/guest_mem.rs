@@ -0,0 +1,90 @@
+//! Copies between the hypervisor and the buffers hypercalls take at guest physical addresses.
+//! Every page of a buffer is looked up in the zone's stage 2 page table and must be normal
+//! memory the zone may access the same way: the guest must not have the hypervisor write to
+//! a device through it, and hypervisor loads, stores and atomics on Device memory fault.
+use core::mem::size_of;
+
+use crate::{
+    arch::s2pt::Stage2PageTable,
+    error::HvResult,
+    memory::{addr::phys_to_virt, GuestPhysAddr, MemFlags, MemorySet, VirtAddr, PAGE_SIZE},
+};
+
+/// Hypervisor virtual address of `gpa`, if the zone of `gpm` has normal memory there and
+/// may access it with `flags`.
+pub fn guest_ram(
+    gpm: &MemorySet<Stage2PageTable>,
+    gpa: GuestPhysAddr,
+    flags: MemFlags,
+) -> HvResult<VirtAddr> {
+    let (hpa, mapped, _) = unsafe { gpm.page_table_query(gpa) }
+        .map_err(|_| hv_err!(EFAULT, "guest buffer not mapped"))?;
+    if mapped.contains(MemFlags::IO) || !mapped.contains(flags) {
+        return hv_result_err!(EFAULT, "guest buffer not in RAM the zone may access");
+    }
+    Ok(phys_to_virt(hpa))
+}
+
+// Call `f` with the hypervisor address, offset into the buffer and length of each piece of
+// the `len` bytes at `gpa` which lies within one page.
+fn for_each_page(
+    gpm: &MemorySet<Stage2PageTable>,
+    gpa: GuestPhysAddr,
+    len: usize,
+    flags: MemFlags,
+    mut f: impl FnMut(VirtAddr, usize, usize),
+) -> HvResult {
+    let end = gpa
+        .checked_add(len)
+        .ok_or_else(|| hv_err!(EFAULT, "guest buffer wraps around"))?;
+    let mut cur = gpa;
+    while cur < end {
+        let chunk = (PAGE_SIZE - cur % PAGE_SIZE).min(end - cur);
+        f(guest_ram(gpm, cur, flags)?, cur - gpa, chunk);
+        cur += chunk;
+    }
+    Ok(())
+}
+
+/// Copy `src` to the zone's memory at `gpa`. On an error a part of it may have been copied.
+pub fn copy_to_guest(gpm: &MemorySet<Stage2PageTable>, gpa: GuestPhysAddr, src: &[u8]) -> HvResult {
+    let copy = |hva: VirtAddr, offset: usize, len: usize| unsafe {
+        core::ptr::copy_nonoverlapping(src[offset..].as_ptr(), hva as *mut u8, len)
+    };
+    for_each_page(gpm, gpa, src.len(), MemFlags::WRITE, copy)
+}
+
+/// Fill `dst` from the zone's memory at `gpa`.
+pub fn copy_from_guest(
+    gpm: &MemorySet<Stage2PageTable>,
+    gpa: GuestPhysAddr,
+    dst: &mut [u8],
+) -> HvResult {
+    let len = dst.len();
+    let copy = |hva: VirtAddr, offset: usize, len: usize| unsafe {
+        core::ptr::copy_nonoverlapping(hva as *const u8, dst[offset..].as_mut_ptr(), len)
+    };
+    for_each_page(gpm, gpa, len, MemFlags::READ, copy)
+}
+
+/// The bytes of `value`, for handing one of the `#[repr(C)]` hypercall structs to
+/// `copy_to_guest`.
+pub fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Read a `T` from the zone's memory at `gpa`.
+///
+/// # Safety
+///
+/// Any bit pattern must be a valid `T`, as it is for the structs of integers hypercalls
+/// exchange, the guest chooses them.
+pub unsafe fn read_from_guest<T: Copy>(
+    gpm: &MemorySet<Stage2PageTable>,
+    gpa: GuestPhysAddr,
+) -> HvResult<T> {
+    let mut value = core::mem::MaybeUninit::<T>::zeroed();
+    let bytes = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>());
+    copy_from_guest(gpm, gpa, bytes)?;
+    Ok(value.assume_init())
+}
@@ -0,0 +1,117 @@
+//! Per-SPI locks serializing what the hypervisor does to the shared active state of a
+//! physical SPI. SGIs and PPIs are banked per cpu and need none.
+//!
+//! A lock is held from the duplicate scan of an injection up to the write of its hardware
+//! linked list register, and around the hypervisor deactivating the SPI, so it is never
+//! deactivated in the middle of its injection.
+//!
+//! Lock order: a zone's lock and the inject filter list come before an SPI lock, the pending
+//! queues and the LR pins after it. So callers look up the zone's settings before locking,
+//! code holding a zone's lock for writing must drop it before anything that deactivates an
+//! irq, and no cpu holds two SPI locks.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Once;
+
+use super::{is_spi, GICV3_ESPI_BASE};
+
+// one bit for each SPI, the extended SPIs follow the 1024 regular INTIDs
+static SPI_LOCKS: Once<Vec<AtomicU32>> = Once::new();
+
+pub fn init() {
+    SPI_LOCKS.call_once(|| (0..2048 / 32).map(|_| AtomicU32::new(0)).collect());
+}
+
+fn lock_bit(irq_id: usize) -> (&'static AtomicU32, u32) {
+    let index = if irq_id >= GICV3_ESPI_BASE as usize {
+        1024 + irq_id - GICV3_ESPI_BASE as usize
+    } else {
+        irq_id
+    };
+    (&SPI_LOCKS.get().unwrap()[index / 32], 1 << (index % 32))
+}
+
+/// Held lock of one SPI, released when dropped.
+pub struct SpiGuard(usize);
+
+/// Lock physical irq `irq_id`, or nothing if it isn't an SPI.
+pub fn lock(irq_id: usize) -> Option<SpiGuard> {
+    if !is_spi(irq_id as _) {
+        return None;
+    }
+    let (word, bit) = lock_bit(irq_id);
+    while word.fetch_or(bit, Ordering::Acquire) & bit != 0 {
+        core::hint::spin_loop();
+    }
+    Some(SpiGuard(irq_id))
+}
+
+impl Drop for SpiGuard {
+    fn drop(&mut self) {
+        let (word, bit) = lock_bit(self.0);
+        word.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing;
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use std::{sync::Arc, thread};
+
+    const SPI: usize = 48;
+
+    // Holders of the lock of one SPI exclude each other: a holder never finds the flag the
+    // others set while they hold it. This covers the lock itself, not the injection and
+    // deactivation paths taking it.
+    #[test]
+    fn spi_lock_is_exclusive() {
+        init();
+        let injecting = Arc::new(AtomicBool::new(false));
+        let deactivations = Arc::new(AtomicU32::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|cpu| {
+                let injecting = injecting.clone();
+                let deactivations = deactivations.clone();
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let _guard = lock(SPI).unwrap();
+                        if cpu % 2 == 0 {
+                            assert!(!injecting.swap(true, Ordering::Relaxed));
+                            core::hint::spin_loop();
+                            injecting.store(false, Ordering::Relaxed);
+                        } else {
+                            assert!(!injecting.load(Ordering::Relaxed));
+                            deactivations.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(deactivations.load(Ordering::Relaxed), 20_000);
+    }
+
+    #[test]
+    fn only_spis_are_locked() {
+        // with extended SPIs
+        testing::boot(0);
+        assert!(lock(27).is_none());
+        assert!(lock(1020).is_none());
+        let _spi = lock(SPI).unwrap();
+        // another SPI, and the extended SPI with the same offset, have their own locks
+        let _other = lock(SPI + 1).unwrap();
+        let _espi = lock(GICV3_ESPI_BASE as usize + SPI).unwrap();
+    }
+
+    #[test]
+    fn dropping_the_guard_unlocks() {
+        init();
+        drop(lock(SPI + 2).unwrap());
+        let (word, bit) = lock_bit(SPI + 2);
+        assert_eq!(word.load(Ordering::Relaxed) & bit, 0);
+    }
+}
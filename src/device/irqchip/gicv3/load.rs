@@ -0,0 +1,49 @@
+//! Decaying estimate of the irq rate of each vcpu, a hint for the placement of vcpus. Every
+//! injection adds one to the vcpu's load, which halves each `LOAD_HALF_LIFE_US` that passes.
+//! A vcpu getting r irqs per half-life settles at a load of 2r, `vcpu_irq_load` reports r.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::cpu_state::cpu_gic;
+use crate::arch::aarch64::sysreg::read_sysreg;
+
+/// Time after which the weight of an injection has halved.
+pub const LOAD_HALF_LIFE_US: u64 = 10_000;
+
+const LOAD_BITS: u64 = 24;
+const LOAD_MASK: u64 = (1 << LOAD_BITS) - 1;
+
+/// The load of one vcpu: the half-life period it was last updated in, above the load itself.
+#[derive(Debug, Default)]
+pub struct IrqLoad(AtomicU64);
+
+impl IrqLoad {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+}
+
+fn current_period() -> u64 {
+    let ticks = read_sysreg!(cntfrq_el0) * LOAD_HALF_LIFE_US / 1_000_000;
+    read_sysreg!(cntpct_el0) / ticks.max(1)
+}
+
+// `packed` aged to `period`.
+fn decayed(packed: u64, period: u64) -> u64 {
+    let elapsed = period.saturating_sub(packed >> LOAD_BITS);
+    (packed & LOAD_MASK).checked_shr(elapsed as u32).unwrap_or(0)
+}
+
+/// Count an injection into the vcpu on `cpu`, which may be another cpu.
+pub fn count(cpu: usize) {
+    let period = current_period();
+    let _ = cpu_gic(cpu).load.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |packed| {
+        let load = (decayed(packed, period) + 1).min(LOAD_MASK);
+        Some(period << LOAD_BITS | load)
+    });
+}
+
+/// Irqs injected into the vcpu on `cpu` per `LOAD_HALF_LIFE_US`, recent ones weighing more.
+pub fn vcpu_irq_load(cpu: usize) -> u32 {
+    let packed = cpu_gic(cpu).load.0.load(Ordering::Relaxed);
+    (decayed(packed, current_period()) / 2) as u32
+}
@@ -59,6 +59,10 @@
 //!     - When an SGI occurs in a multiprocessor implementation, the CPUID field in the Interrupt
 //!       Acknowledge Register, GICC_IAR, or the Aliased Interrupt Acknowledge Register, GICC_AIAR,
 //!       identifies the processor that requested the interrupt.
+//!     - This is the legacy (GICv2) interface only. With system register access ICC_IAR1_EL1
+//!       returns the bare INTID, and ICH_LR_EL2 only has the source CPUID in vINTID[12:10] for
+//!       a guest running with ICC_SRE_EL1.SRE == 0. Guests here always run with SRE set (see
+//!       `GUEST_ICC_SRE`), so injected SGIs carry no source.
 //!
 //! # Architecture Specification - 2.2.1 Interrupt IDs
 //!
@@ -76,31 +80,331 @@
 //!           - 00..15 SGIs
 //!           - 16..31 PPIs
 #![allow(dead_code)]
+// With no-log only error! is left in the GIC code, the other log macros expand to dead code:
+// their arguments are still type checked, but no call or format string makes it to the binary.
+#[cfg(feature = "no-log")]
+macro_rules! no_log {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(feature = "no-log")]
+macro_rules! warn { ($($arg:tt)*) => { no_log!($($arg)*) }; }
+#[cfg(feature = "no-log")]
+macro_rules! info { ($($arg:tt)*) => { no_log!($($arg)*) }; }
+#[cfg(feature = "no-log")]
+macro_rules! debug { ($($arg:tt)*) => { no_log!($($arg)*) }; }
+#[cfg(feature = "no-log")]
+macro_rules! trace { ($($arg:tt)*) => { no_log!($($arg)*) }; }
+
+#[cfg(feature = "bench")]
+mod bench;
+pub mod audit;
+pub mod cpu_state;
+pub mod filter;
 pub mod gicd;
+#[cfg(feature = "gicd_write_combine")]
+pub mod gicd_wc;
 pub mod gicr;
+pub mod guest_mem;
+mod irq_lock;
+pub mod load;
+pub mod pending;
+pub mod pin;
+pub mod pv;
+pub mod stats;
+pub mod storm;
+pub mod summary;
+#[cfg(test)]
+pub mod testing;
 pub mod vgic;
+pub mod vits;
+pub mod watchdog;
 
 use core::arch::asm;
 use core::ptr::write_volatile;
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use spin::Once;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
 
-use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER};
-use self::gicr::enable_ipi;
+use self::gicd::{
+    enable_gic_are_ns, read_espi_num, read_nmi_support, read_single_security, reset_distributor,
+    GICDV3_PIDR2, GICD_ICACTIVER, GICD_ICACTIVERNE, GICD_ICENABLER, GICD_ICENABLERNE, GICD_IIDR,
+    GICD_IPRIORITYR, GICD_IPRIORITYRNE, GICD_IROUTER_IRM, GICD_ISENABLER, GICD_ISENABLERNE,
+    GICD_TYPER, GICD_TYPER_ITLINES_MASK, GICD_TYPER_LPIS,
+};
+use self::gicr::{
+    check_gicr_frame, enable_hv_ppi, enable_ipi, gicr_power_on, read_eppi_num, redist_awake,
+    reset_banked_irqs, wake_redist, GICR_ISENABLER, GICR_SGI_BASE, GICR_TYPER, GICR_TYPER_VLPIS,
+};
+use self::audit::AuditRing;
+use self::cpu_state::{cpu_gic, this_gic};
+use self::pending::PendingIrq;
+use self::storm::{IrqStorm, StormConfig};
+use self::watchdog::WatchdogConfig;
+use crate::arch::aarch64::cpu::{mpidr_to_cpuid, this_cpu_id};
+use crate::arch::zone::HvArchZoneConfig;
 use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
 use crate::config::root_zone_config;
 use crate::consts::MAX_CPU_NUM;
+use crate::error::HvResult;
 
-use crate::event::check_events;
+use crate::event::{check_events, send_event, IPI_EVENT_FLUSH_PENDING_IRQS};
 use crate::hypercall::SGI_IPI_ID;
-use crate::zone::Zone;
+use crate::percpu::{get_cpu_data, this_cpu_data, CpuSet, PerCpu};
+use crate::work::{queue_work, Work};
+use crate::zone::{find_irq_owner, find_zone, register_zone_hooks, Zone, ZoneHooks};
+
+bitflags::bitflags! {
+    /// ICH_HCR_EL2 controls of the virtual cpu interface.
+    #[derive(Clone, Copy, Debug)]
+    pub struct HcrConfig: u64 {
+        /// Enable the virtual cpu interface.
+        const EN =          1 << 0;
+        /// Underflow maintenance irq: at most one list register holds an irq.
+        const UIE =         1 << 1;
+        /// Maintenance irq while EOIcount is non-zero.
+        const LRENPIE =     1 << 2;
+        /// No pending maintenance irq: no list register is pending.
+        const NPIE =        1 << 3;
+        const VGRP0EIE =    1 << 4;
+        const VGRP0DIE =    1 << 5;
+        const VGRP1EIE =    1 << 6;
+        const VGRP1DIE =    1 << 7;
+        /// Trap the common ICC_* registers, SGI generation included.
+        const TC =          1 << 10;
+        /// Trap the group 0 ICC_* registers.
+        const TALL0 =       1 << 11;
+        /// Trap the group 1 ICC_* registers.
+        const TALL1 =       1 << 12;
+        const TSEI =        1 << 13;
+        const TDIR =        1 << 14;
+    }
+}
+
+// written to ICH_HCR_EL2 by every cpu in `gicc_init`
+static HCR_CONFIG: Mutex<HcrConfig> = Mutex::new(HcrConfig::EN);
+
+/// Request `flags` in ICH_HCR_EL2 of the cpus initialized from now on.
+pub fn hcr_config_insert(flags: HcrConfig) {
+    HCR_CONFIG.lock().insert(flags);
+}
+
+/// Set or clear `flags` in ICH_HCR_EL2 of this cpu only.
+pub fn set_local_hcr(flags: HcrConfig, value: bool) {
+    let mut hcr = HcrConfig::from_bits_retain(read_sysreg!(ich_hcr_el2));
+    hcr.set(flags, value);
+    write_sysreg!(ich_hcr_el2, hcr.bits());
+}
+
+/// Set up the virtual cpu interface of this cpu for the vcpu of its zone, before the vcpu
+/// runs. A zone with fewer priority bits than the hardware has the common ICC_* registers
+/// trapped (ICH_HCR_EL2.TC), so the hypervisor masks its ICC_PMR_EL1 writes and reports its
+/// priority bits in ICC_CTLR_EL1. The group registers are trapped as the zone asks for, see
+/// `Vgicv3::group_traps`. The vcpu's watchdog timeout is taken from its zone as well.
+pub fn gicv3_vcpu_init() {
+    let hw_bits = this_gic().vtr.priority_bits;
+    let (reduced, group_traps) = match &this_cpu_data().zone {
+        Some(zone) => {
+            let vgic = &zone.read().vgicv3;
+            (vgic.vpri_bits(hw_bits) < hw_bits, vgic.group_traps)
+        }
+        None => (false, HcrConfig::empty()),
+    };
+    let config = *HCR_CONFIG.lock();
+    set_local_hcr(HcrConfig::TC, reduced || config.contains(HcrConfig::TC));
+    let all_groups = HcrConfig::TALL0 | HcrConfig::TALL1;
+    set_local_hcr(all_groups, false);
+    set_local_hcr(group_traps | config & all_groups, true);
+    watchdog::vcpu_init();
+}
+
+const ICH_VMCR_VPMR_SHIFT: u64 = 24;
+const ICH_VMCR_VPMR_MASK: u64 = 0xff << ICH_VMCR_VPMR_SHIFT;
+const ICH_VMCR_VEOIM: u64 = 1 << 9;
+const ICH_VMCR_VCBPR: u64 = 1 << 4;
+const ICH_VMCR_VENG0: u64 = 1 << 0;
+const ICH_VMCR_VENG1: u64 = 1 << 1;
+const ICH_VMCR_VBPR0_SHIFT: u64 = 21;
+const ICH_VMCR_VBPR1_SHIFT: u64 = 18;
+const ICH_VMCR_VBPR_MASK: u64 = 0b111;
+
+fn vmcr_group_enable(group: IrqGroup) -> u64 {
+    match group {
+        IrqGroup::Group0 => ICH_VMCR_VENG0,
+        IrqGroup::Group1 => ICH_VMCR_VENG1,
+    }
+}
+
+/// Whether the guest enabled `group` on this cpu, what it reads from ICC_IGRPEN<n>_EL1.
+pub fn read_vgrpen(group: IrqGroup) -> bool {
+    read_sysreg!(ich_vmcr_el2) & vmcr_group_enable(group) != 0
+}
+
+/// Enable or disable the guest's `group` on this cpu, the physical interface is left alone.
+pub fn write_vgrpen(group: IrqGroup, enable: bool) {
+    let vmcr = read_sysreg!(ich_vmcr_el2) & !vmcr_group_enable(group);
+    let bit = if enable { vmcr_group_enable(group) } else { 0 };
+    write_sysreg!(ich_vmcr_el2, vmcr | bit);
+}
+
+fn vmcr_bpr_shift(group: IrqGroup) -> u64 {
+    match group {
+        IrqGroup::Group0 => ICH_VMCR_VBPR0_SHIFT,
+        IrqGroup::Group1 => ICH_VMCR_VBPR1_SHIFT,
+    }
+}
+
+// The smallest binary point of `group` with the preemption bits of the virtual interface.
+fn min_vbpr(group: IrqGroup) -> u64 {
+    let min_bpr0 = 7 - this_gic().vtr.preemption_bits as u64;
+    match group {
+        IrqGroup::Group0 => min_bpr0,
+        IrqGroup::Group1 => min_bpr0 + 1,
+    }
+}
+
+/// The binary point of `group` the guest reads from ICC_BPR<n>_EL1, only trapped with
+/// ICH_HCR_EL2.TALL<n>. With ICC_CTLR_EL1.CBPR set group 1 goes by the one of group 0.
+pub fn read_vbpr(group: IrqGroup) -> u64 {
+    let vmcr = read_sysreg!(ich_vmcr_el2);
+    let bpr = |group| (vmcr >> vmcr_bpr_shift(group)) & ICH_VMCR_VBPR_MASK;
+    if group == IrqGroup::Group1 && vmcr & ICH_VMCR_VCBPR != 0 {
+        return (bpr(IrqGroup::Group0) + 1).min(7);
+    }
+    bpr(group)
+}
+
+/// Take the guest's write of ICC_BPR<n>_EL1, raised to the smallest binary point of the
+/// virtual interface. Group 1 writes are ignored while ICC_CTLR_EL1.CBPR is set.
+pub fn write_vbpr(group: IrqGroup, bpr: u64) {
+    let vmcr = read_sysreg!(ich_vmcr_el2);
+    if group == IrqGroup::Group1 && vmcr & ICH_VMCR_VCBPR != 0 {
+        return;
+    }
+    let shift = vmcr_bpr_shift(group);
+    let bpr = (bpr & ICH_VMCR_VBPR_MASK).max(min_vbpr(group));
+    let vmcr = vmcr & !(ICH_VMCR_VBPR_MASK << shift);
+    write_sysreg!(ich_vmcr_el2, vmcr | bpr << shift);
+}
+
+/// Priority mask of the guest on this cpu, what it reads from ICC_PMR_EL1.
+pub fn read_vpmr() -> u64 {
+    (read_sysreg!(ich_vmcr_el2) & ICH_VMCR_VPMR_MASK) >> ICH_VMCR_VPMR_SHIFT
+}
+
+/// Set the guest's priority mask, independent of whether it enabled group 1 yet.
+pub fn write_vpmr(pmr: u64) {
+    let pmr = pmr & vpri_mask() as u64;
+    let vmcr = read_sysreg!(ich_vmcr_el2) & !ICH_VMCR_VPMR_MASK;
+    write_sysreg!(ich_vmcr_el2, vmcr | (pmr << ICH_VMCR_VPMR_SHIFT));
+}
+
+// Mask of the priority bits the vcpu on this cpu implements, see `Vgicv3::pri_bits`.
+fn vpri_mask() -> u8 {
+    match &this_cpu_data().zone {
+        Some(zone) => zone.read().vgicv3.priority_mask(this_gic().vtr.priority_bits),
+        None => 0xff,
+    }
+}
+
+const ICC_CTLR_CBPR: u64 = 1 << 0;
+const ICC_CTLR_EOIMODE: u64 = 1 << 1;
+const ICC_CTLR_PRIBITS_SHIFT: u64 = 8;
+const ICC_CTLR_IDBITS_SHIFT: u64 = 11;
+const ICC_CTLR_SEIS: u64 = 1 << 14;
+const ICC_CTLR_A3V: u64 = 1 << 15;
+
+/// The ICC_CTLR_EL1 the guest reads on this cpu, only trapped with ICH_HCR_EL2.TC. The physical
+/// register keeps the hypervisor's EOImode 1, the guest's CBPR and EOImode live in
+/// ICH_VMCR_EL2, which is what `virtual_eoi` goes by. The read-only fields describe the virtual
+/// cpu interface from ICH_VTR_EL2.
+pub fn read_vctlr() -> u64 {
+    let vmcr = read_sysreg!(ich_vmcr_el2);
+    let vtr = this_gic().vtr;
+    let pri_bits = vpri_mask().count_ones() as u64;
+    // IDbits is 0 for 16 and 1 for 24 bits, like in ICH_VTR_EL2
+    let id_bits = (vtr.id_bits == 24) as u64;
+    let mut ctlr = (pri_bits - 1) << ICC_CTLR_PRIBITS_SHIFT | id_bits << ICC_CTLR_IDBITS_SHIFT;
+    if vtr.seis {
+        ctlr |= ICC_CTLR_SEIS;
+    }
+    if vtr.a3v {
+        ctlr |= ICC_CTLR_A3V;
+    }
+    if vmcr & ICH_VMCR_VCBPR != 0 {
+        ctlr |= ICC_CTLR_CBPR;
+    }
+    if vmcr & ICH_VMCR_VEOIM != 0 {
+        ctlr |= ICC_CTLR_EOIMODE;
+    }
+    ctlr
+}
+
+/// Take the guest's write of ICC_CTLR_EL1 on this cpu, only CBPR and EOImode are writable.
+pub fn write_vctlr(ctlr: u64) {
+    let mut vmcr = read_sysreg!(ich_vmcr_el2) & !(ICH_VMCR_VCBPR | ICH_VMCR_VEOIM);
+    if ctlr & ICC_CTLR_CBPR != 0 {
+        vmcr |= ICH_VMCR_VCBPR;
+    }
+    if ctlr & ICC_CTLR_EOIMODE != 0 {
+        vmcr |= ICH_VMCR_VEOIM;
+    }
+    write_sysreg!(ich_vmcr_el2, vmcr);
+}
+
+const ICC_SRE_SRE: u64 = 1 << 0;
+const ICC_SRE_DFB: u64 = 1 << 1;
+const ICC_SRE_DIB: u64 = 1 << 2;
+const ICC_SRE_EL2_ENABLE: u64 = 1 << 3;
+
+/// The ICC_SRE_EL1 guests see: system register access on for good, the legacy memory mapped
+/// cpu interface isn't available to them.
+pub const GUEST_ICC_SRE: u64 = ICC_SRE_SRE | ICC_SRE_DFB | ICC_SRE_DIB;
+
+// Make sure the system register interface is in use at EL2, and trap the guest's accesses to
+// ICC_SRE_EL1 (Enable = 0) so it can't turn it off.
+fn sre_init() {
+    let sre = read_sysreg!(icc_sre_el2);
+    if sre & ICC_SRE_SRE == 0 {
+        warn!("icc_sre_el2.SRE not set by firmware, sre = {:#x}", sre);
+    }
+    write_sysreg!(icc_sre_el2, (sre | ICC_SRE_SRE) & !ICC_SRE_EL2_ENABLE);
+    unsafe { asm!("isb") };
+    assert!(
+        read_sysreg!(icc_sre_el2) & ICC_SRE_SRE != 0,
+        "gic system register interface unavailable"
+    );
+}
+
+// The cpu interface should come out of firmware disabled, an enabled one may deliver irqs
+// before the init below has set it up. ICC_IGRPEN0_EL1 is only read with hv_ipi_fiq, which
+// needs group 0 accessible: with SCR_EL3.FIQ set the access traps to EL3.
+fn check_reset_state() {
+    let igrpen1 = read_sysreg!(icc_igrpen1_el1);
+    let igrpen0 = if hv_ipi_fiq() {
+        read_sysreg!(icc_igrpen0_el1)
+    } else {
+        0
+    };
+    let ich_hcr = read_sysreg!(ich_hcr_el2);
+    if igrpen1 & 1 != 0 || igrpen0 & 1 != 0 || ich_hcr & 1 != 0 {
+        warn!(
+            "gic cpu interface not in reset state: igrpen0 = {:#x}, igrpen1 = {:#x}, ich_hcr = {:#x}",
+            igrpen0, igrpen1, ich_hcr
+        );
+    }
+}
 
 //TODO: add Distributor init
 pub fn gicc_init() {
     //TODO: add Redistributor init
-    let sdei_ver = unsafe { smc_arg1!(0xc4000020) }; //sdei_check();
-
+    this_cpu_data().gic.init();
+    sre_init();
+    check_reset_state();
     // Make ICC_EOIR1_EL1 provide priority drop functionality only. ICC_DIR_EL1 provides interrupt deactivation functionality.
     let _ctlr = read_sysreg!(icc_ctlr_el1);
     write_sysreg!(icc_ctlr_el1, 0x2);
@@ -110,64 +414,328 @@ pub fn gicc_init() {
     // Enable group 1 irq
     let _igrpen = read_sysreg!(icc_igrpen1_el1);
     write_sysreg!(icc_igrpen1_el1, 0x1);
+    // the hypervisor IPI is the only group 0 irq
+    if hv_ipi_fiq() {
+        write_sysreg!(icc_igrpen0_el1, 0x1);
+    }
 
     gicv3_clear_pending_irqs();
-    let _vtr = read_sysreg!(ich_vtr_el2);
     let vmcr = ((pmr & 0xff) << 24) | (1 << 1) | (1 << 9); //VPMR|VENG1|VEOIM
     write_sysreg!(ich_vmcr_el2, vmcr);
-    write_sysreg!(ich_hcr_el2, 0x1); //enable virt cpu interface
+    let hcr = HcrConfig::EN | *HCR_CONFIG.lock();
+    write_sysreg!(ich_hcr_el2, hcr.bits()); //enable virt cpu interface
 
-    info!("gicc init done, sdei_ver = {}", sdei_ver);
+    info!("gicc init done");
+}
+
+/// Tear down the virtual and then the physical cpu interface of this cpu, `gicc_init` in
+/// reverse. Each step leaves nothing behind the next one could signal: with the guest's group
+/// enables off the LRs can't raise a virtual irq while they are cleared, with the LRs and
+/// active priorities clear the interface has nothing to raise a maintenance irq for when
+/// ICH_HCR_EL2.En goes, and the physical interface is only disabled once no virtual state
+/// depends on it anymore.
+pub fn gicv3_cpu_shutdown() {
+    shutdown_cpu_interface(lr_count(), apr_count(), hv_ipi_fiq());
+    pending::clear(this_cpu_id());
+    info!("gicc shutdown done");
+}
+
+// The register writes of `gicv3_cpu_shutdown`, for `lrs` list registers and `aprs` active
+// priority registers of each group. Group 0 is only disabled if the hypervisor takes it.
+fn shutdown_cpu_interface(lrs: usize, aprs: usize, group0: bool) {
+    let vmcr = read_sysreg!(ich_vmcr_el2) & !(ICH_VMCR_VENG0 | ICH_VMCR_VENG1);
+    write_sysreg!(ich_vmcr_el2, vmcr);
+    unsafe { asm!("isb") };
+    // the guest never deactivates the hardware irqs of its LRs now
+    force_deactivate_lrs(lrs);
+    clear_lrs_and_aprs(lrs, aprs);
+    write_sysreg!(ich_hcr_el2, 0);
+    unsafe { asm!("isb") };
+
+    if group0 {
+        write_sysreg!(icc_igrpen0_el1, 0);
+    }
+    write_sysreg!(icc_igrpen1_el1, 0);
+    unsafe { asm!("isb") };
+}
+
+// cpus done with `gicv3_cpu_shutdown` during `gicv3_system_shutdown`
+static CPUS_SHUT_DOWN: AtomicUsize = AtomicUsize::new(0);
+// how long the other cpus get to shut down their interfaces
+const SYSTEM_SHUTDOWN_TIMEOUT_US: u64 = 100_000;
+
+// Run by every other cpu from its work ring, it never gets back to its guest.
+fn park_after_shutdown(_: usize) {
+    gicv3_cpu_shutdown();
+    CPUS_SHUT_DOWN.fetch_add(1, Ordering::Release);
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+/// Tear down the GIC of the whole system for a reset or the next boot stage (firmware or
+/// kexec). Every other cpu shuts down its cpu interface and parks, this one waits for them,
+/// shuts down its own interface and resets the distributor. Cpus which don't answer in time
+/// are reported, the distributor is reset without them.
+pub fn gicv3_system_shutdown() {
+    let this_cpu = this_cpu_id();
+    CPUS_SHUT_DOWN.store(0, Ordering::Relaxed);
+    let mut expected = 0;
+    for cpu in (0..PerCpu::entered_cpus() as usize).filter(|&cpu| cpu != this_cpu) {
+        match queue_work(cpu, Work::Call(park_after_shutdown, 0)) {
+            Ok(()) => expected += 1,
+            Err(_) => warn!("system shutdown: work ring of cpu {} full", cpu),
+        }
+    }
+    let end = read_sysreg!(cntpct_el0)
+        + SYSTEM_SHUTDOWN_TIMEOUT_US * read_sysreg!(cntfrq_el0) / 1_000_000;
+    while CPUS_SHUT_DOWN.load(Ordering::Acquire) < expected && read_sysreg!(cntpct_el0) < end {
+        core::hint::spin_loop();
+    }
+    let done = CPUS_SHUT_DOWN.load(Ordering::Acquire);
+    if done < expected {
+        warn!("system shutdown: only {} of {} cpus shut down their gic", done, expected);
+    }
+    gicv3_cpu_shutdown();
+    reset_distributor(host_gicd_base());
+    info!("gic system shutdown done");
 }
 
 fn gicv3_clear_pending_irqs() {
-    let vtr = read_sysreg!(ich_vtr_el2) as usize;
-    let lr_num: usize = (vtr & 0xf) + 1;
-    for i in 0..lr_num {
-        write_lr(i, 0) //clear lr
+    clear_lrs_and_aprs(lr_count(), apr_count());
+}
+
+// Empty the first `lrs` list registers and the `aprs` active priority registers of both groups,
+// a group 0 irq active at a teardown would hold back the next vcpu's irqs otherwise.
+fn clear_lrs_and_aprs(lrs: usize, aprs: usize) {
+    for i in 0..lrs {
+        write_lr(i, 0)
+    }
+    for n in 0..aprs {
+        write_apr(IrqGroup::Group0, n, 0);
+        write_apr(IrqGroup::Group1, n, 0);
+    }
+}
+
+/// Number of active priority registers of each group on this cpu, see `PerCpuGic`.
+pub fn apr_count() -> usize {
+    this_gic().apr_num
+}
+
+/// Reset the GIC state private to the vcpu running on `cpu`: its banked SGIs/PPIs in the
+/// redistributor, list registers, active priorities and deferred irqs. The distributor and
+/// other vcpus are left untouched.
+pub fn gicv3_reset_vcpu(cpu: usize) {
+    // list registers are only accessible from their own cpu
+    assert_eq!(cpu, this_cpu_id());
+    force_deactivate_hw_irqs();
+    reset_banked_irqs(cpu);
+    gicv3_clear_pending_irqs();
+    pending::clear(cpu);
+    pv::reset(cpu);
+    this_gic().boosts.lock().clear();
+}
+
+/// Deactivate the physical irqs behind the active list registers of this cpu, which the guest
+/// will never deactivate now that its zone goes away. Otherwise they would stay active and
+/// never fire again for the next zone they are assigned to. The SPIs of a zone are also
+/// deactivated at the distributor by `arch_irqchip_reset`, which may run before this cpu got
+/// to leave the guest.
+pub fn force_deactivate_hw_irqs() {
+    force_deactivate_lrs(lr_count());
+}
+
+fn force_deactivate_lrs(lrs: usize) {
+    for i in 0..lrs {
+        let lr_val = read_lr(i);
+        if lr_val & LR_STATE_ACTIVE == 0 {
+            continue;
+        }
+        let irq_id = (lr_val & LR_VIRTIRQ_MASK) as usize;
+        // a physical irq without the HW bit is deactivated by complete_lr_eois otherwise. The
+        // zone is already detached from this cpu, so its hw_unmapped set isn't available.
+        let virtual_only =
+            lr_val & LR_EOI != 0 && !is_sgi(irq_id as _) && eoi_callback(irq_id).is_none();
+        if lr_val & LR_HW != 0 || virtual_only {
+            debug!("force deactivating physical irq {} of lr {}", lr_pintid(lr_val), i);
+            deactivate_lr_irq(lr_val);
+        }
+    }
+}
+
+/// PPI raised by the virtual cpu interface, never forwarded to guests.
+pub const MAINTENANCE_IRQ: usize = 25;
+// above the guests' irqs like the EL2 timer, so an irq the zone handles doesn't hold back the
+// EOI maintenance of the list registers
+const MAINTENANCE_PRIORITY: u8 = 0x10;
+/// PPI of the EL2 physical timer, owned by the hypervisor.
+pub const HV_TIMER_IRQ: usize = 26;
+/// Read from ICC_IAR0_EL1 at EL3 when the highest priority pending irq is secure group 1.
+const INTID_SECURE_GROUP1: usize = 1020;
+/// Read from ICC_IAR0_EL1 at EL3 when the highest priority pending irq is non-secure group 1.
+const INTID_NONSECURE_GROUP1: usize = 1021;
+/// Read from ICC_IAR1_EL1 with GICv3.3 NMIs when the irq to acknowledge is an NMI.
+const INTID_NMI_PENDING: usize = 1022;
+/// No pending irq the read may acknowledge.
+const INTID_SPURIOUS: usize = 1023;
+
+bitflags::bitflags! {
+    /// ICH_MISR_EL2, the conditions a maintenance irq was raised for.
+    #[derive(Clone, Copy, Debug)]
+    pub struct MisrFlags: u64 {
+        const EOI =     1 << 0;
+        const U =       1 << 1;
+        const LRENP =   1 << 2;
+        const NP =      1 << 3;
+        const VGRP0E =  1 << 4;
+        const VGRP0D =  1 << 5;
+        const VGRP1E =  1 << 6;
+        const VGRP1D =  1 << 7;
     }
-    let num_priority_bits = (vtr >> 29) + 1;
-    /* Clear active priority bits */
-    if num_priority_bits >= 5 {
-        write_sysreg!(ICH_AP1R0_EL2, 0); //Interrupt Controller Hyp Active Priorities Group 1 Register 0 No interrupt active
+}
+
+impl MisrFlags {
+    pub fn from_raw(misr: u64) -> Self {
+        Self::from_bits_truncate(misr)
     }
-    if num_priority_bits >= 6 {
-        write_sysreg!(ICH_AP1R1_EL2, 0);
+}
+
+impl core::fmt::Display for MisrFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        const NAMES: [(MisrFlags, &str); 8] = [
+            (MisrFlags::EOI, "EOI"),
+            (MisrFlags::U, "U"),
+            (MisrFlags::LRENP, "LRENP"),
+            (MisrFlags::NP, "NP"),
+            (MisrFlags::VGRP0E, "VGrp0E"),
+            (MisrFlags::VGRP0D, "VGrp0D"),
+            (MisrFlags::VGRP1E, "VGrp1E"),
+            (MisrFlags::VGRP1D, "VGrp1D"),
+        ];
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        let mut first = true;
+        for (flag, name) in NAMES {
+            if self.contains(flag) {
+                write!(f, "{}{}", if first { "" } else { "|" }, name)?;
+                first = false;
+            }
+        }
+        Ok(())
     }
-    if num_priority_bits > 6 {
-        write_sysreg!(ICH_AP1R2_EL2, 0);
-        write_sysreg!(ICH_AP1R3_EL2, 0);
+}
+
+// The maintenance irq is level triggered, its cause has to go away before returning.
+fn handle_maintenance_irq() {
+    let misr = MisrFlags::from_raw(read_sysreg!(ich_misr_el2));
+    trace!("maintenance irq, misr = {}", misr);
+    stats::count(|s| &s.maintenance);
+    if misr.contains(MisrFlags::EOI) {
+        complete_lr_eois();
     }
+    flush_pending_irqs();
 }
 
 static TIMER_INTERRUPT_COUNTER: AtomicU64 = AtomicU64::new(0);
 // how often to print timer interrupt counter
 const TIMER_INTERRUPT_PRINT_TIMES: u64 = 50;
+// irqs handled per trap at most, the rest stay pending until the next one
+const MAX_IRQS_PER_TRAP: usize = 16;
 
 pub fn gicv3_handle_irq_el1() {
-    if let Some(irq_id) = pending_irq() {
-        // enum ipi_msg_type {
-        //     IPI_WAKEUP,
-        //     IPI_TIMER,
-        //     IPI_RESCHEDULE,
-        //     IPI_CALL_FUNC,
-        //     IPI_CPU_STOP,
-        //     IPI_IRQ_WORK,
-        //     IPI_COMPLETION,
-        //     /*
-        //      * CPU_BACKTRACE is special and not included in NR_IPI
-        //      * or tracable with trace_ipi_*
-        //      */
-        //     IPI_CPU_BACKTRACE,
-        //     /*
-        //      * SGI8-15 can be reserved by secure firmware, and thus may
-        //      * not be usable by the kernel. Please keep the above limited
-        //      * to at most 8 entries.
-        //      */
-        // };
-        //SGI
-        if irq_id < 8 {
-            deactivate_irq(irq_id);
+    // the irq may depend on distributor configuration the guest wrote
+    #[cfg(feature = "gicd_write_combine")]
+    gicd_wc::flush();
+    poll_irq_storm();
+    drain_pending(MAX_IRQS_PER_TRAP);
+    flush_pending_irqs();
+    check_interface_idle();
+    trace!("handle done")
+}
+
+/// Group 0 irqs, only the hypervisor IPI is one of them with hv_ipi_fiq.
+pub fn gicv3_handle_fiq_el1() {
+    while let Some(irq_id) = pending_irq(IrqGroup::Group0) {
+        if irq_id != SGI_IPI_ID as usize {
+            warn!("skip group 0 irq {}", irq_id);
+            priority_drop(irq_id, IrqGroup::Group0);
+            deactivate(irq_id);
+            continue;
+        }
+        deactivate_irq(irq_id, IrqGroup::Group0);
+        if !check_events() {
+            trace!("sgi get {}, inject", irq_id);
+            inject_irq(irq_id, false);
+        }
+    }
+    flush_pending_irqs();
+    check_interface_idle();
+}
+
+// Count an SPI arrival against the storm limits of the zone it arrived for. The cpu has its
+// timer fire when the cooldown of an SPI it masks ends.
+fn record_irq_storm(irq_id: usize) {
+    if let Some(zone) = &this_cpu_data().zone {
+        let zone_r = zone.read();
+        let mut storm = zone_r.vgicv3.storm.lock();
+        if storm.record(irq_id) {
+            let deadline = storm.next_deadline().unwrap_or(0);
+            this_gic().storm_deadline.store(deadline, Ordering::Relaxed);
+        }
+    }
+}
+
+// Enable the SPIs of this cpu's zone whose storm cooldown is over.
+fn poll_irq_storm() {
+    if let Some(zone) = &this_cpu_data().zone {
+        let zone_r = zone.read();
+        let mut storm = zone_r.vgicv3.storm.lock();
+        if storm.has_masked() {
+            storm.poll();
+        }
+        let deadline = &this_gic().storm_deadline;
+        if deadline.load(Ordering::Relaxed) != 0 {
+            deadline.store(storm.next_deadline().unwrap_or(0), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Acknowledge and dispatch up to `max` physical irqs, stopping at the first spurious read.
+/// Returns how many were dispatched.
+pub fn drain_pending(max: usize) -> usize {
+    for handled in 0..max {
+        match pending_irq(IrqGroup::Group1) {
+            Some(irq_id) => dispatch_irq(irq_id),
+            None => return handled,
+        }
+    }
+    max
+}
+
+fn dispatch_irq(irq_id: usize) {
+    // enum ipi_msg_type {
+    //     IPI_WAKEUP,
+    //     IPI_TIMER,
+    //     IPI_RESCHEDULE,
+    //     IPI_CALL_FUNC,
+    //     IPI_CPU_STOP,
+    //     IPI_IRQ_WORK,
+    //     IPI_COMPLETION,
+    //     /*
+    //      * CPU_BACKTRACE is special and not included in NR_IPI
+    //      * or tracable with trace_ipi_*
+    //      */
+    //     IPI_CPU_BACKTRACE,
+    //     /*
+    //      * SGI8-15 can be reserved by secure firmware, and thus may
+    //      * not be usable by the kernel. Please keep the above limited
+    //      * to at most 8 entries.
+    //      */
+    // };
+    match irq_kind(irq_id as _) {
+        IrqKind::Sgi if irq_id < 8 => {
+            deactivate_irq(irq_id, IrqGroup::Group1);
             let mut ipi_handled = false;
             if irq_id == SGI_IPI_ID as _ {
                 trace!("SGI_IPI_ID");
@@ -177,10 +745,23 @@ pub fn gicv3_handle_irq_el1() {
                 trace!("sgi get {}, inject", irq_id);
                 inject_irq(irq_id, false);
             }
-        } else if irq_id < 16 {
+        }
+        IrqKind::Sgi => {
             warn!("skip sgi {}", irq_id);
-            deactivate_irq(irq_id);
-        } else {
+            deactivate_irq(irq_id, IrqGroup::Group1);
+        }
+        IrqKind::Ppi if irq_id == MAINTENANCE_IRQ => {
+            // not forwarded to a guest, so nobody else deactivates it
+            priority_drop(irq_id, IrqGroup::Group1);
+            deactivate(irq_id);
+            handle_maintenance_irq();
+        }
+        IrqKind::Ppi if irq_id == HV_TIMER_IRQ => {
+            watchdog::timer_irq();
+            priority_drop(irq_id, IrqGroup::Group1);
+            deactivate(irq_id);
+        }
+        IrqKind::Ppi | IrqKind::ExtPpi | IrqKind::Spi | IrqKind::ExtSpi => {
             if irq_id == 27 {
                 // virtual timer interrupt
                 TIMER_INTERRUPT_COUNTER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
@@ -192,30 +773,145 @@ pub fn gicv3_handle_irq_el1() {
             //inject phy irq
             if irq_id > 31 {
                 debug!("*** get spi_irq id = {}", irq_id);
+                #[cfg(feature = "irq_route_check")]
+                check_irq_route(irq_id);
+                record_irq_storm(irq_id);
+                summary::count_injection(irq_id);
             }
-            deactivate_irq(irq_id);
+            stats::count(|s| &s.forwarded);
+            // stays active until the guest deactivates the hardware mapped virtual irq
+            priority_drop(irq_id, IrqGroup::Group1);
             inject_irq(irq_id, true);
         }
+        IrqKind::Special => {
+            // special INTIDs are never active, so there is nothing to deactivate
+            warn!("skip special irq {}", irq_id);
+        }
+        IrqKind::Other => {
+            warn!("skip unsupported irq {}", irq_id);
+            priority_drop(irq_id, IrqGroup::Group1);
+            deactivate(irq_id);
+        }
     }
-    trace!("handle done")
 }
 
-fn pending_irq() -> Option<usize> {
-    let iar = read_sysreg!(icc_iar1_el1) as usize;
-    if iar >= 0x3fe {
-        // spurious
-        None
-    } else {
-        Some(iar as _)
+/// Register `callback` to be called with the cpu id each time the virtual interface of
+/// `cpu` becomes idle: every list register free and no deferred irq left.
+pub fn set_idle_callback(cpu: usize, callback: Option<fn(usize)>) {
+    cpu_gic(cpu).idle.lock().callback = callback;
+}
+
+fn check_interface_idle() {
+    let cpu = this_cpu_id();
+    let in_use = lr_count() - free_lr_count();
+    summary::update_lrs_in_use(cpu, in_use);
+    let is_idle = in_use == 0 && pending::is_empty(cpu);
+    let callback = {
+        let mut notifier = this_gic().idle.lock();
+        let became_idle = is_idle && !notifier.idle;
+        notifier.idle = is_idle;
+        notifier.callback.filter(|_| became_idle)
+    };
+    // called without the lock held so it may change the registration
+    if let Some(callback) = callback {
+        callback(cpu);
+    }
+}
+
+// An SPI should only arrive on cpus of the zone owning it, otherwise it is routed wrong.
+#[cfg(feature = "irq_route_check")]
+fn check_irq_route(irq_id: usize) {
+    let owned = match &this_cpu_data().zone {
+        Some(zone) => zone.read().irq_in_zone(irq_id as _),
+        None => false,
+    };
+    if !owned {
+        let irouter_addr = match irq_kind(irq_id as _) {
+            IrqKind::ExtSpi => gicd::GICD_IROUTERNE + (irq_id - GICV3_ESPI_BASE as usize) * 8,
+            _ => gicd::GICD_IROUTER + irq_id * 8,
+        };
+        let irouter = unsafe { ((host_gicd_base() + irouter_addr) as *const u64).read_volatile() };
+        warn!(
+            "spi {} arrived on cpu {} outside its zone, irouter = {:#x}",
+            irq_id,
+            this_cpu_id(),
+            irouter
+        );
+    }
+}
+
+// Group 0 registers are only accessed with hv_ipi_fiq, which needs them accessible: with
+// SCR_EL3.FIQ set the accesses trap to EL3. Without it the hypervisor has no group 0 irqs.
+fn pending_irq(group: IrqGroup) -> Option<usize> {
+    if group == IrqGroup::Group0 && !hv_ipi_fiq() {
+        return None;
+    }
+    let mut iar = match group {
+        IrqGroup::Group0 => read_sysreg!(icc_iar0_el1),
+        IrqGroup::Group1 => read_sysreg!(icc_iar1_el1),
+    } as usize;
+    // the highest priority pending irq is an NMI, only ICC_NMIAR1_EL1 acknowledges it
+    if iar == INTID_NMI_PENDING && group == IrqGroup::Group1 && host_nmi_supported() {
+        iar = read_sysreg!(S3_0_C12_C9_5) as usize; // ICC_NMIAR1_EL1
+    }
+    // extended PPIs and SPIs are numbered above the special INTIDs
+    match iar {
+        INTID_SECURE_GROUP1 | INTID_NONSECURE_GROUP1 | INTID_NMI_PENDING | INTID_SPURIOUS => {
+            let count = summary::count_special_intid(iar);
+            // 1020 and 1021 are only returned at EL3, the group configuration is broken. A
+            // left over 1022 means an NMI ICC_NMIAR1_EL1 couldn't acknowledge.
+            if iar != INTID_SPURIOUS && (count == 1 || count % 1000 == 0) {
+                warn!("special intid {} read from {:?} iar, {} times", iar, group, count);
+            }
+            None
+        }
+        _ => Some(iar as _),
+    }
+}
+
+/// Priority drop of `irq_id` through ICC_EOIR<n>_EL1. With EOImode 1 the irq stays active
+/// until `deactivate` is called on it, or until the guest deactivates its hardware mapped
+/// virtual irq.
+pub fn priority_drop(irq_id: usize, group: IrqGroup) {
+    match group {
+        IrqGroup::Group0 if !hv_ipi_fiq() => warn!("group 0 irq {} without hv_ipi_fiq", irq_id),
+        IrqGroup::Group0 => write_sysreg!(icc_eoir0_el1, irq_id as u64),
+        IrqGroup::Group1 => write_sysreg!(icc_eoir1_el1, irq_id as u64),
+    }
+}
+
+/// Deactivate `irq_id` through ICC_DIR_EL1, after its priority drop.
+pub fn deactivate(irq_id: usize) {
+    write_sysreg!(icc_dir_el1, irq_id as u64);
+}
+
+/// Priority drop of `pintid`, the physical INTID the hypervisor acknowledged, SGIs are
+/// deactivated right away as well. Other irqs are injected hardware mapped and deactivated by
+/// the guest, for those use `deactivate_lr_irq` which looks at the LR's pINTID.
+fn deactivate_irq(pintid: usize, group: IrqGroup) {
+    priority_drop(pintid, group);
+    if pintid < 16 {
+        deactivate(pintid);
     }
 }
 
-fn deactivate_irq(irq_id: usize) {
-    write_sysreg!(icc_eoir1_el1, irq_id as u64);
-    if irq_id < 16 {
-        write_sysreg!(icc_dir_el1, irq_id as u64);
+/// Physical irq behind list register value `lr_val`: the pINTID of a hardware mapped irq,
+/// otherwise the vINTID, as virtual-only irqs are injected with the INTID of their physical
+/// irq.
+fn lr_pintid(lr_val: u64) -> usize {
+    if lr_val & LR_HW != 0 {
+        ((lr_val >> LR_PINTID_SHIFT) & LR_PINTID_MASK) as usize
+    } else {
+        (lr_val & LR_VIRTIRQ_MASK) as usize
     }
-    //write_sysreg!(icc_dir_el1, irq_id as usize);
+}
+
+// Deactivate the physical irq of list register value `lr_val` through ICC_DIR_EL1. It has to
+// be the pINTID: the vINTID of a hardware mapped irq only names the guest's view of it.
+fn deactivate_lr_irq(lr_val: u64) {
+    let pintid = lr_pintid(lr_val);
+    let _guard = irq_lock::lock(pintid);
+    deactivate(pintid);
 }
 
 fn read_lr(id: usize) -> u64 {
@@ -271,123 +967,1931 @@ fn write_lr(id: usize, val: u64) {
     }
 }
 
+/// Inject a virtual irq in the group the zone's guest configured for it.
 pub fn inject_irq(irq_id: usize, is_hardware: bool) {
-    // mask
-    const LR_VIRTIRQ_MASK: usize = (1 << 32) - 1;
+    let group = match &this_cpu_data().zone {
+        Some(zone) => zone.read().vgicv3.irq_group(irq_id),
+        None => IrqGroup::Group1,
+    };
+    inject_irq_to_group(irq_id, is_hardware, group);
+}
 
-    let elsr: u64 = read_sysreg!(ich_elrsr_el2);
-    let vtr = read_sysreg!(ich_vtr_el2) as usize;
-    let lr_num: usize = (vtr & 0xf) + 1;
-    let mut free_ir = -1 as isize;
-    for i in 0..lr_num {
-        // find a free list register
-        if (1 << i) & elsr > 0 {
-            if free_ir == -1 {
-                free_ir = i as isize;
+/// What happens to an SPI which arrives for a vcpu that is off, not booted yet or after
+/// PSCI CPU_OFF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfflineIrqPolicy {
+    /// Keep it pending on the vcpu, the guest gets it once the vcpu boots.
+    HoldPending,
+    DropWithWarn,
+    /// Inject it on the first online vcpu of the zone instead, held pending if there is none.
+    RerouteToOnline,
+}
+
+impl OfflineIrqPolicy {
+    /// The policy `HvArchZoneConfig::offline_irq_policy` selects, unknown values hold irqs.
+    pub fn from_config(policy: usize) -> Self {
+        match policy {
+            1 => Self::DropWithWarn,
+            2 => Self::RerouteToOnline,
+            _ => Self::HoldPending,
+        }
+    }
+}
+
+// Apply the zone's `OfflineIrqPolicy` to an SPI arriving on this cpu while its vcpu is off.
+// Returns whether the irq was dealt with and is not to be injected here.
+fn handle_offline_irq(irq: PendingIrq) -> bool {
+    let PendingIrq {
+        irq_id,
+        is_hardware,
+        ..
+    } = irq;
+    let cpu_data = this_cpu_data();
+    if cpu_data.arch_cpu.psci_on || !is_spi(irq_id as _) {
+        return false;
+    }
+    let Some(zone) = &cpu_data.zone else {
+        return false;
+    };
+    let (policy, cpu_set) = {
+        let zone = zone.read();
+        (zone.vgicv3.offline_irq_policy, zone.cpu_set)
+    };
+    match policy {
+        OfflineIrqPolicy::HoldPending => false,
+        OfflineIrqPolicy::DropWithWarn => {
+            warn!("drop irq {} for offline vcpu on cpu {}", irq_id, cpu_data.id);
+            if is_hardware {
+                let _guard = irq_lock::lock(irq_id);
+                deactivate(irq_id);
             }
-            continue;
+            true
         }
-        let lr_val = read_lr(i) as usize;
-        // if a virtual interrupt is enabled and equals to the physical interrupt irq_id
-        if (lr_val & LR_VIRTIRQ_MASK) == irq_id {
-            trace!("virtual irq {} enables again", irq_id);
-            return;
+        OfflineIrqPolicy::RerouteToOnline => {
+            let online = cpu_set.iter().find(|&cpu| get_cpu_data(cpu).arch_cpu.psci_on);
+            let Some(target) = online else {
+                return false;
+            };
+            trace!("reroute irq {} from offline cpu {} to {}", irq_id, cpu_data.id, target);
+            inject_irq_on(target, irq);
+            true
         }
     }
-    // debug!("To Inject IRQ {}, find lr {}", irq_id, free_ir);
+}
 
-    if free_ir == -1 {
-        panic!("full lr");
-    } else {
-        let mut val = irq_id as u64; //v intid
-        val |= 1 << 60; //group 1
-        val |= 1 << 62; //state pending
+/// Inject a virtual irq as part of `group`, group 0 irqs are signaled to the guest as FIQs.
+pub fn inject_irq_to_group(irq_id: usize, is_hardware: bool, group: IrqGroup) {
+    inject_irq_as(irq_id, is_hardware, group, false);
+}
 
-        if !is_sgi(irq_id as _) && is_hardware {
-            val |= 1 << 61; //map hardware
-            val |= (irq_id as u64) << 32; //pINTID
+/// Inject a virtual group 1 irq as a GICv3.3 NMI: the list register gets its NMI bit, the
+/// guest can't mask it with its priority mask or PSTATE.I (with SCTLR_EL1.NMI set) and
+/// acknowledges it through ICC_NMIAR1_EL1, which the virtual interface handles itself.
+/// Without NMI support in both the GIC and the cpus the bit is RES0 and this fails.
+pub fn inject_nmi(irq_id: usize, is_hardware: bool) -> HvResult {
+    if !gic_capabilities().nmi {
+        return hv_result_err!(ENOSYS, "the gic doesn't support nmis");
+    }
+    inject_irq_as(irq_id, is_hardware, IrqGroup::Group1, true);
+    Ok(())
+}
+
+fn inject_irq_as(irq_id: usize, is_hardware: bool, group: IrqGroup, nmi: bool) {
+    let cpu = this_cpu_id();
+    // a remapped irq keeps the group of the original one
+    let Some((irq_id, is_hardware)) = filter::run(irq_id, is_hardware, cpu) else {
+        return;
+    };
+    let irq = PendingIrq {
+        irq_id,
+        is_hardware,
+        group,
+        nmi,
+    };
+    // group 0 irqs are the hypervisor's own, like the watchdog irq, the guest doesn't enable
+    // them. A disabled irq stays pending until the guest enables it, see replay_enabled_irqs.
+    if !is_hardware && group == IrqGroup::Group1 && !irq_enabled(irq_id) {
+        trace!("hold virtual irq {}, the guest has it disabled", irq_id);
+        pending::hold_disabled(cpu, irq);
+        return;
+    }
+    if handle_offline_irq(irq) {
+        return;
+    }
+    stats::count(|s| &s.injected);
+    summary::stamp_injection(irq_id);
+    audit::record(irq_id, is_hardware);
+    pv::notify(irq_id);
+    load::count(cpu);
+    if free_lr_count() == 0 {
+        let full = summary::count_lrs_full(cpu);
+        if full == 1 || full % 1000 == 0 {
+            warn!("cpu {}: all list registers taken at injection, {} times", cpu, full);
         }
-        write_lr(free_ir as usize, val);
+    }
+    // with all list registers taken an irq of higher priority may still get one of them
+    let evicted = || {
+        zone_dist_enabled()
+            && injection_blocked()
+            && lr_holding(irq_id).is_none()
+            && evict_lr_for(irq_id)
+    };
+    if !must_defer(cpu) || evicted() {
+        write_irq_to_lr(irq_id, is_hardware, group, nmi);
+    } else {
+        trace!("defer virtual irq {}", irq_id);
+        stats::count(|s| &s.deferred);
+        pending::defer(cpu, irq);
+        flush_pending_irqs();
     }
 }
 
-pub static GIC: Once<Gic> = Once::new();
-pub const PER_GICR_SIZE: usize = 0x20000;
+// Whether an injection on this cpu goes to the pending queue instead of a list register. The
+// order of deferred irqs is kept, new ones go behind them.
+fn must_defer(cpu: usize) -> bool {
+    !pending::is_empty(cpu) || injection_blocked()
+}
 
-#[derive(Debug)]
-pub struct Gic {
-    pub gicd_base: usize,
-    pub gicr_base: usize,
-    pub gicd_size: usize,
-    pub gicr_size: usize,
+/// What `inject_irq` would do with an irq, see `inject_irq_dryrun`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectDecision {
+    /// Written to the free list register `lr` of `cpu`.
+    Lr { cpu: usize, lr: usize },
+    /// Merged into list register `lr` of `cpu`, which already holds the irq.
+    Coalesced { cpu: usize, lr: usize },
+    /// Deferred to the pending queue of `cpu`.
+    Queued { cpu: usize },
+    /// Injected on `cpu`, another one whose list registers can't be looked at from here.
+    Remote { cpu: usize },
+    Dropped(DropReason),
 }
 
-pub fn host_gicd_base() -> usize {
-    GIC.get().unwrap().gicd_base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The SPI belongs to no zone.
+    NotOwned,
+    /// The SPI isn't routed to a single cpu of the zone owning it.
+    NotRouted,
 }
 
-pub fn host_gicr_base(id: usize) -> usize {
-    assert!(id < MAX_CPU_NUM);
-    GIC.get().unwrap().gicr_base + id * PER_GICR_SIZE
+/// Work out where `irq_id` would end up if it was injected now, without touching any list
+/// register: physical SPIs go to the cpu GICD_IROUTER sends them to, which has to run the
+/// zone owning them, everything else is injected on this cpu.
+pub fn inject_irq_dryrun(irq_id: usize, is_hardware: bool) -> InjectDecision {
+    let this_cpu = this_cpu_id();
+    let cpu = if is_hardware && is_spi(irq_id as _) {
+        let Some(owner) = find_irq_owner(irq_id as _) else {
+            return InjectDecision::Dropped(DropReason::NotOwned);
+        };
+        let irouter_addr = match irq_kind(irq_id as _) {
+            IrqKind::ExtSpi => gicd::GICD_IROUTERNE + (irq_id - GICV3_ESPI_BASE as usize) * 8,
+            _ => gicd::GICD_IROUTER + irq_id * 8,
+        };
+        let irouter = unsafe { ((host_gicd_base() + irouter_addr) as *const u64).read_volatile() };
+        let cpu = mpidr_to_cpuid(irouter) as usize;
+        let in_zone = find_zone(owner).map_or(false, |zone| zone.read().cpu_set.contains_cpu(cpu));
+        if irouter & GICD_IROUTER_IRM != 0 || !in_zone {
+            return InjectDecision::Dropped(DropReason::NotRouted);
+        }
+        cpu
+    } else {
+        this_cpu
+    };
+    if cpu != this_cpu {
+        return InjectDecision::Remote { cpu };
+    }
+    if must_defer(cpu) {
+        return InjectDecision::Queued { cpu };
+    }
+    match (lr_holding(irq_id), lr_for_irq(irq_id)) {
+        (Some((lr, _)), _) => InjectDecision::Coalesced { cpu, lr },
+        (None, Some(lr)) => InjectDecision::Lr { cpu, lr },
+        (None, None) => InjectDecision::Queued { cpu },
+    }
 }
 
-pub fn host_gicd_size() -> usize {
-    GIC.get().unwrap().gicd_size
+// Inject `irq` on `cpu`, which may be another cpu: it goes through the pending queue of
+// `cpu` and is moved into a list register by that cpu itself.
+fn inject_irq_on(cpu: usize, irq: PendingIrq) {
+    load::count(cpu);
+    pending::defer(cpu, irq);
+    if cpu == this_cpu_id() {
+        flush_pending_irqs();
+    } else {
+        kick_flush_pending_irqs(cpu);
+    }
 }
 
-pub fn host_gicr_size() -> usize {
-    GIC.get().unwrap().gicr_size
+/// Inject the group 0 SGI `sgi_id` on `cpu`.
+pub fn inject_sgi0(cpu: usize, sgi_id: usize) {
+    let irq = PendingIrq {
+        irq_id: sgi_id,
+        is_hardware: false,
+        group: IrqGroup::Group0,
+        nmi: false,
+    };
+    inject_irq_on(cpu, irq);
 }
 
-pub fn is_spi(irqn: u32) -> bool {
-    irqn > 31 && irqn < 1020
+/// SGI injected by `inject_wakeup`, a reschedule IPI for Linux guests which is harmless when
+/// there is nothing to reschedule.
+pub const WAKEUP_SGI_ID: usize = 0;
+
+/// Bring the vcpu on `cpu` out of WFI with a benign virtual SGI, for testing guest idle and
+/// wakeup paths. The redistributor of `cpu` is woken first if it was put to sleep.
+pub fn inject_wakeup(cpu: usize) {
+    if !redist_awake(cpu) {
+        warn!("redistributor of cpu {} asleep, waking it up", cpu);
+        wake_redist(cpu);
+    }
+    let irq = PendingIrq {
+        irq_id: WAKEUP_SGI_ID,
+        is_hardware: false,
+        group: IrqGroup::Group1,
+        nmi: false,
+    };
+    inject_irq_on(cpu, irq);
 }
 
-pub fn is_sgi(irqn: u32) -> bool {
-    irqn < 16
+/// Drop `irq_id` from the pending queues of all cpus, so an irq deferred before it was taken
+/// from its zone is never injected afterwards.
+pub fn pending_queue_remove(irq_id: usize) {
+    for cpu in 0..MAX_CPU_NUM {
+        pending::remove(cpu, irq_id);
+    }
 }
 
-pub fn enable_irqs() {
-    unsafe { asm!("msr daifclr, #0xf") };
+/// Move this cpu's deferred irqs into list registers, as far as the zone's distributor enable
+/// and injection depth limit allow.
+pub fn flush_pending_irqs() {
+    let cpu = this_cpu_id();
+    if !zone_dist_enabled() {
+        park_pending_lrs(cpu, lr_count());
+    }
+    // an irq which finds no list register goes back to the queue, only try each one once
+    for _ in 0..pending::len(cpu) {
+        if injection_blocked() {
+            break;
+        }
+        match pending::pop(cpu) {
+            Some(irq) => write_irq_to_lr(irq.irq_id, irq.is_hardware, irq.group, irq.nmi),
+            None => break,
+        }
+    }
+    // Refill the list registers once the guest has handled all but one of them. With fewer in
+    // use the underflow irq would fire right away again.
+    let in_use = lr_count() - free_lr_count();
+    set_local_hcr(HcrConfig::UIE, !pending::is_empty(cpu) && in_use >= 2);
 }
 
-pub fn disable_irqs() {
-    unsafe { asm!("msr daifset, #0xf") };
+fn zone_dist_enabled() -> bool {
+    match &this_cpu_data().zone {
+        Some(zone) => zone.read().vgicv3.dist_enabled,
+        None => true,
+    }
 }
 
-pub fn primary_init_early() {
-    let root_config = root_zone_config();
-    
-    GIC.call_once(|| Gic {
-        gicd_base: root_config.arch.gicd_base,
-        gicr_base: root_config.arch.gicr_base,
-        gicd_size: root_config.arch.gicd_size,
-        gicr_size: root_config.arch.gicr_size,
-    });
-    debug!("gic = {:#x?}", GIC.get().unwrap());
+// The guest disabled group 1 at its distributor: group 1 irqs not yet acknowledged go back
+// from the list registers to the front of the pending queue, in their order, until it enables
+// group 1 again. Active ones stay for the guest to finish them.
+fn park_pending_lrs(cpu: usize, lrs: usize) {
+    for i in (0..lrs).rev() {
+        let lr_val = read_lr(i);
+        if lr_val & LR_STATE_MASK != LR_STATE_PENDING || lr_val & LR_GROUP1 == 0 {
+            continue;
+        }
+        write_lr(i, 0);
+        pending::requeue(cpu, lr_pending_irq(lr_val));
+    }
 }
 
-pub fn primary_init_late() {
-    enable_gic_are_ns();
-    enable_irqs();
+// The irq of a list register that only is pending, to queue it instead.
+fn lr_pending_irq(lr_val: u64) -> PendingIrq {
+    let irq_id = (lr_val & LR_VIRTIRQ_MASK) as usize;
+    // physical irqs stay active at the distributor until they are injected again
+    let is_hardware = lr_val & LR_HW != 0
+        || (lr_val & LR_EOI != 0 && !is_sgi(irq_id as _) && !irq_hw_mapped(irq_id));
+    let group = if lr_val & LR_GROUP1 != 0 {
+        IrqGroup::Group1
+    } else {
+        IrqGroup::Group0
+    };
+    PendingIrq {
+        irq_id,
+        is_hardware,
+        group,
+        nmi: lr_val & LR_NMI != 0,
+    }
 }
 
-pub fn percpu_init() {
-    gicc_init();
-    enable_ipi();
+// Have `cpu` flush its pending queue, through its work ring or, if that is full, an event.
+fn kick_flush_pending_irqs(cpu: usize) {
+    if queue_work(cpu, Work::FlushPendingIrqs).is_err() {
+        send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_FLUSH_PENDING_IRQS);
+    }
 }
 
-impl Zone {
-    pub fn arch_irqchip_reset(&self) {
-        let gicd_base = host_gicd_base();
-        for (idx, &mask) in self.irq_bitmap.iter().enumerate() {
-            if idx == 0 {
-                continue;
-            }
-            unsafe {
-                write_volatile((gicd_base + GICD_ICENABLER + idx * 4) as *mut u32, mask);
-                write_volatile((gicd_base + GICD_ICACTIVER + idx * 4) as *mut u32, mask);
-            }
+/// Have all cpus in `cpu_set` move their pending irqs into list registers, or back out of them
+/// if their zone's distributor is disabled.
+pub fn flush_pending_irqs_on(cpu_set: &CpuSet) {
+    let this_cpu = this_cpu_id();
+    for cpu in cpu_set.iter() {
+        if cpu == this_cpu {
+            flush_pending_irqs();
+        } else {
+            kick_flush_pending_irqs(cpu);
+        }
+    }
+}
+
+// Whether new virtual irqs must wait in the pending queue: the zone hasn't enabled its
+// distributor yet, or this cpu already holds as many irqs in its list registers as it allows,
+// at most one per list register.
+fn injection_blocked() -> bool {
+    let (dist_enabled, limit) = match &this_cpu_data().zone {
+        Some(zone) => {
+            let zone_r = zone.read();
+            (zone_r.vgicv3.dist_enabled, zone_r.vgicv3.irq_depth_limit)
         }
+        None => (true, 0),
+    };
+    let lrs = lr_count();
+    blocks_injection(dist_enabled, limit, lrs - free_lr_count(), lrs)
+}
+
+// `injection_blocked` for `in_use` of `lrs` list registers holding irqs, a depth `limit` of 0
+// allows one irq per list register.
+fn blocks_injection(dist_enabled: bool, limit: usize, in_use: usize, lrs: usize) -> bool {
+    if !dist_enabled {
+        return true;
+    }
+    let limit = if limit == 0 { lrs } else { limit };
+    in_use >= limit
+}
+
+const LR_VIRTIRQ_MASK: u64 = (1 << 32) - 1;
+const LR_STATE_PENDING: u64 = 1 << 62;
+const LR_STATE_ACTIVE: u64 = 1 << 63;
+const LR_HW: u64 = 1 << 61;
+// signal a maintenance irq once the guest deactivated the irq, only without HW
+const LR_EOI: u64 = 1 << 41;
+const LR_STATE_MASK: u64 = 0b11 << 62;
+const LR_GROUP1: u64 = 1 << 60;
+// GICv3.3, RES0 without NMI support
+const LR_NMI: u64 = 1 << 59;
+const LR_PRIORITY_SHIFT: u64 = 48;
+const LR_PINTID_SHIFT: u64 = 32;
+const LR_PINTID_MASK: u64 = 0x1fff;
+
+// how often ELRSR reported a list register as free while the LR itself still held an irq
+static LR_STATE_MISMATCHES: AtomicU64 = AtomicU64::new(0);
+
+pub fn lr_state_mismatches() -> u64 {
+    LR_STATE_MISMATCHES.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// Number of list registers implemented, from ICH_VTR_EL2.ListRegs.
+pub fn lr_count() -> usize {
+    this_gic().vtr.list_regs
+}
+
+/// Number of list registers ICH_ELRSR_EL2 reports as free.
+pub fn free_lr_count() -> usize {
+    let elsr = read_sysreg!(ich_elrsr_el2);
+    (elsr & ((1 << lr_count()) - 1)).count_ones() as usize
+}
+
+/// First list register that is free, ELRSR alone may be stale so the LR state must agree.
+/// LRs pinned to an irq are never free for others.
+pub fn free_lr_index() -> Option<usize> {
+    let elsr = read_sysreg!(ich_elrsr_el2) & !pin::pinned_lrs();
+    (0..lr_count()).find(|&i| {
+        if (1 << i) & elsr == 0 {
+            return false;
+        }
+        if read_lr(i) & LR_STATE_MASK == 0 {
+            return true;
+        }
+        let n = LR_STATE_MISMATCHES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        debug!("lr {} in use but marked free by elrsr, {} mismatches", i, n + 1);
+        false
+    })
+}
+
+// The list register a new injection of `irq_id` goes to. A pinned irq takes its own LR, or a
+// free one if its LR still holds an irq injected before the pin.
+fn lr_for_irq(irq_id: usize) -> Option<usize> {
+    match pin::pinned_lr(irq_id) {
+        Some(lr) if read_lr(lr) & LR_STATE_MASK == 0 => Some(lr),
+        Some(lr) => {
+            debug!("lr {} pinned to irq {} still in use", lr, irq_id);
+            free_lr_index()
+        }
+        None => free_lr_index(),
+    }
+}
+
+// The list register holding `irq_id` pending or active, and its value. LRs only waiting for
+// their EOI maintenance don't count.
+fn lr_holding(irq_id: usize) -> Option<(usize, u64)> {
+    (0..lr_count()).map(|i| (i, read_lr(i))).find(|&(_, lr_val)| {
+        lr_val & LR_STATE_MASK != 0 && (lr_val & LR_VIRTIRQ_MASK) as usize == irq_id
+    })
+}
+
+fn write_irq_to_lr(irq_id: usize, is_hardware: bool, group: IrqGroup, nmi: bool) {
+    if irq_id >> this_gic().vtr.id_bits != 0 {
+        warn!("virtual irq {} beyond the vINTIDs of the virtual interface", irq_id);
+        return;
+    }
+    let is_physical = !is_sgi(irq_id as _) && is_hardware;
+    let hw_mapped = is_physical && irq_hw_mapped(irq_id);
+    let needs_eoi = !hw_mapped && (is_physical || eoi_callback(irq_id).is_some());
+    let priority = lr_priority(irq_id);
+    // looked up before locking, a zone lock is never taken while holding an SPI lock
+    let edge = irq_is_edge_triggered(irq_id);
+    let _guard = if is_physical { irq_lock::lock(irq_id) } else { None };
+    // The guest can take the irq as soon as the LR write below lands and then reads the state
+    // of the device that raised it, so the stores of that state (by an emulated device, maybe
+    // on another cpu through the pending queue or a work ring) have to be complete first. A
+    // DSB does that, a DMB would only order them against other memory accesses and not
+    // against the system register write.
+    unsafe { asm!("dsb ish") };
+    if let Some((i, lr_val)) = lr_holding(irq_id) {
+        trace!("virtual irq {} enables again", irq_id);
+        // a new edge while the guest handles the last one must not get lost, a level irq is
+        // still asserted once the guest deactivates it. Hardware irqs can't fire again
+        // before the guest deactivates them.
+        if lr_val & LR_STATE_MASK == LR_STATE_ACTIVE && lr_val & LR_HW == 0 && edge {
+            write_lr(i, lr_val | LR_STATE_PENDING);
+            lr_sync();
+        }
+        return;
+    }
+
+    let lr = lr_for_irq(irq_id).or_else(|| {
+        let lr = eviction_candidate(priority)?;
+        evict_lr(lr);
+        Some(lr)
+    });
+    if let Some(free_lr) = lr {
+        let mut val = irq_id as u64; //v intid
+        if group == IrqGroup::Group1 {
+            val |= 1 << 60; //group 1
+        }
+        val |= 1 << 62; //state pending
+        val |= (priority as u64) << LR_PRIORITY_SHIFT;
+        if nmi {
+            val |= LR_NMI;
+        }
+
+        if hw_mapped {
+            val |= 1 << 61; //map hardware
+            val |= (irq_id as u64) << LR_PINTID_SHIFT; //pINTID
+        } else if needs_eoi {
+            // the physical irq is deactivated and the callback run by complete_lr_eois
+            val |= LR_EOI;
+        }
+        write_lr(free_lr, val);
+        lr_sync();
+    } else {
+        // all LRs are active or hold irqs of the same or a higher priority
+        trace!("no list register for virtual irq {}, deferring it", irq_id);
+        pending::defer(
+            this_cpu_id(),
+            PendingIrq {
+                irq_id,
+                is_hardware,
+                group,
+                nmi,
+            },
+        );
+    }
+}
+
+// Priority of `irq_id` in a list register of this cpu, limited to the zone's priority bits and
+// raised to its boost, if it has one.
+fn lr_priority(irq_id: usize) -> u8 {
+    let Some(zone) = &this_cpu_data().zone else {
+        return 0;
+    };
+    let zone = zone.read();
+    let mask = zone.vgicv3.priority_mask(this_gic().vtr.priority_bits);
+    let priority = vgic::irq_priority(&zone, this_cpu_id(), irq_id);
+    let boost = this_gic().boosts.lock().iter().find(|&&(irq, _)| irq == irq_id).map(|b| b.1);
+    boost.map_or(priority, |boost| boost.min(priority)) & mask
+}
+
+// irqs a cpu may have boosted at the same time, `lr_priority` searches them on every injection
+const MAX_BOOSTS: usize = 16;
+
+/// Raise the priority of `irq_id` on the vcpu on physical cpu `cpu` to `priority`, e.g. for
+/// priority inheritance, until `restore_irq_priority`. An irq pending in a list register gets
+/// the new priority right away, one which isn't in a list register gets it once it is
+/// injected. A lower priority than its own leaves the irq at its own. The work is done by the
+/// cpu itself, through its work ring if that isn't this one. At most `MAX_BOOSTS` irqs of a
+/// cpu are boosted at a time, another one is refused with EBUSY.
+pub fn boost_irq_priority(cpu: usize, irq_id: usize, priority: u8) -> HvResult {
+    {
+        let mut boosts = cpu_gic(cpu).boosts.lock();
+        boosts.retain(|&(irq, _)| irq != irq_id);
+        if boosts.len() >= MAX_BOOSTS {
+            return hv_result_err!(EBUSY, format!("cpu {}: {} irqs boosted", cpu, MAX_BOOSTS));
+        }
+        boosts.push((irq_id, priority));
+    }
+    update_lr_priority_of(cpu, irq_id);
+    Ok(())
+}
+
+/// Give `irq_id` its own priority back on the vcpu on physical cpu `cpu`.
+pub fn restore_irq_priority(cpu: usize, irq_id: usize) {
+    cpu_gic(cpu).boosts.lock().retain(|&(irq, _)| irq != irq_id);
+    update_lr_priority_of(cpu, irq_id);
+}
+
+fn update_lr_priority_of(cpu: usize, irq_id: usize) {
+    if cpu == this_cpu_id() {
+        lr_update_priority(irq_id);
+    } else if queue_work(cpu, Work::Call(lr_update_priority, irq_id)).is_err() {
+        debug!("cpu {}: work ring full, lr priority of irq {} not updated", cpu, irq_id);
+    }
+}
+
+// The list register to evict for an irq of `priority` when none is free: the one with the
+// lowest priority (highest value) below `priority` of those only pending. Active irqs and
+// pinned LRs are never evicted, an irq of the same priority doesn't preempt the one in place.
+fn eviction_candidate(priority: u8) -> Option<usize> {
+    eviction_candidate_of(priority, lr_count(), pin::pinned_lrs())
+}
+
+// `eviction_candidate` among the first `lrs` list registers, those in `pinned` are skipped.
+fn eviction_candidate_of(priority: u8, lrs: usize, pinned: u64) -> Option<usize> {
+    (0..lrs)
+        .filter(|&i| pinned & (1 << i) == 0)
+        .map(|i| (i, read_lr(i)))
+        .filter(|&(_, lr_val)| lr_val & LR_STATE_MASK == LR_STATE_PENDING)
+        .map(|(i, lr_val)| (i, ((lr_val >> LR_PRIORITY_SHIFT) & 0xff) as u8))
+        .filter(|&(_, lr_prio)| lr_prio > priority)
+        .max_by_key(|&(_, lr_prio)| lr_prio)
+        .map(|(i, _)| i)
+}
+
+// Move the irq of list register `i` back to the front of the pending queue, it is injected
+// again once a list register frees up.
+fn evict_lr(i: usize) {
+    let lr_val = read_lr(i);
+    write_lr(i, 0);
+    lr_sync();
+    trace!("evict virtual irq {} from lr {}", lr_val & LR_VIRTIRQ_MASK, i);
+    pending::requeue(this_cpu_id(), lr_pending_irq(lr_val));
+}
+
+// Make room for an injection of `irq_id` while all list registers this cpu may use are taken,
+// by evicting one holding an irq of lower priority. Returns whether it did.
+fn evict_lr_for(irq_id: usize) -> bool {
+    match eviction_candidate(lr_priority(irq_id)) {
+        Some(lr) => {
+            evict_lr(lr);
+            true
+        }
+        None => false,
+    }
+}
+
+// Writes of list registers are only guaranteed to be seen by later reads of ICH_ELRSR_EL2 and
+// the LRs after a context synchronization. Without it the next injection of this exit (e.g.
+// in flush_pending_irqs) could find the LR just written still free and overwrite it, or miss
+// the irq in it and write it a second time. The ERET to the guest synchronizes as well, the
+// injection paths don't rely on that, so don't drop this when moving LR writes around.
+fn lr_sync() {
+    unsafe { asm!("isb") };
+}
+
+fn irq_hw_mapped(irq_id: usize) -> bool {
+    match &this_cpu_data().zone {
+        Some(zone) => zone.read().vgicv3.is_hw_mapped(irq_id),
+        None => true,
+    }
+}
+
+/// ICH_EISR_EL2, one bit for each list register whose irq the guest deactivated while it
+/// asked for an EOI maintenance irq.
+pub fn read_eisr() -> u64 {
+    read_sysreg!(ich_eisr_el2)
+}
+
+// callback run with the irq id each time the guest deactivates the irq
+type EoiCallback = (usize, fn(usize));
+
+static EOI_CALLBACKS: Mutex<Vec<EoiCallback>> = Mutex::new(Vec::new());
+
+/// Register `callback` to run when the guest deactivates virtual irq `irq_id`, e.g. for an
+/// emulated device to deassert or re-evaluate its level line. Hardware mapped irqs are
+/// deactivated without the hypervisor noticing, their callback never runs. `None` removes it.
+pub fn set_eoi_callback(irq_id: usize, callback: Option<fn(usize)>) {
+    let mut callbacks = EOI_CALLBACKS.lock();
+    callbacks.retain(|&(irq, _)| irq != irq_id);
+    if let Some(callback) = callback {
+        callbacks.push((irq_id, callback));
+    }
+}
+
+fn eoi_callback(irq_id: usize) -> Option<fn(usize)> {
+    EOI_CALLBACKS
+        .lock()
+        .iter()
+        .find(|&&(irq, _)| irq == irq_id)
+        .map(|&(_, callback)| callback)
+}
+
+// Handle the list registers ICH_EISR_EL2 reports: deactivate the physical irqs of virtual-only
+// irqs, run the EOI callbacks, and free the list registers.
+fn complete_lr_eois() {
+    let mut eisr = read_eisr();
+    while eisr != 0 {
+        let i = eisr.trailing_zeros() as usize;
+        eisr &= eisr - 1;
+        let lr_val = read_lr(i);
+        let irq_id = (lr_val & LR_VIRTIRQ_MASK) as usize;
+        if !is_sgi(irq_id as _) && !irq_hw_mapped(irq_id) {
+            trace!("virtual irq {} deactivated, deactivating the physical one", irq_id);
+            deactivate_lr_irq(lr_val);
+        }
+        write_lr(i, 0);
+        // called without the lock held so it may change the registration
+        if let Some(callback) = eoi_callback(irq_id) {
+            callback(irq_id);
+        }
+    }
+}
+
+fn irq_is_edge_triggered(irq_id: usize) -> bool {
+    match &this_cpu_data().zone {
+        Some(zone) => zone.read().vgicv3.is_edge_triggered(this_cpu_id(), irq_id),
+        None => is_sgi(irq_id as _),
+    }
+}
+
+/// Bitmap of the 32 irqs starting at `first_irq` which are active in this cpu's list registers.
+pub fn lr_active_irqs(first_irq: usize) -> u32 {
+    let elsr: u64 = read_sysreg!(ich_elrsr_el2);
+    let mut active = 0;
+    for i in 0..lr_count() {
+        if (1 << i) & elsr > 0 {
+            continue;
+        }
+        let lr_val = read_lr(i);
+        let irq_id = (lr_val & LR_VIRTIRQ_MASK) as usize;
+        if lr_val & LR_STATE_ACTIVE != 0 && (first_irq..first_irq + 32).contains(&irq_id) {
+            active |= 1 << (irq_id - first_irq);
+        }
+    }
+    active
+}
+
+/// Give `irq_id` its current priority in this cpu's list registers, if it only is pending
+/// there. An active irq keeps the priority the guest took it at, it is what its running
+/// priority is based on.
+pub fn lr_update_priority(irq_id: usize) {
+    let Some((i, lr_val)) = lr_holding(irq_id) else {
+        return;
+    };
+    if lr_val & LR_STATE_MASK != LR_STATE_PENDING {
+        return;
+    }
+    let priority = lr_priority(irq_id) as u64;
+    write_lr(i, lr_val & !(0xff << LR_PRIORITY_SHIFT) | priority << LR_PRIORITY_SHIFT);
+    lr_sync();
+}
+
+/// `lr_update_priority` on all cpus in `cpu_set`, the other cpus do it from their work ring.
+pub fn lr_update_priority_on(cpu_set: &CpuSet, irq_id: usize) {
+    for cpu in cpu_set.iter() {
+        update_lr_priority_of(cpu, irq_id);
+    }
+}
+
+/// Clear the active state of the irqs in `mask`, relative to `first_irq`, in this cpu's list
+/// registers. An irq which is also pending stays pending.
+pub fn lr_deactivate_irqs(first_irq: usize, mask: u32) {
+    let elsr: u64 = read_sysreg!(ich_elrsr_el2);
+    for i in 0..lr_count() {
+        if (1 << i) & elsr > 0 {
+            continue;
+        }
+        let lr_val = read_lr(i);
+        let irq_id = (lr_val & LR_VIRTIRQ_MASK) as usize;
+        if lr_val & LR_STATE_ACTIVE != 0
+            && (first_irq..first_irq + 32).contains(&irq_id)
+            && mask & (1 << (irq_id - first_irq)) != 0
+        {
+            lr_deactivate(i, lr_val);
+        }
+    }
+}
+
+// Clear the active state of list register `i`. A hardware mapped irq is deactivated at the
+// distributor as well, as if the guest had done it through the virtual interface.
+fn lr_deactivate(i: usize, lr_val: u64) {
+    write_lr(i, lr_val & !LR_STATE_ACTIVE);
+    if lr_val & LR_HW != 0 {
+        deactivate_lr_irq(lr_val);
+    }
+}
+
+/// Emulate a guest write of `irq_id` to ICC_EOIR<n>_EL1, only trapped with ICH_HCR_EL2.TALL<n>.
+/// Drops the highest active priority of `group`, and deactivates the irq as well unless the
+/// guest runs with EOImode 1.
+pub fn virtual_eoi(irq_id: usize, group: IrqGroup) {
+    // an EOI of an irq which isn't active would drop the priority of the one which is
+    match eoi_lr(irq_id, group, lr_count()) {
+        Some((i, lr_val)) => complete_eoi(i, lr_val, group, apr_count()),
+        None => {
+            if !clear_active_shadow(irq_id) {
+                report_bogus_completion("eoi", irq_id);
+            }
+        }
+    }
+}
+
+// The EOI of the irq list register `i` holds active, with `apr_num` active priority registers.
+fn complete_eoi(i: usize, lr_val: u64, group: IrqGroup, apr_num: usize) {
+    for n in 0..apr_num {
+        let apr = read_apr(group, n);
+        if apr != 0 {
+            write_apr(group, n, apr & (apr - 1));
+            break;
+        }
+    }
+    if read_sysreg!(ich_vmcr_el2) & ICH_VMCR_VEOIM == 0 {
+        lr_deactivate(i, lr_val);
+    }
+}
+
+/// Emulate a guest write of `irq_id` to ICC_DIR_EL1, only trapped with ICH_HCR_EL2.TDIR or TC.
+pub fn virtual_deactivate(irq_id: usize) {
+    match active_lr(irq_id, lr_count()) {
+        // a virtual-only irq which wants its EOI maintenance gets it from the LR state
+        Some((i, lr_val)) => lr_deactivate(i, lr_val),
+        None => {
+            if !clear_active_shadow(irq_id) {
+                report_bogus_completion("deactivation", irq_id);
+            }
+        }
+    }
+}
+
+// The one of the first `lrs` list registers holding `irq_id` active.
+fn active_lr(irq_id: usize, lrs: usize) -> Option<(usize, u64)> {
+    (0..lrs).map(|i| (i, read_lr(i))).find(|&(_, lr_val)| {
+        (lr_val & LR_VIRTIRQ_MASK) as usize == irq_id && lr_val & LR_STATE_ACTIVE != 0
+    })
+}
+
+// The list register an EOI of `irq_id` in `group` completes, the one holding it active as an
+// irq of that group.
+fn eoi_lr(irq_id: usize, group: IrqGroup, lrs: usize) -> Option<(usize, u64)> {
+    let group1 = group == IrqGroup::Group1;
+    active_lr(irq_id, lrs).filter(|&(_, lr_val)| (lr_val & LR_GROUP1 != 0) == group1)
+}
+
+// An SPI the guest made active through GICD_ISACTIVER is in no LR, completing it only clears
+// the shadow. Returns whether it was set.
+fn clear_active_shadow(irq_id: usize) -> bool {
+    let Some(zone) = &this_cpu_data().zone else {
+        return false;
+    };
+    if !is_spi(irq_id as _) || irq_id >= 1024 {
+        return false;
+    }
+    let shadow = &mut zone.write().vgicv3.active_shadow[irq_id / 32];
+    let bit = 1 << (irq_id % 32);
+    let was_active = *shadow & bit != 0;
+    *shadow &= !bit;
+    was_active
+}
+
+static BOGUS_COMPLETIONS: AtomicU64 = AtomicU64::new(0);
+
+// The guest completed an irq which isn't active. Nothing is dropped or deactivated, least of
+// all a physical irq, the guest only gets logged.
+fn report_bogus_completion(what: &str, irq_id: usize) {
+    let n = BOGUS_COMPLETIONS.fetch_add(1, core::sync::atomic::Ordering::Relaxed) + 1;
+    if n == 1 || n % 1000 == 0 {
+        warn!(
+            "cpu {}: guest {} of virtual irq {} which isn't active, ignored, {} times",
+            this_cpu_id(),
+            what,
+            irq_id,
+            n
+        );
+    }
+}
+
+// Lowest active priority of both groups of the vcpu on this cpu, 0x100 if no irq is active.
+fn running_priority() -> u64 {
+    let group_shift = 8 - this_gic().vtr.preemption_bits;
+    [IrqGroup::Group0, IrqGroup::Group1]
+        .iter()
+        .flat_map(|&group| (0..apr_count()).map(move |n| (n, read_apr(group, n))))
+        .filter(|&(_, apr)| apr != 0)
+        .map(|(n, apr)| (n as u64 * 32 + apr.trailing_zeros() as u64) << group_shift)
+        .min()
+        .unwrap_or(0x100)
+}
+
+/// The running priority of the guest on this cpu, what it reads from ICC_RPR_EL1, only
+/// trapped with ICH_HCR_EL2.TC. It is 0xff while no irq is active.
+pub fn read_vrpr() -> u64 {
+    running_priority().min(0xff)
+}
+
+// The highest priority pending irq of `group` in the list registers of this cpu, the first
+// of them if several have that priority: (list register, its value, priority).
+fn highest_pending_lr(group: IrqGroup) -> Option<(usize, u64, u64)> {
+    let group1 = group == IrqGroup::Group1;
+    (0..lr_count())
+        .map(|i| (i, read_lr(i)))
+        .filter(|&(_, lr_val)| lr_val & LR_STATE_MASK == LR_STATE_PENDING)
+        .filter(|&(_, lr_val)| (lr_val & LR_GROUP1 != 0) == group1)
+        .map(|(i, lr_val)| (i, lr_val, (lr_val >> LR_PRIORITY_SHIFT) & 0xff))
+        .min_by_key(|&(_, _, priority)| priority)
+}
+
+/// The irq the guest reads from ICC_HPPIR<n>_EL1, only trapped with ICH_HCR_EL2.TALL<n>: the
+/// vINTID of the highest priority pending irq of `group` in the list registers, whether the
+/// guest could take it or not, or 1023 if there is none.
+pub fn read_vhppir(group: IrqGroup) -> u64 {
+    let lr_val = highest_pending_lr(group).map(|(_, lr_val, _)| lr_val);
+    lr_val.map_or(INTID_SPURIOUS as u64, |lr_val| lr_val & LR_VIRTIRQ_MASK)
+}
+
+/// Emulate a guest read of ICC_IAR1_EL1 on `vcpu`, only trapped with ICH_HCR_EL2.TALL1, see
+/// `virtual_acknowledge_group`. List registers are private to a cpu, so `vcpu` has to be the
+/// one running here.
+pub fn virtual_acknowledge(vcpu: usize) -> u32 {
+    virtual_acknowledge_group(vcpu, IrqGroup::Group1)
+}
+
+/// Emulate a guest read of ICC_IAR<n>_EL1 of `group` on `vcpu`: the highest priority pending
+/// irq of `group` in the list registers turns active, if it may preempt the running priority
+/// and neither `group` is disabled nor the irq masked by the guest. Returns its vINTID, or
+/// 1023 if there is none. A group 1 NMI is only acknowledged through ICC_NMIAR1_EL1, this
+/// returns 1022 for it like the physical ICC_IAR1_EL1.
+pub fn virtual_acknowledge_group(vcpu: usize, group: IrqGroup) -> u32 {
+    acknowledge(vcpu, group, false)
+}
+
+/// Emulate a guest read of ICC_NMIAR1_EL1 on `vcpu`, trapped along with ICC_IAR1_EL1: like
+/// `virtual_acknowledge`, for an NMI, which the guest's priority mask doesn't mask.
+pub fn virtual_acknowledge_nmi(vcpu: usize) -> u32 {
+    acknowledge(vcpu, IrqGroup::Group1, true)
+}
+
+fn acknowledge(vcpu: usize, group: IrqGroup, nmi: bool) -> u32 {
+    assert_eq!(vcpu, this_cpu_id(), "virtual acknowledge of another cpu");
+    let vmcr = read_sysreg!(ich_vmcr_el2);
+    if vmcr & vmcr_group_enable(group) == 0 {
+        return INTID_SPURIOUS as _;
+    }
+    let Some((i, lr_val, priority)) = highest_pending_lr(group) else {
+        return INTID_SPURIOUS as _;
+    };
+    let group_shift = 8 - this_gic().vtr.preemption_bits;
+    let pmr = (vmcr & ICH_VMCR_VPMR_MASK) >> ICH_VMCR_VPMR_SHIFT;
+    let is_nmi = lr_val & LR_NMI != 0;
+    if priority >> group_shift << group_shift >= running_priority() {
+        return INTID_SPURIOUS as _;
+    }
+    match (is_nmi, nmi) {
+        (true, false) => return INTID_NMI_PENDING as _,
+        (false, true) => return INTID_SPURIOUS as _,
+        (false, false) if priority >= pmr => return INTID_SPURIOUS as _,
+        _ => {}
+    }
+    write_lr(i, lr_val & !LR_STATE_MASK | LR_STATE_ACTIVE);
+    // the priority drop of the guest's EOI clears this bit again
+    let level = priority >> group_shift;
+    let n = (level / 32) as usize;
+    write_apr(group, n, read_apr(group, n) | 1 << (level % 32));
+    (lr_val & LR_VIRTIRQ_MASK) as u32
+}
+
+/// The active priorities of `group` the guest reads from ICC_AP<n>R<m>_EL1, only trapped with
+/// ICH_HCR_EL2.TALL<n>. Registers the virtual interface doesn't implement read as zero.
+pub fn read_vapr(group: IrqGroup, m: usize) -> u64 {
+    if m < apr_count() {
+        read_apr(group, m)
+    } else {
+        0
+    }
+}
+
+/// Take the guest's write of ICC_AP<n>R<m>_EL1, as a guest restoring its own context does.
+/// Writes to registers the virtual interface doesn't implement are ignored.
+pub fn write_vapr(group: IrqGroup, m: usize, val: u64) {
+    if m < apr_count() {
+        write_apr(group, m, val);
+    }
+}
+
+fn read_apr(group: IrqGroup, n: usize) -> u64 {
+    match (group, n) {
+        (IrqGroup::Group0, 0) => read_sysreg!(ich_ap0r0_el2),
+        (IrqGroup::Group0, 1) => read_sysreg!(ich_ap0r1_el2),
+        (IrqGroup::Group0, 2) => read_sysreg!(ich_ap0r2_el2),
+        (IrqGroup::Group0, 3) => read_sysreg!(ich_ap0r3_el2),
+        (IrqGroup::Group1, 0) => read_sysreg!(ich_ap1r0_el2),
+        (IrqGroup::Group1, 1) => read_sysreg!(ich_ap1r1_el2),
+        (IrqGroup::Group1, 2) => read_sysreg!(ich_ap1r2_el2),
+        (IrqGroup::Group1, 3) => read_sysreg!(ich_ap1r3_el2),
+        _ => panic!("invalid active priority register {}", n),
+    }
+}
+
+fn write_apr(group: IrqGroup, n: usize, val: u64) {
+    match (group, n) {
+        (IrqGroup::Group0, 0) => write_sysreg!(ich_ap0r0_el2, val),
+        (IrqGroup::Group0, 1) => write_sysreg!(ich_ap0r1_el2, val),
+        (IrqGroup::Group0, 2) => write_sysreg!(ich_ap0r2_el2, val),
+        (IrqGroup::Group0, 3) => write_sysreg!(ich_ap0r3_el2, val),
+        (IrqGroup::Group1, 0) => write_sysreg!(ich_ap1r0_el2, val),
+        (IrqGroup::Group1, 1) => write_sysreg!(ich_ap1r1_el2, val),
+        (IrqGroup::Group1, 2) => write_sysreg!(ich_ap1r2_el2, val),
+        (IrqGroup::Group1, 3) => write_sysreg!(ich_ap1r3_el2, val),
+        _ => panic!("invalid active priority register {}", n),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectedState {
+    Pending,
+    Active,
+    PendingActive,
+    /// Still in the pending queue, not in a list register yet.
+    Queued,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InjectedIrq {
+    pub irq_id: usize,
+    pub state: InjectedState,
+    pub group: IrqGroup,
+    /// Priority in the list register, none while queued.
+    pub priority: Option<u8>,
+    /// Physical irq the virtual one is mapped to.
+    pub hw_irq: Option<usize>,
+}
+
+const MAX_INJECTED_IRQS: usize = 64;
+
+/// Fixed capacity list of `InjectedIrq`, doesn't need the heap.
+pub struct InjectedIrqs {
+    irqs: [Option<InjectedIrq>; MAX_INJECTED_IRQS],
+    len: usize,
+    /// Whether irqs were left out because the list was full.
+    pub truncated: bool,
+}
+
+impl InjectedIrqs {
+    fn push(&mut self, irq: InjectedIrq) {
+        if self.len == MAX_INJECTED_IRQS {
+            self.truncated = true;
+            return;
+        }
+        self.irqs[self.len] = Some(irq);
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InjectedIrq> {
+        self.irqs[..self.len].iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Every virtual irq of the vcpu on `cpu`: the ones in its list registers, then the queued
+/// ones. List registers are only readable locally, so `cpu` must be this cpu.
+pub fn injected_irqs(cpu: usize) -> InjectedIrqs {
+    assert_eq!(cpu, this_cpu_id());
+    let mut list = InjectedIrqs {
+        irqs: [None; MAX_INJECTED_IRQS],
+        len: 0,
+        truncated: false,
+    };
+    for i in 0..lr_count() {
+        let lr_val = read_lr(i);
+        let state = match lr_val >> 62 {
+            0b01 => InjectedState::Pending,
+            0b10 => InjectedState::Active,
+            0b11 => InjectedState::PendingActive,
+            _ => continue,
+        };
+        list.push(InjectedIrq {
+            irq_id: (lr_val & LR_VIRTIRQ_MASK) as usize,
+            state,
+            group: if lr_val & (1 << 60) != 0 {
+                IrqGroup::Group1
+            } else {
+                IrqGroup::Group0
+            },
+            priority: Some((lr_val >> 48) as u8),
+            hw_irq: (lr_val & (1 << 61) != 0).then_some(((lr_val >> 32) & 0x1fff) as usize),
+        });
+    }
+    pending::for_each(cpu, |irq| {
+        list.push(InjectedIrq {
+            irq_id: irq.irq_id,
+            state: InjectedState::Queued,
+            group: irq.group,
+            priority: None,
+            hw_irq: irq.is_hardware.then_some(irq.irq_id),
+        })
+    });
+    list
+}
+
+machine_static! {
+    /// The GIC found by `primary_init_early`.
+    pub fn gic() -> Once<Gic> = Once::new();
+}
+pub const PER_GICR_SIZE: usize = 0x20000;
+
+#[derive(Debug)]
+pub struct Gic {
+    pub gicd_base: usize,
+    pub gicr_base: usize,
+    pub gicd_size: usize,
+    pub gicr_size: usize,
+    /// Number of extended PPIs (0, 32 or 64), taken from GICR_TYPER.PPInum.
+    pub eppi_num: usize,
+    pub espi_num: usize,
+    /// GICv3.3 NMIs, supported by both the distributor and the cpus (FEAT_NMI).
+    pub nmi: bool,
+    /// Implementation detected from GICD_IIDR, selecting the quirks applied.
+    pub implementation: GicImpl,
+    /// GICD_CTLR.DS, the layout of GICD_CTLR depends on it.
+    pub single_security: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicImpl {
+    Gic500,
+    Gic600,
+    Unknown,
+}
+
+struct GicQuirk {
+    iidr: u32,
+    mask: u32,
+    implementation: GicImpl,
+    // run on every cpu for its own redistributor before it is used
+    redist_init: Option<fn(usize)>,
+}
+
+// matched against GICD_IIDR ProductID and Implementer (Arm)
+const GIC_QUIRKS: &[GicQuirk] = &[
+    GicQuirk {
+        iidr: 0x0000_043b,
+        mask: 0xff00_0fff,
+        implementation: GicImpl::Gic500,
+        redist_init: None,
+    },
+    GicQuirk {
+        iidr: 0x0200_043b,
+        mask: 0xff00_0fff,
+        implementation: GicImpl::Gic600,
+        redist_init: Some(gicr_power_on),
+    },
+];
+
+fn gic_quirk(implementation: GicImpl) -> Option<&'static GicQuirk> {
+    GIC_QUIRKS
+        .iter()
+        .find(|quirk| quirk.implementation == implementation)
+}
+
+fn detect_gic_impl(gicd_base: usize) -> GicImpl {
+    let iidr = unsafe { ((gicd_base + GICD_IIDR) as *const u32).read_volatile() };
+    let implementation = GIC_QUIRKS
+        .iter()
+        .find(|quirk| iidr & quirk.mask == quirk.iidr)
+        .map_or(GicImpl::Unknown, |quirk| quirk.implementation);
+    info!("gic implementation: {:?}, iidr = {:#x}", implementation, iidr);
+    implementation
+}
+
+/// The GIC features the hypervisor runs with, detected once by the primary cpu.
+#[derive(Debug, Clone, Copy)]
+pub struct GicCaps {
+    /// Architecture version from GICD_PIDR2.ArchRev, 3 for GICv3 and 4 for GICv4.
+    pub version: u8,
+    pub lr_num: usize,
+    /// Virtual priority bits, ICH_VTR_EL2.PRIbits + 1.
+    pub priority_bits: usize,
+    /// Highest SPI INTID the distributor implements.
+    pub max_spi: usize,
+    pub lpis: bool,
+    pub vlpis: bool,
+    pub espi_num: usize,
+    pub nmi: bool,
+    pub implementation: GicImpl,
+}
+
+static GIC_CAPS: Once<GicCaps> = Once::new();
+
+fn detect_gic_caps(gic: &Gic) -> GicCaps {
+    let gicd_read = |reg| unsafe { ((gic.gicd_base + reg) as *const u32).read_volatile() };
+    let typer = gicd_read(GICD_TYPER) as usize;
+    let gicr_typer = unsafe { ((gic.gicr_base + GICR_TYPER) as *const u64).read_volatile() };
+    GicCaps {
+        version: ((gicd_read(GICDV3_PIDR2) >> 4) & 0xf) as u8,
+        lr_num: lr_count(),
+        priority_bits: this_gic().vtr.priority_bits,
+        max_spi: (32 * ((typer & GICD_TYPER_ITLINES_MASK) + 1) - 1).min(1019),
+        lpis: typer & GICD_TYPER_LPIS != 0,
+        vlpis: gicr_typer as usize & GICR_TYPER_VLPIS != 0,
+        espi_num: gic.espi_num,
+        nmi: gic.nmi,
+        implementation: gic.implementation,
+    }
+}
+
+/// The GIC features detected in `gicv3_primary_init`.
+pub fn gic_capabilities() -> GicCaps {
+    *GIC_CAPS.get().expect("gic capabilities queried before gicv3_primary_init")
+}
+
+pub fn host_gicd_base() -> usize {
+    gic().get().unwrap().gicd_base
+}
+
+/// Base of the redistributor frame of cpu `id`. A frame that doesn't lie within the configured
+/// `gicr_size` bytes is a config error, it panics here instead of faulting at a wild address.
+pub fn host_gicr_base(id: usize) -> usize {
+    let gic = gic().get().unwrap();
+    match gicr_frame(gic.gicr_base, gic.gicr_size, id) {
+        Some(base) => base,
+        None => panic!(
+            "no redistributor frame for cpu {} within {:#x} bytes at {:#x}, check gicr_size",
+            id, gic.gicr_size, gic.gicr_base
+        ),
+    }
+}
+
+// The frame of cpu `id` in the `gicr_size` bytes of redistributors at `gicr_base`, if it is
+// all in there.
+fn gicr_frame(gicr_base: usize, gicr_size: usize, id: usize) -> Option<usize> {
+    let frame_in_region = |offset: usize| {
+        let end = offset.checked_add(PER_GICR_SIZE);
+        end.map_or(false, |end| end <= gicr_size)
+    };
+    id.checked_mul(PER_GICR_SIZE)
+        .filter(|&offset| id < MAX_CPU_NUM && frame_in_region(offset))
+        .and_then(|offset| gicr_base.checked_add(offset))
+}
+
+pub fn host_gicd_size() -> usize {
+    gic().get().unwrap().gicd_size
+}
+
+pub fn host_gicr_size() -> usize {
+    gic().get().unwrap().gicr_size
+}
+
+pub fn host_gic_impl() -> GicImpl {
+    gic().get().map_or(GicImpl::Unknown, |gic| gic.implementation)
+}
+
+pub fn host_eppi_num() -> usize {
+    gic().get().map_or(0, |gic| gic.eppi_num)
+}
+
+pub fn host_espi_num() -> usize {
+    gic().get().map_or(0, |gic| gic.espi_num)
+}
+
+pub fn host_single_security() -> bool {
+    gic().get().map_or(false, |gic| gic.single_security)
+}
+
+/// Whether the hypervisor IPI is a group 0 irq. It takes hv_ipi_fiq and a GIC with a single
+/// security state: with two of them firmware keeps group 0 for the secure world and sets
+/// SCR_EL3.FIQ, so the group 0 registers trap to EL3.
+pub fn hv_ipi_fiq() -> bool {
+    cfg!(feature = "hv_ipi_fiq") && host_single_security()
+}
+
+pub fn host_nmi_supported() -> bool {
+    gic().get().map_or(false, |gic| gic.nmi)
+}
+
+// FEAT_NMI, ID_AA64PFR1_EL1.NMI
+fn cpu_has_nmi() -> bool {
+    (read_sysreg!(id_aa64pfr1_el1) >> 36) & 0xf == 1
+}
+
+/// Interrupt ID classes, see `Architecture Specification - 2.2.1 Interrupt IDs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqKind {
+    Sgi,
+    Ppi,
+    Spi,
+    /// INTIDs 1020..1023, returned by the CPU interface instead of a real interrupt.
+    Special,
+    /// GICv3.1 extended PPIs, only valid if the redistributor implements them.
+    ExtPpi,
+    /// GICv3.1 extended SPIs, only valid if the distributor implements them.
+    ExtSpi,
+    Other,
+}
+
+/// Interrupt group, selecting the LR group bit and the ICC_IAR/EOIR registers used for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqGroup {
+    Group0,
+    Group1,
+}
+
+pub const GICV3_EPPI_BASE: u32 = 1056;
+pub const GICV3_ESPI_BASE: u32 = 4096;
+
+pub fn irq_kind(irqn: u32) -> IrqKind {
+    match irqn {
+        0..=15 => IrqKind::Sgi,
+        16..=31 => IrqKind::Ppi,
+        32..=1019 => IrqKind::Spi,
+        1020..=1023 => IrqKind::Special,
+        n if n >= GICV3_EPPI_BASE && n < GICV3_EPPI_BASE + host_eppi_num() as u32 => {
+            IrqKind::ExtPpi
+        }
+        n if n >= GICV3_ESPI_BASE && n < GICV3_ESPI_BASE + host_espi_num() as u32 => {
+            IrqKind::ExtSpi
+        }
+        _ => IrqKind::Other,
+    }
+}
+
+pub fn is_spi(irqn: u32) -> bool {
+    matches!(irq_kind(irqn), IrqKind::Spi | IrqKind::ExtSpi)
+}
+
+pub fn is_ppi(irqn: u32) -> bool {
+    matches!(irq_kind(irqn), IrqKind::Ppi | IrqKind::ExtPpi)
+}
+
+pub fn is_sgi(irqn: u32) -> bool {
+    irq_kind(irqn) == IrqKind::Sgi
+}
+
+/// Whether the guest enabled `irq_id` for this cpu. The guest's enable writes go through to the
+/// hardware, so this reads the physical enable bits: the banked ones in this cpu's
+/// redistributor for SGIs and PPIs, the distributor ones for SPIs. Hardware irqs only fire
+/// while enabled, for virtual ones this is the only check.
+pub fn irq_enabled(irq_id: usize) -> bool {
+    let Some((frame, reg, bit)) = enable_bit(irq_id) else {
+        return false;
+    };
+    let base = match frame {
+        EnableFrame::Redist => host_gicr_base(this_cpu_id()) + GICR_SGI_BASE,
+        EnableFrame::Dist => host_gicd_base(),
+    };
+    let val = unsafe { ((base + reg + bit / 32 * 4) as *const u32).read_volatile() };
+    val & (1 << (bit % 32)) != 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnableFrame {
+    /// The SGI frame of this cpu's redistributor.
+    Redist,
+    Dist,
+}
+
+// Where the set-enable bit of `irq_id` is: the frame, the register of the bit 0 irq and the
+// bit counted from there, `None` for INTIDs which have none.
+fn enable_bit(irq_id: usize) -> Option<(EnableFrame, usize, usize)> {
+    Some(match irq_kind(irq_id as _) {
+        IrqKind::Sgi | IrqKind::Ppi => (EnableFrame::Redist, GICR_ISENABLER, irq_id),
+        // GICR_ISENABLER<n>E follows ISENABLER0
+        IrqKind::ExtPpi => (
+            EnableFrame::Redist,
+            GICR_ISENABLER + 4,
+            irq_id - GICV3_EPPI_BASE as usize,
+        ),
+        IrqKind::Spi => (EnableFrame::Dist, GICD_ISENABLER, irq_id),
+        IrqKind::ExtSpi => (EnableFrame::Dist, GICD_ISENABLERNE, irq_id - GICV3_ESPI_BASE as usize),
+        IrqKind::Special | IrqKind::Other => return None,
+    })
+}
+
+/// Inject the virtual irqs held on `cpu` while the guest had them disabled which are among the
+/// 32 irqs starting at `first_irq` with their bit set in `mask`, after the guest enabled them.
+pub fn replay_enabled_irqs(cpu: usize, first_irq: usize, mask: u32) {
+    for irq in pending::take_enabled(cpu, first_irq, mask) {
+        trace!("virtual irq {} enabled, injecting it on cpu {}", irq.irq_id, cpu);
+        inject_irq_on(cpu, irq);
+    }
+}
+
+pub fn enable_irqs() {
+    unsafe { asm!("msr daifclr, #0xf") };
+}
+
+pub fn disable_irqs() {
+    unsafe { asm!("msr daifset, #0xf") };
+}
+
+pub fn primary_init_early() {
+    let root_config = root_zone_config();
+    pending::init(MAX_CPU_NUM);
+    irq_lock::init();
+    register_zone_hooks(ZoneHooks {
+        on_start: stats::zone_started,
+        on_stop: stats::zone_stopped,
+    });
+    register_zone_hooks(ZoneHooks {
+        on_start: |_| {},
+        on_stop: pv::zone_stopped,
+    });
+
+    // the redistributors are left to their cpus, see redist_init
+    gic().call_once(|| Gic {
+        gicd_base: root_config.arch.gicd_base,
+        gicr_base: root_config.arch.gicr_base,
+        gicd_size: root_config.arch.gicd_size,
+        gicr_size: root_config.arch.gicr_size,
+        eppi_num: read_eppi_num(root_config.arch.gicr_base),
+        espi_num: read_espi_num(root_config.arch.gicd_base),
+        nmi: read_nmi_support(root_config.arch.gicd_base) && cpu_has_nmi(),
+        implementation: detect_gic_impl(root_config.arch.gicd_base),
+        single_security: read_single_security(root_config.arch.gicd_base),
+    });
+    debug!("gic = {:#x?}", gic().get().unwrap());
+    if cfg!(feature = "hv_ipi_fiq") && !hv_ipi_fiq() {
+        warn!("hv_ipi_fiq needs a gic with a single security state, ipis stay group 1");
+    }
+    summary::init(MAX_CPU_NUM);
+}
+
+pub fn primary_init_late() {
+    report_percpu_init_time();
+    enable_gic_are_ns();
+    enable_irqs();
+    #[cfg(feature = "bench")]
+    bench::run();
+}
+
+const SDEI_VERSION: i64 = 0xc4000020;
+// firmware not ready to answer yet, the call may be retried. The other errors are final,
+// e.g. -2 is SDEI_INVALID_PARAMETERS.
+const SDEI_PENDING: i64 = -5;
+const SDEI_PROBE_ATTEMPTS: usize = 10;
+const SDEI_PROBE_DELAY_US: u64 = 1000;
+
+fn delay_us(us: u64) {
+    let end = read_sysreg!(cntpct_el0) + us * read_sysreg!(cntfrq_el0) / 1_000_000;
+    while read_sysreg!(cntpct_el0) < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// SDEI version of the firmware, `None` if it has no SDEI. Firmware which is still coming up
+/// may answer SDEI_PENDING, it is asked again a few times before SDEI is taken as unavailable.
+fn sdei_probe() -> Option<i64> {
+    for attempt in 0..SDEI_PROBE_ATTEMPTS {
+        let ret = unsafe { smc_arg1!(SDEI_VERSION) };
+        if ret != SDEI_PENDING {
+            return (ret >= 0).then_some(ret);
+        }
+        debug!("sdei probe attempt {}: firmware pending", attempt);
+        delay_us(SDEI_PROBE_DELAY_US << attempt.min(4));
+    }
+    warn!("sdei probe: firmware still busy after {} attempts", SDEI_PROBE_ATTEMPTS);
+    None
+}
+
+/// Per-cpu init of the primary cpu, which also does the system wide setup not tied to the
+/// distributor. The distributor itself is set up in `primary_init_late`.
+pub fn gicv3_primary_init() {
+    match sdei_probe() {
+        Some(ver) => info!("sdei_ver = {:#x}", ver),
+        None => info!("sdei unavailable"),
+    }
+    if let Err(e) = gicv3_secondary_init() {
+        panic!("gic init of the primary cpu failed: {:?}", e);
+    }
+    // after the cpu interface init, which reads this cpu's ICH_VTR_EL2
+    let caps = GIC_CAPS.call_once(|| detect_gic_caps(gic().get().unwrap()));
+    info!("gic capabilities: {:#x?}", caps);
+}
+
+/// Per-cpu init of a secondary cpu: only its own CPU interface and redistributor, the state
+/// shared with other cpus is left to the primary.
+pub fn gicv3_secondary_init() -> HvResult {
+    redist_init(this_cpu_id())?;
+    gicc_init();
+    enable_ipi();
+    // the EOIs of virtual-only irqs with a physical one behind them are signaled through it
+    enable_hv_ppi(MAINTENANCE_IRQ, MAINTENANCE_PRIORITY);
+    watchdog::cpu_init();
+    Ok(())
+}
+
+// Bring up the redistributor of `cpu`, on that cpu. No cpu touches the frame of another one
+// before, so the cpus bring up their redistributors in parallel, and a frame the GIC keeps
+// powered down until its cpu asks isn't accessed early.
+fn redist_init(cpu: usize) -> HvResult {
+    if let Some(power_on) = gic_quirk(host_gic_impl()).and_then(|quirk| quirk.redist_init) {
+        power_on(cpu);
+    }
+    check_gicr_frame(cpu, gic().get().unwrap().gicr_size)?;
+    wake_redist(cpu);
+    Ok(())
+}
+
+/// GIC init of this cpu. A secondary whose redistributor can't be brought up gets an error
+/// and has to stay offline: `cpu_online` tells the others.
+pub fn percpu_init(is_primary: bool) -> HvResult {
+    let start = read_sysreg!(cntpct_el0);
+    if is_primary {
+        gicv3_primary_init();
+    } else if let Err(e) = gicv3_secondary_init() {
+        error!("cpu {}: gic init failed, it stays offline: {:?}", this_cpu_id(), e);
+        this_gic().offline.store(true, Ordering::Relaxed);
+        return Err(e);
+    }
+    let ticks = read_sysreg!(cntpct_el0) - start;
+    this_gic().init_ticks.store(ticks, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether `cpu` got through its GIC init and may run a vcpu.
+pub fn cpu_online(cpu: usize) -> bool {
+    !cpu_gic(cpu).offline.load(Ordering::Relaxed)
+}
+
+// The cpus bring up their own GIC state in parallel: the slowest one is what boot waits for,
+// the sum what a serial bring-up by the primary would have cost.
+fn report_percpu_init_time() {
+    let ticks = (0..MAX_CPU_NUM).filter(|&cpu| cpu_online(cpu));
+    let ticks = ticks.map(|cpu| cpu_gic(cpu).init_ticks.load(Ordering::Relaxed));
+    let (slowest, total) = ticks.fold((0, 0), |(max, sum), t| (max.max(t), sum + t));
+    let freq = read_sysreg!(cntfrq_el0);
+    info!(
+        "gic per-cpu init: {} us for the slowest cpu, {} us for all of them in a row",
+        slowest * 1_000_000 / freq,
+        total * 1_000_000 / freq
+    );
+}
+
+impl Zone {
+    pub fn arch_irqchip_init(&mut self, arch: &HvArchZoneConfig) {
+        self.vgicv3.irq_depth_limit = arch.irq_depth_limit;
+        self.vgicv3.hw_unmapped = arch.hw_unmapped_irqs;
+        self.vgicv3.storm = Mutex::new(IrqStorm::new(StormConfig {
+            limit: arch.irq_storm_limit,
+            window_us: arch.irq_storm_window_us as _,
+            cooldown_us: arch.irq_storm_cooldown_us as _,
+        }));
+        self.vgicv3.pri_bits = arch.virtual_pri_bits;
+        self.vgicv3.update_pending_priority = arch.update_pending_priority != 0;
+        self.vgicv3.audit = Mutex::new(AuditRing::new(arch.irq_audit_entries));
+        self.vgicv3.audit_enabled = arch.irq_audit_entries != 0;
+        self.vgicv3.offline_irq_policy = OfflineIrqPolicy::from_config(arch.offline_irq_policy);
+        self.vgicv3.group_traps.set(HcrConfig::TALL0, arch.trap_group_regs & 1 != 0);
+        self.vgicv3.group_traps.set(HcrConfig::TALL1, arch.trap_group_regs & 2 != 0);
+        self.init_spi_priorities(arch.default_spi_priority);
+        self.icfgr_shadow_init();
+        self.vgicv3.watchdog = WatchdogConfig {
+            timeout_us: arch.watchdog_timeout_us as _,
+            irq: arch.watchdog_irq,
+        };
+    }
+
+    // Program `priority` into the priority byte of every SPI and extended SPI of the zone.
+    fn init_spi_priorities(&self, priority: u8) {
+        let gicd_base = host_gicd_base();
+        let spis = self.irq_bitmap.iter().enumerate().skip(1);
+        let spis = spis.map(|(idx, &mask)| (GICD_IPRIORITYR, idx * 32, mask));
+        let espis = self.espi_bitmap.iter().enumerate();
+        let espis = espis.map(|(idx, &mask)| (GICD_IPRIORITYRNE, idx * 32, mask));
+        for (reg, first_irq, mask) in spis.chain(espis) {
+            for bit in (0..32).filter(|bit| mask & (1 << bit) != 0) {
+                let addr = gicd_base + reg + first_irq + bit;
+                unsafe { write_volatile(addr as *mut u8, priority) };
+            }
+        }
+    }
+
+    pub fn arch_irqchip_reset(&self) {
+        let gicd_base = host_gicd_base();
+        for (idx, &mask) in self.irq_bitmap.iter().enumerate() {
+            if idx == 0 {
+                continue;
+            }
+            unsafe {
+                write_volatile((gicd_base + GICD_ICENABLER + idx * 4) as *mut u32, mask);
+                write_volatile((gicd_base + GICD_ICACTIVER + idx * 4) as *mut u32, mask);
+            }
+            for bit in 0..32 {
+                if mask & (1 << bit) != 0 {
+                    pending_queue_remove(idx * 32 + bit);
+                }
+            }
+        }
+        for (idx, &mask) in self.espi_bitmap.iter().enumerate() {
+            if mask == 0 {
+                continue;
+            }
+            unsafe {
+                write_volatile((gicd_base + GICD_ICENABLERNE + idx * 4) as *mut u32, mask);
+                write_volatile((gicd_base + GICD_ICACTIVERNE + idx * 4) as *mut u32, mask);
+            }
+            for bit in 0..32 {
+                if mask & (1 << bit) != 0 {
+                    pending_queue_remove(GICV3_ESPI_BASE as usize + idx * 32 + bit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::sysreg::mock;
+
+    // PPIs are only enabled in the redistributor, the distributor has no enable bits for them.
+    #[test]
+    fn ppi_enable_is_looked_up_in_the_redistributor() {
+        // extended PPIs only are PPIs on a GIC which has them
+        testing::boot(0);
+        assert_eq!(enable_bit(27), Some((EnableFrame::Redist, GICR_ISENABLER, 27)));
+        assert_eq!(enable_bit(3), Some((EnableFrame::Redist, GICR_ISENABLER, 3)));
+        assert_eq!(
+            enable_bit(GICV3_EPPI_BASE as usize + 5),
+            Some((EnableFrame::Redist, GICR_ISENABLER + 4, 5))
+        );
+    }
+
+    #[test]
+    fn spi_enable_is_looked_up_in_the_distributor() {
+        testing::boot(0);
+        assert_eq!(enable_bit(33), Some((EnableFrame::Dist, GICD_ISENABLER, 33)));
+        assert_eq!(
+            enable_bit(GICV3_ESPI_BASE as usize + 40),
+            Some((EnableFrame::Dist, GICD_ISENABLERNE, 40))
+        );
+        assert_eq!(enable_bit(1023), None);
+    }
+
+    // A guest configuring its distributor before it sets EnableGrp1 gets nothing injected,
+    // however many list registers are free, and as many irqs as its depth limit allows once
+    // it sets it.
+    #[test]
+    fn injection_waits_for_the_distributor_enable() {
+        assert!(blocks_injection(false, 0, 0, 4));
+        assert!(blocks_injection(false, 2, 0, 4));
+        assert!(!blocks_injection(true, 0, 0, 4));
+        assert!(!blocks_injection(true, 0, 3, 4));
+        assert!(blocks_injection(true, 0, 4, 4));
+        assert!(blocks_injection(true, 2, 2, 4));
+    }
+
+    // With EOImode 0 the guest's EOI drops the priority and deactivates the irq, with EOImode 1
+    // only the priority is dropped and the irq stays active until the guest writes ICC_DIR_EL1.
+    #[test]
+    fn eoi_deactivates_only_with_combined_eoimode() {
+        let lr_val = 40 | LR_GROUP1 | LR_STATE_ACTIVE;
+        for (vmcr, deactivated) in [(0, true), (ICH_VMCR_VEOIM, false)] {
+            mock::reset();
+            write_sysreg!(ich_vmcr_el2, vmcr);
+            write_sysreg!(ich_ap1r0_el2, 1 << 3 | 1 << 5);
+            write_lr(0, lr_val);
+            complete_eoi(0, lr_val, IrqGroup::Group1, 1);
+            assert_eq!(read_sysreg!(ich_ap1r0_el2), 1 << 5);
+            assert_eq!(read_lr(0) & LR_STATE_ACTIVE == 0, deactivated);
+        }
+        lr_deactivate(0, read_lr(0));
+        assert_eq!(read_lr(0), 40 | LR_GROUP1);
+    }
+
+    // Disabling the distributor parks the pending irqs of the list registers in front of the
+    // queue, an irq injected meanwhile waits behind them, and enabling it again replays them
+    // in that order. The active irq stays for the guest to finish.
+    #[test]
+    fn disabled_distributor_parks_and_replays_pending_irqs() {
+        pending::init(MAX_CPU_NUM);
+        let cpu = 1;
+        pending::clear(cpu);
+        mock::reset();
+        write_lr(0, 40 | LR_GROUP1 | LR_STATE_PENDING);
+        write_lr(1, 41 | LR_GROUP1 | LR_STATE_ACTIVE);
+        write_lr(2, 42 | LR_GROUP1 | LR_STATE_PENDING);
+        park_pending_lrs(cpu, 4);
+        assert_eq!(read_lr(0), 0);
+        assert_eq!(read_lr(1), 41 | LR_GROUP1 | LR_STATE_ACTIVE);
+        assert_eq!(read_lr(2), 0);
+
+        assert!(blocks_injection(false, 0, 1, 4));
+        let injected = lr_pending_irq(43 | LR_GROUP1);
+        pending::defer(cpu, injected);
+
+        assert!(!blocks_injection(true, 0, 1, 4));
+        let replayed: Vec<usize> = core::iter::from_fn(|| pending::pop(cpu))
+            .map(|irq| irq.irq_id)
+            .collect();
+        assert_eq!(replayed, [40, 42, 43]);
+        assert!(!injected.is_hardware);
+    }
+
+    // A hardware mapped irq the guest sees under another INTID is deactivated at the
+    // distributor by its pINTID, never by the vINTID of the list register.
+    #[test]
+    fn remapped_irq_is_deactivated_by_its_pintid() {
+        irq_lock::init();
+        mock::reset();
+        let lr_val = 40 | LR_GROUP1 | LR_STATE_ACTIVE | LR_HW | 72 << LR_PINTID_SHIFT;
+        assert_eq!(lr_pintid(lr_val), 72);
+        assert_eq!(lr_pintid(40 | LR_GROUP1 | LR_STATE_ACTIVE), 40);
+        write_lr(0, lr_val);
+        lr_deactivate(0, lr_val);
+        assert_eq!(read_lr(0) & LR_STATE_ACTIVE, 0);
+        assert_eq!(read_sysreg!(icc_dir_el1), 72);
+    }
+
+    // The guest loses its virtual interface before the hardware irqs of its list registers are
+    // deactivated, the list registers and active priorities are cleared before the hypervisor
+    // control, and the physical cpu interface is disabled last.
+    #[test]
+    fn cpu_shutdown_writes_the_registers_in_order() {
+        irq_lock::init();
+        mock::reset();
+        let pmr = 0xf0 << ICH_VMCR_VPMR_SHIFT;
+        write_sysreg!(ich_vmcr_el2, pmr | ICH_VMCR_VENG0 | ICH_VMCR_VENG1);
+        let hw_irq = 40 | LR_GROUP1 | LR_STATE_ACTIVE | LR_HW | 72 << LR_PINTID_SHIFT;
+        write_lr(0, hw_irq);
+        write_lr(1, 41 | LR_GROUP1 | LR_STATE_PENDING);
+        let setup = mock::writes().len();
+
+        shutdown_cpu_interface(2, 1, false);
+        let writes: Vec<(&str, u64)> = mock::writes()[setup..].to_vec();
+        assert_eq!(
+            writes,
+            [
+                ("ich_vmcr_el2", pmr),
+                ("icc_dir_el1", 72),
+                ("ich_lr0_el2", 0),
+                ("ich_lr1_el2", 0),
+                ("ich_ap0r0_el2", 0),
+                ("ich_ap1r0_el2", 0),
+                ("ich_hcr_el2", 0),
+                ("icc_igrpen1_el1", 0),
+            ]
+        );
+    }
+
+    // An irq of higher priority (lower value) than those in the full list registers takes the
+    // one of the lowest priority that only is pending. Active and pinned irqs stay, and an irq
+    // of the same priority waits.
+    #[test]
+    fn high_priority_irq_evicts_the_lowest_priority_pending_one() {
+        mock::reset();
+        let lr = |irq: u64, state: u64, priority: u64| {
+            irq | LR_GROUP1 | state | priority << LR_PRIORITY_SHIFT
+        };
+        write_lr(0, lr(40, LR_STATE_PENDING, 0xa0));
+        write_lr(1, lr(41, LR_STATE_ACTIVE, 0xf0));
+        write_lr(2, lr(42, LR_STATE_PENDING, 0x80));
+        write_lr(3, lr(43, LR_STATE_PENDING, 0xe0));
+        let pinned = 1 << 3;
+        assert_eq!(eviction_candidate_of(0x40, 4, pinned), Some(0));
+        assert_eq!(eviction_candidate_of(0x90, 4, pinned), Some(0));
+        assert_eq!(eviction_candidate_of(0xa0, 4, pinned), None);
+        assert_eq!(eviction_candidate_of(0xa0, 4, 0), Some(3));
+    }
+
+    const OFFLINE_SPI: usize = 50;
+    // the list register of OFFLINE_SPI injected on the cpu it arrived on
+    const OFFLINE_SPI_LR: u64 =
+        OFFLINE_SPI as u64 | (OFFLINE_SPI as u64) << 32 | LR_GROUP1 | LR_HW | LR_STATE_PENDING;
+
+    // Boot cpu 1 of a zone on cpus 0 and 1 which has `policy` for irqs of offline vcpus, with
+    // the vcpu of cpu 1 off.
+    fn boot_offline_vcpu(policy: OfflineIrqPolicy) {
+        testing::boot(1);
+        let zone = testing::zone(0, &[0, 1], &[OFFLINE_SPI]);
+        zone.write().vgicv3.dist_enabled = true;
+        zone.write().vgicv3.offline_irq_policy = policy;
+        get_cpu_data(1).arch_cpu.psci_on = false;
+    }
+
+    // A zone config without a policy, 0, or with one this hypervisor doesn't know holds the
+    // irqs of offline vcpus: the irq takes a list register of the vcpu, which gets it once it
+    // boots.
+    #[test]
+    fn offline_irqs_are_held_by_default() {
+        for policy in [0, 3, usize::MAX] {
+            let policy = OfflineIrqPolicy::from_config(policy);
+            assert_eq!(policy, OfflineIrqPolicy::HoldPending);
+        }
+        boot_offline_vcpu(OfflineIrqPolicy::HoldPending);
+        inject_irq(OFFLINE_SPI, true);
+        assert_eq!(read_lr(0), OFFLINE_SPI_LR);
+        assert!(!mock::writes().contains(&("icc_dir_el1", OFFLINE_SPI as u64)));
+    }
+
+    // Policy 1 drops the irq, a hardware one is deactivated as the guest will never EOI it.
+    #[test]
+    fn offline_irq_policy_1_drops_them() {
+        assert_eq!(
+            OfflineIrqPolicy::from_config(1),
+            OfflineIrqPolicy::DropWithWarn
+        );
+        boot_offline_vcpu(OfflineIrqPolicy::DropWithWarn);
+        inject_irq(OFFLINE_SPI, true);
+        assert_eq!(read_lr(0), 0);
+        assert!(mock::writes().contains(&("icc_dir_el1", OFFLINE_SPI as u64)));
+    }
+
+    // Policy 2 queues the irq on the first online vcpu of the zone, which injects it when it
+    // flushes its pending irqs; with no vcpu online the irq is held like with policy 0.
+    #[test]
+    fn offline_irq_policy_2_reroutes_them() {
+        assert_eq!(
+            OfflineIrqPolicy::from_config(2),
+            OfflineIrqPolicy::RerouteToOnline
+        );
+        boot_offline_vcpu(OfflineIrqPolicy::RerouteToOnline);
+        inject_irq(OFFLINE_SPI, true);
+        assert_eq!(read_lr(0), 0);
+        assert_eq!(pending::len(0), 1);
+        testing::run_on(0);
+        crate::work::drain_work(0);
+        assert_eq!(read_lr(0), OFFLINE_SPI_LR);
+        assert_eq!(pending::len(0), 0);
+
+        boot_offline_vcpu(OfflineIrqPolicy::RerouteToOnline);
+        get_cpu_data(0).arch_cpu.psci_on = false;
+        inject_irq(OFFLINE_SPI, true);
+        assert_eq!(read_lr(0), OFFLINE_SPI_LR);
+        assert_eq!(pending::len(0), 0);
+    }
+
+    // A guest EOI of an irq no list register holds active, only pending or active in the other
+    // group, completes nothing, so the active priority of the irq which is active stays.
+    #[test]
+    fn bogus_eoi_completes_no_list_register() {
+        mock::reset();
+        write_lr(0, 40 | LR_GROUP1 | LR_STATE_ACTIVE);
+        write_lr(1, 41 | LR_GROUP1 | LR_STATE_PENDING);
+        assert_eq!(eoi_lr(41, IrqGroup::Group1, 2), None);
+        assert_eq!(eoi_lr(42, IrqGroup::Group1, 2), None);
+        assert_eq!(eoi_lr(40, IrqGroup::Group0, 2), None);
+        assert_eq!(
+            eoi_lr(40, IrqGroup::Group1, 2),
+            Some((0, 40 | LR_GROUP1 | LR_STATE_ACTIVE))
+        );
+    }
+
+    // A cpu index beyond the configured redistributors or MAX_CPU_NUM, or one whose frame
+    // address overflows, has no frame instead of a wild address.
+    #[test]
+    fn out_of_range_cpu_has_no_redistributor_frame() {
+        let base = 0x80a_0000;
+        let size = MAX_CPU_NUM * PER_GICR_SIZE;
+        assert_eq!(gicr_frame(base, size, 0), Some(base));
+        let last = MAX_CPU_NUM - 1;
+        let last_frame = base + last * PER_GICR_SIZE;
+        assert_eq!(gicr_frame(base, size, last), Some(last_frame));
+        assert_eq!(gicr_frame(base, size, MAX_CPU_NUM), None);
+        assert_eq!(gicr_frame(base, usize::MAX, MAX_CPU_NUM), None);
+        assert_eq!(gicr_frame(base, PER_GICR_SIZE, 1), None);
+        assert_eq!(gicr_frame(base, size, usize::MAX), None);
+        assert_eq!(gicr_frame(usize::MAX - PER_GICR_SIZE + 1, size, 1), None);
+    }
+
+    // A vcpu torn down with a group 0 irq active, e.g. the watchdog irq, doesn't leave its
+    // active priority behind for the next vcpu on the cpu.
+    #[test]
+    fn vcpu_reset_clears_the_active_priorities_of_both_groups() {
+        mock::reset();
+        write_lr(0, 40 | LR_STATE_ACTIVE);
+        write_sysreg!(ich_ap0r0_el2, 1 << 2);
+        write_sysreg!(ich_ap1r0_el2, 1 << 5);
+        write_sysreg!(ich_ap0r1_el2, 1);
+        clear_lrs_and_aprs(4, 2);
+        assert_eq!(read_lr(0), 0);
+        assert_eq!(read_sysreg!(ich_ap0r0_el2), 0);
+        assert_eq!(read_sysreg!(ich_ap1r0_el2), 0);
+        assert_eq!(read_sysreg!(ich_ap0r1_el2), 0);
+    }
+
+    // A physical SPI the zone has injected without the HW bit is only priority dropped when
+    // it is forwarded. The guest's EOI raises the maintenance irq, which deactivates it.
+    #[test]
+    fn unmapped_spi_is_deactivated_after_the_guest_eoi() {
+        const SPI: usize = 45;
+        testing::boot(0);
+        let zone = testing::zone(0, &[0], &[SPI]);
+        zone.write().vgicv3.dist_enabled = true;
+        zone.write().vgicv3.hw_unmapped[SPI / 32] |= 1 << (SPI % 32);
+        let deactivated = || mock::writes().contains(&("icc_dir_el1", SPI as u64));
+
+        dispatch_irq(SPI);
+        assert!(mock::writes().contains(&("icc_eoir1_el1", SPI as u64)));
+        assert!(!deactivated());
+        let lr_val = read_lr(0);
+        assert_eq!(lr_val, SPI as u64 | LR_GROUP1 | LR_STATE_PENDING | LR_EOI);
+
+        // the guest acknowledges and deactivates the virtual irq
+        write_lr(0, lr_val & !LR_STATE_MASK);
+        write_sysreg!(ich_eisr_el2, 1);
+        write_sysreg!(ich_misr_el2, MisrFlags::EOI.bits());
+        dispatch_irq(MAINTENANCE_IRQ);
+        assert!(deactivated());
+        assert_eq!(read_lr(0), 0);
+    }
+
+    // A zone asking for group 1 traps has its vcpus run with ICH_HCR_EL2.TALL1, and TALL0 off.
+    #[test]
+    fn vcpus_trap_the_group_registers_their_zone_asks_for() {
+        testing::boot(0);
+        let zone = testing::zone(0, &[0], &[]);
+        zone.write().vgicv3.group_traps = HcrConfig::TALL1;
+        set_local_hcr(HcrConfig::TALL0, true);
+        gicv3_vcpu_init();
+        let hcr = HcrConfig::from_bits_retain(read_sysreg!(ich_hcr_el2));
+        assert!(hcr.contains(HcrConfig::TALL1));
+        assert!(!hcr.contains(HcrConfig::TALL0));
+    }
+
+    // The binary points of trapped ICC_BPR<n>_EL1 are kept in ICH_VMCR_EL2, no smaller than the
+    // 5 preemption bits of the virtual interface allow. With ICC_CTLR_EL1.CBPR group 1 goes by
+    // the binary point of group 0 and ignores writes.
+    #[test]
+    fn trapped_binary_points_live_in_the_vmcr() {
+        testing::boot(0);
+        let bprs = || (read_vbpr(IrqGroup::Group0), read_vbpr(IrqGroup::Group1));
+        write_vbpr(IrqGroup::Group0, 0);
+        write_vbpr(IrqGroup::Group1, 5);
+        assert_eq!(bprs(), (2, 5));
+        write_vctlr(ICC_CTLR_CBPR);
+        write_vbpr(IrqGroup::Group1, 7);
+        assert_eq!(bprs(), (2, 3));
+        write_vctlr(0);
+        assert_eq!(bprs(), (2, 5));
+    }
+
+    // With the group registers trapped the guest acknowledges the highest priority irq of a
+    // group from the list registers, unless it can't preempt the active one. ICC_HPPIR<n>_EL1
+    // shows it either way, the active priorities of the acknowledged irqs are in ICC_AP<n>R0.
+    #[test]
+    fn trapped_acknowledge_takes_the_highest_priority_irq_of_its_group() {
+        testing::boot(0);
+        let vmcr = 0xff << ICH_VMCR_VPMR_SHIFT | ICH_VMCR_VENG0 | ICH_VMCR_VENG1;
+        write_sysreg!(ich_vmcr_el2, vmcr);
+        let lr = |irq: u64, group, priority: u64| {
+            irq | group | LR_STATE_PENDING | priority << LR_PRIORITY_SHIFT
+        };
+        write_lr(0, lr(40, LR_GROUP1, 0xa0));
+        write_lr(1, lr(41, 0, 0x80));
+        write_lr(2, lr(42, LR_GROUP1, 0x90));
+        assert_eq!(read_vhppir(IrqGroup::Group0), 41);
+        assert_eq!(read_vhppir(IrqGroup::Group1), 42);
+
+        assert_eq!(virtual_acknowledge_group(0, IrqGroup::Group0), 41);
+        assert_eq!(read_lr(1) & LR_STATE_MASK, LR_STATE_ACTIVE);
+        // priority 0x80 is preemption level 16 of 32, in the only register there is
+        assert_eq!(read_vapr(IrqGroup::Group0, 0), 1 << 16);
+        assert_eq!(read_vapr(IrqGroup::Group0, 1), 0);
+        assert_eq!(virtual_acknowledge(0), 1023);
+        assert_eq!(read_vhppir(IrqGroup::Group1), 42);
+
+        virtual_eoi(41, IrqGroup::Group0);
+        assert_eq!(read_vapr(IrqGroup::Group0, 0), 0);
+        assert_eq!(virtual_acknowledge(0), 42);
+        assert_eq!(read_vapr(IrqGroup::Group1, 0), 1 << 18);
+        write_vapr(IrqGroup::Group1, 0, 0);
+        assert_eq!(read_vapr(IrqGroup::Group1, 0), 0);
+    }
+
+    // ICC_IAR1_EL1 leaves a pending NMI to ICC_NMIAR1_EL1 and returns 1022 for it, the
+    // guest's priority mask doesn't mask it.
+    #[test]
+    fn trapped_nmi_is_acknowledged_through_nmiar() {
+        testing::boot(0);
+        write_sysreg!(ich_vmcr_el2, 0x80 << ICH_VMCR_VPMR_SHIFT | ICH_VMCR_VENG1);
+        let lr = |irq: u64, nmi, priority: u64| {
+            irq | LR_GROUP1 | nmi | LR_STATE_PENDING | priority << LR_PRIORITY_SHIFT
+        };
+        write_lr(0, lr(40, 0, 0xa0));
+        write_lr(1, lr(41, LR_NMI, 0x90));
+        assert_eq!(virtual_acknowledge(0), 1022);
+        assert_eq!(virtual_acknowledge_nmi(0), 41);
+        assert_eq!(virtual_acknowledge_nmi(0), 1023);
     }
 }
@@ -0,0 +1,160 @@
+//! Per-cpu queue of virtual interrupts which are deferred instead of being written to a list
+//! register right away. They are moved into list registers by `flush_pending_irqs`.
+use alloc::{collections::VecDeque, vec::Vec};
+use spin::{Mutex, Once};
+
+use super::IrqGroup;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingIrq {
+    pub irq_id: usize,
+    pub is_hardware: bool,
+    pub group: IrqGroup,
+    /// Injected with the NMI bit of the list register, see `inject_nmi`.
+    pub nmi: bool,
+}
+
+#[derive(Default)]
+struct PendingQueue {
+    irqs: VecDeque<PendingIrq>,
+    // how many injections were deferred on this cpu
+    deferred: usize,
+    // virtual irqs injected while the guest had them disabled, see `hold_disabled`
+    disabled: Vec<PendingIrq>,
+}
+
+machine_static! {
+    fn pending_irqs() -> Once<Vec<Mutex<PendingQueue>>> = Once::new();
+}
+
+pub fn init(max_cpus: usize) {
+    let new_queue = |_| Mutex::new(PendingQueue::default());
+    pending_irqs().call_once(|| (0..max_cpus).map(new_queue).collect());
+}
+
+fn queue(cpu: usize) -> &'static Mutex<PendingQueue> {
+    &pending_irqs().get().unwrap()[cpu]
+}
+
+/// Queue `irq` on `cpu`, unless it is already waiting there.
+pub fn defer(cpu: usize, irq: PendingIrq) {
+    let mut q = queue(cpu).lock();
+    q.deferred += 1;
+    if !q.irqs.iter().any(|pending| pending.irq_id == irq.irq_id) {
+        q.irqs.push_back(irq);
+    }
+}
+
+/// Put `irq` back in front of the queue of `cpu`, unless it is already waiting there.
+pub fn requeue(cpu: usize, irq: PendingIrq) {
+    let mut q = queue(cpu).lock();
+    if !q.irqs.iter().any(|pending| pending.irq_id == irq.irq_id) {
+        q.irqs.push_front(irq);
+    }
+}
+
+pub fn pop(cpu: usize) -> Option<PendingIrq> {
+    queue(cpu).lock().irqs.pop_front()
+}
+
+/// Drop `irq_id` from the queue of `cpu`, and from its disabled irqs.
+pub fn remove(cpu: usize, irq_id: usize) {
+    let mut q = queue(cpu).lock();
+    q.irqs.retain(|pending| pending.irq_id != irq_id);
+    q.disabled.retain(|pending| pending.irq_id != irq_id);
+}
+
+pub fn clear(cpu: usize) {
+    let mut q = queue(cpu).lock();
+    q.irqs.clear();
+    q.disabled.clear();
+}
+
+/// Keep `irq` pending on `cpu` while the guest has it disabled, like the distributor keeps a
+/// disabled irq pending, until `take_enabled` hands it back. An irq is held once.
+pub fn hold_disabled(cpu: usize, irq: PendingIrq) {
+    let mut q = queue(cpu).lock();
+    if !q.disabled.iter().any(|held| held.irq_id == irq.irq_id) {
+        q.disabled.push(irq);
+    }
+}
+
+/// Remove the irqs held on `cpu` by `hold_disabled` which are among the 32 irqs starting at
+/// `first_irq` that have their bit set in `mask`, and return them oldest first.
+pub fn take_enabled(cpu: usize, first_irq: usize, mask: u32) -> Vec<PendingIrq> {
+    let in_mask = |irq_id: usize| {
+        (first_irq..first_irq + 32).contains(&irq_id) && mask & (1 << (irq_id - first_irq)) != 0
+    };
+    let mut q = queue(cpu).lock();
+    let (enabled, disabled) =
+        core::mem::take(&mut q.disabled).into_iter().partition(|held| in_mask(held.irq_id));
+    q.disabled = disabled;
+    enabled
+}
+
+/// Call `f` on every irq queued on `cpu`, oldest first.
+pub fn for_each(cpu: usize, f: impl FnMut(&PendingIrq)) {
+    queue(cpu).lock().irqs.iter().for_each(f);
+}
+
+pub fn len(cpu: usize) -> usize {
+    queue(cpu).lock().irqs.len()
+}
+
+pub fn is_empty(cpu: usize) -> bool {
+    queue(cpu).lock().irqs.is_empty()
+}
+
+pub fn deferred_irqs(cpu: usize) -> usize {
+    queue(cpu).lock().deferred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn virtual_irq(irq_id: usize) -> PendingIrq {
+        PendingIrq {
+            irq_id,
+            is_hardware: false,
+            group: IrqGroup::Group1,
+            nmi: false,
+        }
+    }
+
+    #[test]
+    fn disabled_irq_stays_pending_until_enabled() {
+        init(4);
+        let cpu = 0;
+        hold_disabled(cpu, virtual_irq(40));
+        hold_disabled(cpu, virtual_irq(41));
+        hold_disabled(cpu, virtual_irq(40));
+        // enabling another irq of the register leaves them held
+        assert!(take_enabled(cpu, 32, 1 << 2).is_empty());
+        assert_eq!(take_enabled(cpu, 32, 1 << 8), [virtual_irq(40)]);
+        assert!(take_enabled(cpu, 32, 1 << 8).is_empty());
+        assert_eq!(take_enabled(cpu, 32, u32::MAX), [virtual_irq(41)]);
+    }
+
+    // A PPI the guest enabled only in its redistributor comes back through the GICR
+    // ISENABLER0 write, which covers the SGIs and PPIs from 0.
+    #[test]
+    fn ppi_enabled_in_the_redistributor_is_released() {
+        init(4);
+        let cpu = 1;
+        hold_disabled(cpu, virtual_irq(27));
+        assert_eq!(take_enabled(cpu, 0, 1 << 27), [virtual_irq(27)]);
+    }
+
+    #[test]
+    fn held_irqs_are_per_cpu_and_cleared_with_the_queue() {
+        init(4);
+        hold_disabled(2, virtual_irq(50));
+        hold_disabled(3, virtual_irq(50));
+        assert!(take_enabled(2, 32, 0).is_empty());
+        clear(3);
+        assert!(take_enabled(3, 32, u32::MAX).is_empty());
+        remove(2, 50);
+        assert!(take_enabled(2, 32, u32::MAX).is_empty());
+    }
+}
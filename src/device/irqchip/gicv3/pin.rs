@@ -0,0 +1,59 @@
+//! List registers reserved for single irqs, e.g. for latency measurements or irqs which must
+//! never wait in the pending queue. A pinned irq always goes to its LR, and the general free LR
+//! scan never hands that LR out for another irq. Pins apply to every cpu.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::lr_count;
+use crate::error::HvResult;
+
+// (irq id, list register)
+static PINS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+// one bit for each pinned LR, so injections without pins don't take the lock
+static PINNED_LRS: AtomicU64 = AtomicU64::new(0);
+
+/// Reserve list register `lr` for `irq_id`, replacing an earlier pin of `irq_id`. At least one
+/// LR is left for all other irqs.
+pub fn pin_irq_to_lr(irq_id: usize, lr: usize) -> HvResult {
+    if lr >= lr_count() {
+        return hv_result_err!(EINVAL, format!("lr {} out of range, {} lrs", lr, lr_count()));
+    }
+    let mut pins = PINS.lock();
+    if let Some(&(other, _)) = pins.iter().find(|&&(irq, pinned)| pinned == lr && irq != irq_id) {
+        return hv_result_err!(EBUSY, format!("lr {} already pinned to irq {}", lr, other));
+    }
+    pins.retain(|&(irq, _)| irq != irq_id);
+    if pins.len() + 1 >= lr_count() {
+        return hv_result_err!(EINVAL, "can't pin every lr");
+    }
+    pins.push((irq_id, lr));
+    PINNED_LRS.store(mask(&pins), Ordering::Release);
+    info!("irq {} pinned to lr {}", irq_id, lr);
+    Ok(())
+}
+
+pub fn unpin_irq(irq_id: usize) {
+    let mut pins = PINS.lock();
+    pins.retain(|&(irq, _)| irq != irq_id);
+    PINNED_LRS.store(mask(&pins), Ordering::Release);
+}
+
+fn mask(pins: &[(usize, usize)]) -> u64 {
+    pins.iter().fold(0, |mask, &(_, lr)| mask | 1 << lr)
+}
+
+/// The LRs the free LR scan has to skip.
+pub fn pinned_lrs() -> u64 {
+    PINNED_LRS.load(Ordering::Acquire)
+}
+
+/// The LR reserved for `irq_id`, if the cpu running this has it.
+pub fn pinned_lr(irq_id: usize) -> Option<usize> {
+    if pinned_lrs() == 0 {
+        return None;
+    }
+    let pins = PINS.lock();
+    let &(_, lr) = pins.iter().find(|&&(irq, _)| irq == irq_id)?;
+    (lr < lr_count()).then_some(lr)
+}
@@ -0,0 +1,83 @@
+//! Delivery notices for paravirtualized guests. A vcpu may register one page of its memory
+//! with the `HvPvIrqRegister` hypercall, the hypervisor then sets the bit of every irq it
+//! injects into that vcpu in the page and bumps its sequence number, so the guest learns about
+//! delivered irqs without reading its GIC. Vcpus which never register a page don't see a
+//! difference.
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::{
+    cpu_state::{cpu_gic, this_gic},
+    guest_mem::guest_ram,
+};
+use crate::{consts::PAGE_SIZE, error::HvResult, memory::MemFlags, percpu::this_zone, zone::Zone};
+
+/// Layout of the shared page.
+#[repr(C)]
+pub struct PvIrqPage {
+    /// Bumped after each injection, once `pending` and `last_irq` are updated.
+    pub seq: AtomicU64,
+    pub last_irq: AtomicU64,
+    /// One bit per irq below 1024 injected since the guest last cleared it. Only the
+    /// hypervisor sets bits, only the guest clears them.
+    pub pending: [AtomicU64; 16],
+}
+
+/// The vcpu's shared page, 0 if it has none.
+#[derive(Debug, Default)]
+pub struct PvState {
+    page: AtomicUsize,
+}
+
+impl PvState {
+    pub const fn new() -> Self {
+        Self {
+            page: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Register the page at guest physical `gpa` for the vcpu running on this cpu, 0 stops the
+/// notices.
+pub fn register(gpa: usize) -> HvResult {
+    let pv = &this_gic().pv;
+    if gpa == 0 {
+        pv.page.store(0, Ordering::Release);
+        return Ok(());
+    }
+    if gpa % PAGE_SIZE != 0 {
+        return hv_result_err!(EINVAL, "pv irq page not page aligned");
+    }
+    // the page is updated with atomics, which fault on Device memory
+    let page = guest_ram(
+        &this_zone().read().gpm,
+        gpa,
+        MemFlags::READ | MemFlags::WRITE,
+    )?;
+    pv.page.store(page, Ordering::Release);
+    Ok(())
+}
+
+/// Forget the page of the vcpu on `cpu`, when the vcpu is reset.
+pub fn reset(cpu: usize) {
+    cpu_gic(cpu).pv.page.store(0, Ordering::Release);
+}
+
+/// Forget the pages of all vcpus of a zone which is shut down. Its memory may be handed to
+/// another zone before its cpus get to reset their vcpus.
+pub fn zone_stopped(zone: &Zone) {
+    for cpu in zone.cpu_set.iter() {
+        reset(cpu);
+    }
+}
+
+/// Note the injection of `irq_id` into the vcpu on this cpu.
+pub fn notify(irq_id: usize) {
+    let page = this_gic().pv.page.load(Ordering::Acquire);
+    if page == 0 || irq_id >= 1024 {
+        return;
+    }
+    let page = unsafe { &*(page as *const PvIrqPage) };
+    page.pending[irq_id / 64].fetch_or(1 << (irq_id % 64), Ordering::Relaxed);
+    page.last_irq.store(irq_id as _, Ordering::Relaxed);
+    page.seq.fetch_add(1, Ordering::Release);
+}
@@ -0,0 +1,77 @@
+//! Interrupt accounting of one zone instance. The counters start at zero when the zone is
+//! created and are logged when it is removed, so a restarted zone doesn't inherit the numbers
+//! of the previous one.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{arch::aarch64::sysreg::read_sysreg, percpu::this_cpu_data, zone::Zone};
+
+#[derive(Debug, Default)]
+pub struct IrqStats {
+    /// Virtual irqs injected into the zone's vcpus, written to an LR or deferred.
+    pub injected: AtomicU64,
+    /// Injections which had to wait in the pending queue.
+    pub deferred: AtomicU64,
+    /// Physical PPIs and SPIs forwarded to the zone.
+    pub forwarded: AtomicU64,
+    /// Maintenance irqs taken on the zone's cpus.
+    pub maintenance: AtomicU64,
+    // CNTPCT_EL0 when the zone started
+    started_at: AtomicU64,
+}
+
+/// Plain copy of `IrqStats` at one point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqStatsSnapshot {
+    pub injected: u64,
+    pub deferred: u64,
+    pub forwarded: u64,
+    pub maintenance: u64,
+    pub started_at: u64,
+}
+
+impl IrqStats {
+    pub fn reset(&self) {
+        self.injected.store(0, Ordering::Relaxed);
+        self.deferred.store(0, Ordering::Relaxed);
+        self.forwarded.store(0, Ordering::Relaxed);
+        self.maintenance.store(0, Ordering::Relaxed);
+        self.started_at.store(read_sysreg!(cntpct_el0), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> IrqStatsSnapshot {
+        IrqStatsSnapshot {
+            injected: self.injected.load(Ordering::Relaxed),
+            deferred: self.deferred.load(Ordering::Relaxed),
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            maintenance: self.maintenance.load(Ordering::Relaxed),
+            started_at: self.started_at.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bump the `counter` of the zone this cpu runs, if any.
+pub fn count(counter: fn(&IrqStats) -> &AtomicU64) {
+    if let Some(zone) = &this_cpu_data().zone {
+        counter(&zone.read().vgicv3.stats).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Zone start hook, registered in `primary_init_early`.
+pub fn zone_started(zone: &mut Zone) {
+    zone.vgicv3.stats.reset();
+}
+
+/// Zone stop hook, registered in `primary_init_early`.
+pub fn zone_stopped(zone: &Zone) {
+    let stats = zone.vgicv3.stats.snapshot();
+    let ticks = read_sysreg!(cntpct_el0) - stats.started_at;
+    info!(
+        "zone {} irq stats over {} ms: {} injected, {} deferred, {} forwarded, {} maintenance",
+        zone.id,
+        ticks * 1000 / read_sysreg!(cntfrq_el0),
+        stats.injected,
+        stats.deferred,
+        stats.forwarded,
+        stats.maintenance
+    );
+}
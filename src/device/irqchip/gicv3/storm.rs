@@ -0,0 +1,130 @@
+//! Circuit breaker for SPIs a device raises at a runaway rate. An SPI that arrives more often
+//! than the zone allows within a window is masked at the distributor, and enabled again once
+//! its cooldown is over. Cooldowns are polled on every irq exit, the cpu which masked an SPI
+//! also has the hypervisor timer fire when its cooldown ends (see `watchdog::kick`), so the
+//! SPI comes back even if nothing else makes the cpu exit, e.g. while its vcpu idles.
+use alloc::vec::Vec;
+
+use super::{
+    gicd::{GICD_ICENABLER, GICD_ISENABLER},
+    host_gicd_base,
+};
+use crate::arch::aarch64::sysreg::read_sysreg;
+
+/// Thresholds of the breaker, `limit` 0 turns it off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StormConfig {
+    /// Max arrivals of one SPI within `window_us`.
+    pub limit: usize,
+    pub window_us: u64,
+    /// How long an SPI stays masked once it exceeded the limit.
+    pub cooldown_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SpiRate {
+    window_start: u64,
+    count: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct IrqStorm {
+    config: StormConfig,
+    // indexed by irq - 32, only allocated if the breaker is on
+    rates: Vec<SpiRate>,
+    // SPIs masked by the breaker and the tick when they are enabled again
+    masked: Vec<(usize, u64)>,
+}
+
+fn ticks() -> u64 {
+    read_sysreg!(cntpct_el0)
+}
+
+/// Timer ticks in `us` microseconds. Whole seconds are converted first, multiplying all of
+/// `us` by a counter frequency of 100 MHz would overflow after a few hours.
+pub fn us_to_ticks(us: u64) -> u64 {
+    let freq = read_sysreg!(cntfrq_el0);
+    us / 1_000_000 * freq + us % 1_000_000 * freq / 1_000_000
+}
+
+fn write_enable_bit(reg: usize, irq: usize) {
+    let addr = host_gicd_base() + reg + irq / 32 * 4;
+    unsafe { (addr as *mut u32).write_volatile(1 << (irq % 32)) };
+}
+
+impl IrqStorm {
+    pub fn new(config: StormConfig) -> Self {
+        let rates = if config.limit != 0 {
+            alloc::vec![SpiRate::default(); 1024 - 32]
+        } else {
+            Vec::new()
+        };
+        Self {
+            config,
+            rates,
+            masked: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.limit != 0
+    }
+
+    /// Count an arrival of SPI `irq` and mask it if it exceeded the limit of its window.
+    /// Returns whether it got masked.
+    pub fn record(&mut self, irq: usize) -> bool {
+        if !self.is_enabled() || !(32..1024).contains(&irq) {
+            return false;
+        }
+        let now = ticks();
+        let rate = &mut self.rates[irq - 32];
+        if now - rate.window_start > us_to_ticks(self.config.window_us) {
+            rate.window_start = now;
+            rate.count = 0;
+        }
+        rate.count += 1;
+        if rate.count <= self.config.limit || self.masked.iter().any(|&(m, _)| m == irq) {
+            return false;
+        }
+        write_enable_bit(GICD_ICENABLER, irq);
+        self.masked.push((irq, now + us_to_ticks(self.config.cooldown_us)));
+        warn!(
+            "irq storm: spi {} raised {} times within {} us, masked for {} us",
+            irq, rate.count, self.config.window_us, self.config.cooldown_us
+        );
+        true
+    }
+
+    /// Enable the masked SPIs whose cooldown is over.
+    pub fn poll(&mut self) {
+        if self.masked.is_empty() {
+            return;
+        }
+        let now = ticks();
+        self.masked.retain(|&(irq, until)| {
+            if now < until {
+                return true;
+            }
+            info!("irq storm: spi {} enabled again", irq);
+            write_enable_bit(GICD_ISENABLER, irq);
+            false
+        });
+    }
+
+    /// The guest enabled or disabled the SPIs in `mask` starting at `first_irq` itself, they
+    /// aren't enabled again once their cooldown is over.
+    pub fn forget(&mut self, first_irq: usize, mask: u32) {
+        self.masked.retain(|&(irq, _)| {
+            !(first_irq..first_irq + 32).contains(&irq) || mask & (1 << (irq - first_irq)) == 0
+        });
+    }
+
+    pub fn has_masked(&self) -> bool {
+        !self.masked.is_empty()
+    }
+
+    /// The tick when the first cooldown ends, if an SPI is masked.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.masked.iter().map(|&(_, until)| until).min()
+    }
+}
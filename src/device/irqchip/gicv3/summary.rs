@@ -0,0 +1,252 @@
+//! Snapshot of the interrupt configuration for the root zone, see `HvIrqSummary`.
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use super::{
+    gicd::{
+        GICD_IROUTER, GICD_ISACTIVER, GICD_ISENABLER, GICD_ISPENDR, GICD_TYPER,
+        GICD_TYPER_ITLINES_MASK,
+    },
+    guest_mem::{as_bytes, copy_to_guest, read_from_guest},
+    host_gicd_base, pending,
+};
+use crate::{
+    arch::aarch64::sysreg::read_sysreg,
+    consts::MAX_CPU_NUM,
+    error::HvResult,
+    percpu::this_zone,
+    zone::{find_irq_owner, find_zone},
+};
+
+/// Start of the summary buffer, followed by `spi_num` `HvSpiSummary` entries.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvIrqSummary {
+    /// `HvIrqSummary::VERSION`, tells the layout of the header and of the entries.
+    pub version: u32,
+    /// `size_of::<HvIrqSummary>()`, where the entries start.
+    pub size: u32,
+    pub cpu_num: u64,
+    /// Number of entries that follow, fewer than the SPIs in use if the buffer is too small.
+    pub spi_num: u64,
+    /// List registers in use on each cpu when it last handled an irq.
+    pub lrs_in_use: [u64; MAX_CPU_NUM],
+    /// Injections on each cpu which found all of its list registers taken.
+    pub lrs_full: [u64; MAX_CPU_NUM],
+    /// How often the IARs returned each of the special INTIDs 1020 to 1023.
+    pub special_intids: [u64; 4],
+    /// CNTPCT_EL0 when the summary was taken and CNTFRQ_EL0, to tell how long ago the
+    /// `last_injected` of the entries was.
+    pub timestamp: u64,
+    pub counter_freq: u64,
+}
+
+impl HvIrqSummary {
+    /// Bumped whenever a field of the header or of `HvSpiSummary` changes.
+    pub const VERSION: u32 = 1;
+}
+
+/// One SPI which belongs to a zone or is enabled at the distributor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvSpiSummary {
+    pub irq: u32,
+    /// `u32::MAX` if no zone owns the irq.
+    pub zone_id: u32,
+    pub enabled: u32,
+    pub reserved: u32,
+    /// GICD_IROUTER of the irq.
+    pub route: u64,
+    /// How often the irq was injected into its zone.
+    pub injections: u64,
+    /// CNTPCT_EL0 at the last injection of the irq, 0 if it never was injected.
+    pub last_injected: u64,
+}
+
+static SPI_INJECTIONS: Once<Vec<AtomicU64>> = Once::new();
+static SPI_LAST_INJECTED: Once<Vec<AtomicU64>> = Once::new();
+static LRS_IN_USE: Once<Vec<AtomicUsize>> = Once::new();
+static LRS_FULL: Once<Vec<AtomicU64>> = Once::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static SPECIAL_INTIDS: [AtomicU64; 4] = [ZERO; 4];
+
+/// End of the SPI range of the distributor, from GICD_TYPER.ITLinesNumber.
+pub fn max_spi() -> usize {
+    (32 * ((gicd_read(GICD_TYPER) as usize & GICD_TYPER_ITLINES_MASK) + 1)).min(1020)
+}
+
+/// Needs the distributor to be known, for `max_spi`.
+pub fn init(max_cpus: usize) {
+    SPI_INJECTIONS.call_once(|| (0..1024).map(|_| AtomicU64::new(0)).collect());
+    SPI_LAST_INJECTED.call_once(|| (0..max_spi()).map(|_| AtomicU64::new(0)).collect());
+    LRS_IN_USE.call_once(|| (0..max_cpus).map(|_| AtomicUsize::new(0)).collect());
+    LRS_FULL.call_once(|| (0..max_cpus).map(|_| AtomicU64::new(0)).collect());
+}
+
+pub fn count_injection(irq_id: usize) {
+    if let Some(count) = SPI_INJECTIONS.get().unwrap().get(irq_id) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn update_lrs_in_use(cpu: usize, in_use: usize) {
+    LRS_IN_USE.get().unwrap()[cpu].store(in_use, Ordering::Relaxed);
+}
+
+/// Remember when `irq_id` was injected last, for telling irqs which stopped arriving.
+pub fn stamp_injection(irq_id: usize) {
+    if let Some(last) = SPI_LAST_INJECTED.get().unwrap().get(irq_id) {
+        last.store(read_sysreg!(cntpct_el0), Ordering::Relaxed);
+    }
+}
+
+/// Count an injection on `cpu` with all list registers taken, returns the new count.
+pub fn count_lrs_full(cpu: usize) -> u64 {
+    LRS_FULL.get().unwrap()[cpu].fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Count a read of special INTID `intid` from an IAR, returns the new count.
+pub fn count_special_intid(intid: usize) -> u64 {
+    SPECIAL_INTIDS[intid - 1020].fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn gicd_read(reg: usize) -> u32 {
+    unsafe { ((host_gicd_base() + reg) as *const u32).read_volatile() }
+}
+
+/// Write the summary to the root zone's `size` bytes at guest physical `buf`, returns how
+/// many bytes were written.
+pub fn irq_summary(buf: usize, size: usize) -> HvResult<usize> {
+    if size < size_of::<HvIrqSummary>() {
+        return hv_result_err!(EINVAL, "irq summary buffer too small");
+    }
+    let zone = this_zone();
+    let lrs_in_use = LRS_IN_USE.get().unwrap();
+    let mut header = HvIrqSummary {
+        version: HvIrqSummary::VERSION,
+        size: size_of::<HvIrqSummary>() as _,
+        cpu_num: MAX_CPU_NUM as _,
+        spi_num: 0,
+        lrs_in_use: [0; MAX_CPU_NUM],
+        lrs_full: [0; MAX_CPU_NUM],
+        special_intids: [0; 4],
+        timestamp: read_sysreg!(cntpct_el0),
+        counter_freq: read_sysreg!(cntfrq_el0),
+    };
+    for (cpu, in_use) in lrs_in_use.iter().enumerate() {
+        header.lrs_in_use[cpu] = in_use.load(Ordering::Relaxed) as _;
+    }
+    for (cpu, full) in LRS_FULL.get().unwrap().iter().enumerate() {
+        header.lrs_full[cpu] = full.load(Ordering::Relaxed);
+    }
+    for (count, special) in header.special_intids.iter_mut().zip(&SPECIAL_INTIDS) {
+        *count = special.load(Ordering::Relaxed);
+    }
+
+    let irq_num = max_spi();
+    let capacity = (size - size_of::<HvIrqSummary>()) / size_of::<HvSpiSummary>();
+    let mut gpa = buf + size_of::<HvIrqSummary>();
+    for irq in 32..irq_num {
+        if header.spi_num as usize == capacity {
+            break;
+        }
+        let enabled = gicd_read(GICD_ISENABLER + irq / 32 * 4) & (1 << (irq % 32)) != 0;
+        let zone_id = find_irq_owner(irq as _);
+        if zone_id.is_none() && !enabled {
+            continue;
+        }
+        let entry = HvSpiSummary {
+            irq: irq as _,
+            zone_id: zone_id.map_or(u32::MAX, |id| id as _),
+            enabled: enabled as _,
+            reserved: 0,
+            route: unsafe {
+                ((host_gicd_base() + GICD_IROUTER + irq * 8) as *const u64).read_volatile()
+            },
+            injections: SPI_INJECTIONS.get().unwrap()[irq].load(Ordering::Relaxed),
+            last_injected: SPI_LAST_INJECTED.get().unwrap()[irq].load(Ordering::Relaxed),
+        };
+        copy_to_guest(&zone.read().gpm, gpa, as_bytes(&entry))?;
+        gpa += size_of::<HvSpiSummary>();
+        header.spi_num += 1;
+    }
+    copy_to_guest(&zone.read().gpm, buf, as_bytes(&header))?;
+    Ok(size_of::<HvIrqSummary>() + header.spi_num as usize * size_of::<HvSpiSummary>())
+}
+
+/// Start of the buffer of `HvInspectZoneIrqs`. The caller sets `size` to the size of the
+/// buffer, the hypervisor fills in the rest and `spi_num` `HvZoneIrqState` entries after it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneIrqsHeader {
+    pub size: u64,
+    pub zone_id: u64,
+    /// Number of entries that follow, fewer than the zone's SPIs if the buffer is too small.
+    pub spi_num: u64,
+}
+
+/// State of one SPI of the inspected zone.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneIrqState {
+    pub irq: u32,
+    pub enabled: u32,
+    /// Pending at the distributor or in the pending queue of one of the zone's cpus.
+    pub pending: u32,
+    /// Active at the distributor or in the active shadow the guest set.
+    pub active: u32,
+}
+
+fn gicd_bit(reg: usize, irq: usize) -> bool {
+    gicd_read(reg + irq / 32 * 4) & (1 << (irq % 32)) != 0
+}
+
+/// Write the state of the SPIs of zone `zone_id` to the root zone's buffer at guest physical
+/// `buf`, see `HvZoneIrqsHeader`. Returns the bytes written.
+///
+/// List registers can only be read on their own cpu, an irq sitting in an LR of a vcpu which
+/// is running shows as neither pending nor active, unless the distributor has it active as a
+/// hardware mapped irq.
+pub fn zone_irqs(zone_id: usize, buf: usize) -> HvResult<usize> {
+    let root = this_zone();
+    let mut header: HvZoneIrqsHeader = unsafe { read_from_guest(&root.read().gpm, buf)? };
+    let size = header.size as usize;
+    if size < size_of::<HvZoneIrqsHeader>() {
+        return hv_result_err!(EINVAL, "zone irq buffer too small");
+    }
+    let Some(zone) = find_zone(zone_id) else {
+        return hv_result_err!(ENOENT, "no such zone");
+    };
+    let zone = zone.read();
+    header.zone_id = zone_id as _;
+    header.spi_num = 0;
+    let capacity = (size - size_of::<HvZoneIrqsHeader>()) / size_of::<HvZoneIrqState>();
+    let mut gpa = buf + size_of::<HvZoneIrqsHeader>();
+    for irq in (32..max_spi()).filter(|&irq| zone.irq_in_zone(irq as _)) {
+        if header.spi_num as usize == capacity {
+            break;
+        }
+        let mut queued = false;
+        for cpu in zone.cpu_set.iter() {
+            pending::for_each(cpu, |pending| queued |= pending.irq_id == irq);
+        }
+        let shadow_active = zone.vgicv3.active_shadow[irq / 32] & (1 << (irq % 32)) != 0;
+        let entry = HvZoneIrqState {
+            irq: irq as _,
+            enabled: gicd_bit(GICD_ISENABLER, irq) as _,
+            pending: (gicd_bit(GICD_ISPENDR, irq) || queued) as _,
+            active: (gicd_bit(GICD_ISACTIVER, irq) || shadow_active) as _,
+        };
+        copy_to_guest(&root.read().gpm, gpa, as_bytes(&entry))?;
+        gpa += size_of::<HvZoneIrqState>();
+        header.spi_num += 1;
+    }
+    copy_to_guest(&root.read().gpm, buf, as_bytes(&header))?;
+    Ok(size_of::<HvZoneIrqsHeader>() + header.spi_num as usize * size_of::<HvZoneIrqState>())
+}
@@ -0,0 +1,86 @@
+//! The machine of a test thread, see `machine_static!`: its cpus share the thread's mocked
+//! registers, the distributor and redistributors of its GIC are memory on the heap, which
+//! keeps what is written to it without any side effects, and its zones run no guest.
+use alloc::sync::Arc;
+use spin::RwLock;
+
+use super::gicd::GICD_TYPER;
+use super::{gic, irq_lock, pending, summary, Gic, GicImpl, PER_GICR_SIZE};
+use crate::arch::aarch64::sysreg::{mock, write_sysreg};
+use crate::arch::mm::setup_parange_for_tests;
+use crate::consts::MAX_CPU_NUM;
+use crate::percpu::get_cpu_data;
+use crate::zone::Zone;
+
+/// List registers of the virtual cpu interfaces.
+pub const LRS: usize = 4;
+const GICD_SIZE: usize = 0x10000;
+
+/// Reset the registers and boot the machine on `cpu`, with all list registers free. The GIC
+/// has 1020 SPIs, 64 extended PPIs and 1024 extended SPIs.
+pub fn boot(cpu: usize) {
+    mock::reset();
+    run_on(cpu);
+    // LRS list registers, 5 preemption and priority bits
+    write_sysreg!(ich_vtr_el2, (LRS - 1) as u64 | 4 << 26 | 4 << 29);
+    write_sysreg!(ich_elrsr_el2, (1 << LRS) - 1);
+    gic().call_once(|| {
+        let gicd_base = heap_mmio(GICD_SIZE);
+        unsafe { ((gicd_base + GICD_TYPER) as *mut u32).write_volatile(31) };
+        Gic {
+            gicd_base,
+            gicr_base: heap_mmio(MAX_CPU_NUM * PER_GICR_SIZE),
+            gicd_size: GICD_SIZE,
+            gicr_size: MAX_CPU_NUM * PER_GICR_SIZE,
+            eppi_num: 64,
+            espi_num: 1024,
+            nmi: false,
+            implementation: GicImpl::Unknown,
+            single_security: false,
+        }
+    });
+    crate::memory::frame::init();
+    setup_parange_for_tests();
+    crate::event::init(MAX_CPU_NUM);
+    crate::work::init(MAX_CPU_NUM);
+    pending::init(MAX_CPU_NUM);
+    irq_lock::init();
+    summary::init(MAX_CPU_NUM);
+    for cpu in 0..MAX_CPU_NUM {
+        get_cpu_data(cpu).gic.init();
+    }
+}
+
+/// Run what follows on `cpu`, with the registers the cpu it ran on before left behind.
+pub fn run_on(cpu: usize) {
+    write_sysreg!(mpidr_el1, cpu as u64);
+}
+
+/// A zone `zone_id` owning `irqs`, SPIs or extended SPIs, with the vcpus on `cpus` on.
+pub fn zone(zone_id: usize, cpus: &[usize], irqs: &[usize]) -> Arc<RwLock<Zone>> {
+    let mut zone = Zone::new(zone_id);
+    for &cpu in cpus {
+        zone.cpu_set.set_bit(cpu);
+    }
+    for &irq in irqs {
+        let (bitmap, irq) = match irq {
+            0..=1023 => (&mut zone.irq_bitmap, irq),
+            _ => (&mut zone.espi_bitmap, irq - 4096),
+        };
+        bitmap[irq / 32] |= 1 << (irq % 32);
+    }
+    let zone = Arc::new(RwLock::new(zone));
+    for &cpu in cpus {
+        let cpu_data = get_cpu_data(cpu);
+        cpu_data.zone = Some(zone.clone());
+        cpu_data.zone_id = Some(zone_id);
+        cpu_data.arch_cpu.psci_on = true;
+    }
+    zone
+}
+
+// Zeroed memory for `size` bytes of GIC registers, 64KB aligned like the real frames.
+fn heap_mmio(size: usize) -> usize {
+    let layout = core::alloc::Layout::from_size_align(size, 0x10000).unwrap();
+    unsafe { alloc::alloc::alloc_zeroed(layout) as usize }
+}
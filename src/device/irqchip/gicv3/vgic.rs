@@ -1,10 +1,180 @@
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
 
-use super::{gicd::GICD_LOCK, host_gicd_size, is_spi};
+use super::{
+    audit::AuditRing,
+    cpu_state::cpu_gic,
+    flush_pending_irqs_on,
+    gicd::GICD_LOCK,
+    host_espi_num, host_gicd_size, host_single_security, irq_kind, is_sgi, is_spi, lr_active_irqs,
+    lr_deactivate_irqs, lr_update_priority_on, replay_enabled_irqs,
+    stats::IrqStats,
+    storm::IrqStorm,
+    vits::{vgicv3_its_handler, Vits, GITS_FRAME_SIZE},
+    watchdog::WatchdogConfig,
+    HcrConfig, IrqGroup, IrqKind, OfflineIrqPolicy, GICV3_EPPI_BASE, GICV3_ESPI_BASE,
+};
 use crate::{
-    arch::zone::HvArchZoneConfig, consts::MAX_CPU_NUM, device::irqchip::gicv3::{gicd::*, gicr::*, host_gicd_base, host_gicr_base, PER_GICR_SIZE}, error::HvResult, memory::{mmio_perform_access, MMIOAccess}, percpu::{get_cpu_data, this_zone}, zone::Zone
+    arch::{
+        cpu::{cpuid_to_vcpuid, mpidr_to_cpuid, this_cpu_id, vcpuid_to_cpuid},
+        ipi::cpuid_to_affinity,
+        zone::HvArchZoneConfig,
+    },
+    consts::MAX_CPU_NUM,
+    device::irqchip::gicv3::{gicd::*, gicr::*, host_gicd_base, host_gicr_base, PER_GICR_SIZE},
+    error::HvResult,
+    memory::{
+        mmio_perform_access, GuestPhysAddr, HostPhysAddr, MMIOAccess, MemFlags, MemoryRegion,
+    },
+    percpu::{get_cpu_data, this_zone, CpuSet},
+    zone::{find_zone, zone_spi_bitmap, Zone},
 };
 
+/// Per-zone state of the virtual GIC.
+#[derive(Debug)]
+pub struct Vgicv3 {
+    /// Max number of virtual irqs a vcpu may hold in its list registers, 0 means no limit.
+    pub irq_depth_limit: usize,
+    /// Active state of the zone's SPIs as last set by the guest through GICD_ISACTIVER,
+    /// one bit per irq like GICD_ISACTIVER itself.
+    pub active_shadow: [u32; 32],
+    /// Whether the guest has set GICD_CTLR.EnableGrp1. Until then its configuration writes are
+    /// applied, but injected irqs stay in the pending queues.
+    pub dist_enabled: bool,
+    /// GICD_CTLR.EnableGrp0 as set by the guest, only with a single security state. Group 0
+    /// injections don't wait for it.
+    pub dist_grp0_enabled: bool,
+    /// Group of the zone's SPIs as configured by the guest through GICD_IGROUPR, the physical
+    /// irqs always stay in group 1.
+    pub igroup_shadow: [u32; 32],
+    /// GICD_ICFGR of the zone's SPIs as configured by the guest, starting off with the
+    /// distributor's configuration when the zone is created.
+    pub icfgr_shadow: [u32; 64],
+    /// GICR_ICFGR1 of every vcpu as last written by the guest, `None` until the first write.
+    pub ppi_cfg: [Option<u32>; MAX_CPU_NUM],
+    /// GICR_IPRIORITYR0..7 of every vcpu, the priorities of its SGIs and PPIs. They are only
+    /// kept here: the hypervisor's own banked irqs keep their physical priority, and guests
+    /// can set SGI priorities even where the hardware has them read-only.
+    pub sgi_ppi_priority: [[u8; 32]; MAX_CPU_NUM],
+    /// Bitmap of the GICD pages mapped read-only into the zone, see `gicd_trap`.
+    pub gicd_read_passthrough: usize,
+    pub storm: Mutex<IrqStorm>,
+    /// Irqs injected without the LR HW bit, one bit per irq.
+    pub hw_unmapped: [u32; 32],
+    pub stats: IrqStats,
+    /// The emulated ITS, only reachable if the zone has `gits_base` set.
+    pub its: Vits,
+    pub watchdog: WatchdogConfig,
+    /// Priority bits the zone's vcpus implement, 0 for all of the hardware's.
+    pub pri_bits: usize,
+    /// See `HvArchZoneConfig::update_pending_priority`.
+    pub update_pending_priority: bool,
+    pub audit: Mutex<AuditRing>,
+    /// Whether the zone keeps an audit ring, checked before taking the lock of `audit` on
+    /// every injection.
+    pub audit_enabled: bool,
+    pub offline_irq_policy: OfflineIrqPolicy,
+    /// ICH_HCR_EL2.TALL0/TALL1 of the zone's vcpus, see `HvArchZoneConfig::trap_group_regs`.
+    pub group_traps: HcrConfig,
+    /// Log every GICD access of the zone, see `set_gicd_trace`.
+    pub gicd_trace: AtomicBool,
+    #[cfg(feature = "gicd_write_combine")]
+    pub write_buffer: super::gicd_wc::GicdWriteBuffer,
+}
+
+impl Default for Vgicv3 {
+    fn default() -> Self {
+        Self {
+            irq_depth_limit: 0,
+            active_shadow: [0; 32],
+            dist_enabled: false,
+            dist_grp0_enabled: false,
+            // group 1 until the guest says otherwise, as it was before the shadow existed
+            igroup_shadow: [u32::MAX; 32],
+            icfgr_shadow: [0; 64],
+            ppi_cfg: [None; MAX_CPU_NUM],
+            sgi_ppi_priority: [[0; 32]; MAX_CPU_NUM],
+            gicd_read_passthrough: 0,
+            storm: Mutex::new(IrqStorm::default()),
+            hw_unmapped: [0; 32],
+            stats: IrqStats::default(),
+            its: Vits::default(),
+            watchdog: WatchdogConfig::default(),
+            pri_bits: 0,
+            update_pending_priority: false,
+            audit: Mutex::new(AuditRing::default()),
+            audit_enabled: false,
+            offline_irq_policy: OfflineIrqPolicy::HoldPending,
+            group_traps: HcrConfig::empty(),
+            gicd_trace: AtomicBool::new(false),
+            #[cfg(feature = "gicd_write_combine")]
+            write_buffer: Default::default(),
+        }
+    }
+}
+
+/// Which accesses of a zone to a part of the GICD trap to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicdTrap {
+    All,
+    WritesOnly,
+}
+
+impl Vgicv3 {
+    /// How the zone's accesses to the GICD register at `offset` are trapped.
+    pub fn gicd_trap(&self, offset: usize) -> GicdTrap {
+        if self.gicd_read_passthrough & (1 << (offset / GICD_PAGE_SIZE)) != 0 {
+            GicdTrap::WritesOnly
+        } else {
+            GicdTrap::All
+        }
+    }
+
+    pub fn irq_group(&self, irq: usize) -> IrqGroup {
+        // extended SPIs aren't shadowed, they always are group 1
+        let is_group1 = irq_kind(irq as _) != IrqKind::Spi
+            || self.igroup_shadow[irq / 32] & (1 << (irq % 32)) != 0;
+        if is_group1 {
+            IrqGroup::Group1
+        } else {
+            IrqGroup::Group0
+        }
+    }
+
+    /// Whether the physical `irq` is linked to its virtual irq through the LR HW bit, so the
+    /// guest's deactivation deactivates it as well.
+    pub fn is_hw_mapped(&self, irq: usize) -> bool {
+        irq >= 1024 || self.hw_unmapped[irq / 32] & (1 << (irq % 32)) == 0
+    }
+
+    /// The priority bits a vcpu of the zone sees on a virtual cpu interface with `hw_bits`.
+    pub fn vpri_bits(&self, hw_bits: usize) -> usize {
+        match self.pri_bits {
+            0 => hw_bits,
+            bits => bits.min(hw_bits),
+        }
+    }
+
+    /// Mask of the implemented bits of a priority, the upper `vpri_bits` ones.
+    pub fn priority_mask(&self, hw_bits: usize) -> u8 {
+        (0xff00u16 >> self.vpri_bits(hw_bits)) as u8
+    }
+
+    /// Whether `irq` is edge-triggered on the vcpu of `cpu`. SGIs always are, PPIs follow the
+    /// guest's GICR_ICFGR1 and fall back to the physical configuration, SPIs count as level.
+    pub fn is_edge_triggered(&self, cpu: usize, irq: usize) -> bool {
+        if is_sgi(irq as _) {
+            return true;
+        }
+        if !(16..32).contains(&irq) {
+            return false;
+        }
+        let cfg = self.ppi_cfg[cpu].unwrap_or_else(|| read_gicr_icfgr1(cpu));
+        cfg & (GICR_ICFGR_EDGE << ((irq - 16) * 2)) != 0
+    }
+}
+
 pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize> {
     base..(base + (n - 1) * size)
 }
@@ -16,21 +186,68 @@ impl Zone {
         let gicd_size = if arch.gicd_size == 0 {host_gicd_size()} else {arch.gicd_size};
 
         self.mmio_region_register(gicd_base, gicd_size, vgicv3_dist_handler, 0);
+        self.gicd_passthrough_init(gicd_base, gicd_size, arch.gicd_read_passthrough);
         for cpu in 0..MAX_CPU_NUM {
             let gicr_base = gicr_base + cpu * PER_GICR_SIZE;
             debug!("registering gicr {} at {:#x?}", cpu, gicr_base);
             self.mmio_region_register(gicr_base, PER_GICR_SIZE, vgicv3_redist_handler, cpu);
         }
+        if arch.gits_base != 0 {
+            self.mmio_region_register(arch.gits_base, GITS_FRAME_SIZE, vgicv3_its_handler, 0);
+        }
+    }
+
+    // Map the requested GICD pages read-only, writes to them still end up in vgicv3_dist_handler.
+    fn gicd_passthrough_init(&mut self, gicd_base: usize, gicd_size: usize, pages: usize) {
+        let page_num = gicd_size / GICD_PAGE_SIZE;
+        let valid = match page_num {
+            n if n >= usize::BITS as usize => usize::MAX,
+            n => (1 << n) - 1,
+        };
+        let allowed = pages & valid & !GICD_READ_EMULATED_PAGES;
+        if allowed != pages {
+            warn!(
+                "zone {}: gicd pages {:#x} can't be read directly, trapping them",
+                self.id,
+                pages & !allowed
+            );
+        }
+        for page in (0..page_num).filter(|page| allowed & (1 << page) != 0) {
+            let offset = page * GICD_PAGE_SIZE;
+            self.gpm
+                .insert(MemoryRegion::new_with_offset_mapper(
+                    (gicd_base + offset) as GuestPhysAddr,
+                    (host_gicd_base() + offset) as HostPhysAddr,
+                    GICD_PAGE_SIZE,
+                    MemFlags::READ | MemFlags::IO,
+                ))
+                .unwrap();
+        }
+        self.vgicv3.gicd_read_passthrough = allowed;
+        debug!("zone {}: gicd read pass-through pages {:#x}", self.id, allowed);
+    }
+
+    /// Take the trigger configuration of the zone's SPIs from the distributor into the ICFGR
+    /// shadow, after `irq_bitmap_init`.
+    pub fn icfgr_shadow_init(&mut self) {
+        let gicd_base = host_gicd_base();
+        for reg_index in 2..self.vgicv3.icfgr_shadow.len() {
+            let addr = gicd_base + GICD_ICFGR + reg_index * 4;
+            let icfgr = unsafe { (addr as *const u32).read_volatile() };
+            self.vgicv3.icfgr_shadow[reg_index] = icfgr & zone_icfgr_mask(self, reg_index);
+        }
     }
 
     pub fn irq_bitmap_init(&mut self, irqs: &[u32]) {
         for irq in irqs {
             self.insert_irq_to_bitmap(*irq);
         }
-        for (index, &word) in self.irq_bitmap.iter().enumerate() {
+        let espi_words = self.espi_bitmap.iter().map(|&word| (GICV3_ESPI_BASE as usize, word));
+        let words = self.irq_bitmap.iter().map(|&word| (0, word)).chain(espi_words);
+        for (index, (base, word)) in words.enumerate() {
             for bit_position in 0..32 {
                 if word & (1 << bit_position) != 0 {
-                    let interrupt_number = index * 32 + bit_position;
+                    let interrupt_number = base + index % 32 * 32 + bit_position;
                     info!(
                         "Found interrupt in Zone {} irq_bitmap: {}",
                         self.id, interrupt_number
@@ -41,6 +258,13 @@ impl Zone {
     }
 
     fn insert_irq_to_bitmap(&mut self, irq: u32) {
+        if irq >= GICV3_ESPI_BASE {
+            // GICv3.1 supports up to 1024 extended SPIs
+            assert!(irq < GICV3_ESPI_BASE + 1024);
+            let irq = irq - GICV3_ESPI_BASE;
+            self.espi_bitmap[(irq / 32) as usize] |= 1 << (irq % 32);
+            return;
+        }
         assert!(irq < 1024); // 1024 is the maximum number of interrupts supported by GICv3 (GICD_TYPER.ITLinesNumber)
         let irq_index = irq / 32;
         let irq_bit = irq % 32;
@@ -66,9 +290,10 @@ fn restrict_bitmask_access(
     let irq_bits = (1 << bits_per_irq) - 1;
     /* First, extract the first interrupt affected by this access */
     let first_irq = reg_index * irqs_per_reg;
+    let owned = zone_irq_mask(&zone_r, first_irq & !31) >> (first_irq % 32);
 
     for irq in 0..irqs_per_reg {
-        if zone_r.irq_in_zone((first_irq + irq) as _) {
+        if owned & (1 << irq) != 0 {
             trace!("restrict visit irq {}", first_irq + irq);
             access_mask |= irq_bits << (irq * bits_per_irq);
         }
@@ -107,15 +332,21 @@ fn restrict_bitmask_access(
     Ok(())
 }
 
-pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
-    trace!("gicr({}) mmio = {:#x?}", cpu, mmio);
+// Frames are numbered by vcpu id, frame `frame` is the redistributor of the cpu running that
+// vcpu of the zone. Frames past the zone's vcpus are RAZ/WI.
+pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, frame: usize) -> HvResult {
+    trace!("gicr({}) mmio = {:#x?}", frame, mmio);
+    let cpu_set = this_zone().read().cpu_set;
+    let Some(cpu) = vcpuid_to_cpuid(&cpu_set, frame) else {
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
+        return HvResult::Ok(());
+    };
     let gicr_base = host_gicr_base(cpu);
     match mmio.address {
-        GICR_TYPER => {
-            mmio_perform_access(gicr_base, mmio);
-            if cpu == MAX_CPU_NUM - 1 {
-                mmio.value |= GICR_TYPER_LAST;
-            }
+        GICR_TYPER | 0x000c => {
+            vgicv3_redist_typer_access(mmio, frame, &cpu_set, gicr_base);
         }
         GICR_IIDR | 0xffd0..=0xfffc => {
             // Read-only registers that might be used by a zone to find the redistributor corresponding to a CPU. Keep them accessible.
@@ -124,6 +355,44 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
         GICR_SYNCR => {
             mmio.value = 0;
         }
+        GICR_CTLR => {
+            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+                // RWP tracks the zone's own ICENABLER writes to this redistributor
+                mmio_perform_access(gicr_base, mmio);
+            } else if !mmio.is_write {
+                // nothing of the zone is in progress at a foreign redistributor
+                mmio.value = 0;
+            }
+        }
+        reg if reg == GICR_SGI_BASE + GICR_ICFGR => {
+            // SGIs are always edge-triggered, their configuration is read-only
+            if !mmio.is_write {
+                mmio.value = GICR_ICFGR0_SGI_EDGE;
+            }
+        }
+        reg if reg == GICR_SGI_BASE + GICR_ICFGR + 4 => {
+            vgicv3_redist_icfgr1_access(mmio, cpu, gicr_base);
+        }
+        reg if (GICR_SGI_BASE + GICR_IPRIORITYR..GICR_SGI_BASE + GICR_IPRIORITYR + 32)
+            .contains(&reg) =>
+        {
+            vgicv3_redist_priority_access(mmio, cpu);
+        }
+        reg if mmio.is_write
+            && (reg == GICR_SGI_BASE + GICR_ISENABLER
+                || reg == GICR_SGI_BASE + GICR_ISENABLER + 4) =>
+        {
+            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+                mmio_perform_access(gicr_base, mmio);
+                // ISENABLER0 covers the SGIs and PPIs, ISENABLER<n>E follows for the EPPIs
+                let first_irq = if reg == GICR_SGI_BASE + GICR_ISENABLER {
+                    0
+                } else {
+                    GICV3_EPPI_BASE as usize
+                };
+                replay_enabled_irqs(cpu, first_irq, mmio.value as u32);
+            }
+        }
         _ => {
             if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
                 // ignore access to foreign redistributors
@@ -136,6 +405,90 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
     HvResult::Ok(())
 }
 
+// Affinity and Processor_Number are the ones of vcpu `vcpu_id`, like the MPIDR the guest
+// sees. Last is set on the frame of the zone's last vcpu so the guest stops there, and there
+// are no LPIs.
+fn vgicv3_redist_typer_access(
+    mmio: &mut MMIOAccess,
+    vcpu_id: usize,
+    cpu_set: &CpuSet,
+    gicr_base: usize,
+) {
+    if mmio.is_write {
+        // read-only
+        return;
+    }
+    let mut typer = unsafe { ((gicr_base + GICR_TYPER) as *const u64).read_volatile() } as usize;
+    let (aff3, aff2, aff1, aff0) = cpuid_to_affinity(vcpu_id);
+    let affinity = (aff3 << 24 | aff2 << 16 | aff1 << 8 | aff0) as usize;
+    typer &= !(GICR_TYPER_LPI_MASK | GICR_TYPER_LAST)
+        & !(GICR_TYPER_AFFINITY_MASK | GICR_TYPER_PROC_NUM_MASK);
+    typer |= affinity << GICR_TYPER_AFFINITY_SHIFT | vcpu_id << GICR_TYPER_PROC_NUM_SHIFT;
+    if vcpu_id + 1 == cpu_set.iter().count() {
+        typer |= GICR_TYPER_LAST;
+    }
+    mmio.value = match (mmio.address, mmio.size) {
+        (GICR_TYPER, 8) => typer,
+        (GICR_TYPER, _) => typer & 0xffff_ffff,
+        _ => typer >> 32,
+    };
+}
+
+fn vgicv3_redist_icfgr1_access(mmio: &mut MMIOAccess, cpu: usize, gicr_base: usize) {
+    let zone = this_zone();
+    if !Arc::ptr_eq(&zone, get_cpu_data(cpu).zone.as_ref().unwrap()) {
+        trace!("*** gicv3_gicr_mmio_handler: ignore access to foreign redistributors ***");
+        return;
+    }
+    if mmio.is_write {
+        zone.write().vgicv3.ppi_cfg[cpu] = Some(mmio.value as u32);
+        // the physical PPIs keep getting the guest's configuration where they support it
+        mmio_perform_access(gicr_base, mmio);
+    } else {
+        match zone.read().vgicv3.ppi_cfg[cpu] {
+            Some(cfg) => mmio.value = cfg as usize,
+            None => mmio_perform_access(gicr_base, mmio),
+        }
+    }
+}
+
+// Byte or word accesses to the priority shadow of `cpu`.
+fn vgicv3_redist_priority_access(mmio: &mut MMIOAccess, cpu: usize) {
+    let zone = this_zone();
+    if !Arc::ptr_eq(&zone, get_cpu_data(cpu).zone.as_ref().unwrap()) {
+        trace!("*** gicv3_gicr_mmio_handler: ignore access to foreign redistributors ***");
+        return;
+    }
+    let first = mmio.address - GICR_SGI_BASE - GICR_IPRIORITYR;
+    let irqs = first..(first + mmio.size).min(32);
+    if mmio.is_write {
+        let mut zone_w = zone.write();
+        // unimplemented priority bits read as zero, as they would in hardware
+        let mask = zone_w.vgicv3.priority_mask(cpu_gic(cpu).vtr.priority_bits);
+        let priority = &mut zone_w.vgicv3.sgi_ppi_priority[cpu];
+        for (i, irq) in irqs.enumerate() {
+            priority[irq] = (mmio.value >> (8 * i)) as u8 & mask;
+        }
+    } else {
+        let priority = &zone.read().vgicv3.sgi_ppi_priority[cpu];
+        mmio.value = irqs
+            .enumerate()
+            .fold(0, |value, (i, irq)| value | (priority[irq] as usize) << (8 * i));
+    }
+}
+
+/// Priority the guest gave `irq_id` on `cpu`, put into its list register on injection. SPIs
+/// have theirs in the distributor, where the guest's writes go through.
+pub fn irq_priority(zone: &Zone, cpu: usize, irq_id: usize) -> u8 {
+    let reg = match irq_kind(irq_id as _) {
+        IrqKind::Sgi | IrqKind::Ppi => return zone.vgicv3.sgi_ppi_priority[cpu][irq_id],
+        IrqKind::Spi => GICD_IPRIORITYR + irq_id,
+        IrqKind::ExtSpi => GICD_IPRIORITYRNE + irq_id - GICV3_ESPI_BASE as usize,
+        _ => return 0,
+    };
+    unsafe { ((host_gicd_base() + reg) as *const u8).read_volatile() }
+}
+
 // The return value should be the register value to be read.
 fn vgicv3_handle_irq_ops(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
     let zone = this_zone();
@@ -149,18 +502,302 @@ fn vgicv3_handle_irq_ops(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
         return Ok(());
     }
 
+    let is_irouter = mmio.address & !7 == irouter_offset(irq);
+    if is_irouter && mmio.address & 4 == 0 && mmio.is_write {
+        mmio.value = irouter_to_physical(mmio.value, &zone_r.cpu_set);
+    }
     mmio_perform_access(host_gicd_base(), mmio);
+    if is_irouter && mmio.address & 4 == 0 && !mmio.is_write {
+        mmio.value = irouter_to_virtual(mmio.value, &zone_r.cpu_set);
+    }
+
+    Ok(())
+}
+
+fn irouter_offset(irq: u32) -> usize {
+    match irq_kind(irq) {
+        IrqKind::ExtSpi => GICD_IROUTERNE + (irq - GICV3_ESPI_BASE) as usize * 8,
+        _ => GICD_IROUTER + irq as usize * 8,
+    }
+}
+
+// The guest routes SPIs to the affinity of a vcpu, the distributor needs the one of its
+// physical cpu. Routes to affinities without a vcpu go to the zone's first cpu. Only the low
+// word is translated, Aff3 is left as written.
+fn irouter_to_physical(irouter: usize, cpu_set: &CpuSet) -> usize {
+    if irouter as u64 & GICD_IROUTER_IRM != 0 {
+        return irouter;
+    }
+    let vcpu_id = mpidr_to_cpuid(irouter as u64 & 0xff_ffff) as usize;
+    let cpu = vcpuid_to_cpuid(cpu_set, vcpu_id)
+        .or_else(|| cpu_set.first_cpu())
+        .unwrap_or(0);
+    irouter & !0xff_ffff | cpu & 0xff_ffff
+}
+
+fn irouter_to_virtual(irouter: usize, cpu_set: &CpuSet) -> usize {
+    if irouter as u64 & GICD_IROUTER_IRM != 0 {
+        return irouter;
+    }
+    let cpu = mpidr_to_cpuid(irouter as u64 & 0xff_ffff) as usize;
+    match cpuid_to_vcpuid(cpu_set, cpu) {
+        Some(vcpu_id) => irouter & !0xff_ffff | vcpu_id & 0xff_ffff,
+        None => irouter,
+    }
+}
+
+// Active state of the zone's irqs is the physical one (hardware mapped irqs), merged with the
+// irqs active in this cpu's list registers and the shadow kept for guest save/restore.
+fn vgicv3_dist_active_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let reg = mmio.address;
+    let reg_index = (reg & 0x7f) / 4;
+    let first_irq = reg_index * 32;
+    let is_set = reg_range(GICD_ISACTIVER, 32, 4).contains(&reg);
+    let write_val = mmio.value as u32;
+
+    restrict_bitmask_access(mmio, reg_index, 1, true, gicd_base)?;
+    if reg_index == 0 {
+        // SGIs and PPIs are banked in the redistributors
+        return Ok(());
+    }
 
+    let zone = this_zone();
+    let mut zone_w = zone.write();
+    let access_mask = zone_irq_mask(&zone_w, first_irq);
+
+    let shadow = &mut zone_w.vgicv3.active_shadow[reg_index];
+    if !mmio.is_write {
+        mmio.value |= ((*shadow | lr_active_irqs(first_irq)) & access_mask) as usize;
+    } else if is_set {
+        *shadow |= write_val & access_mask;
+    } else {
+        *shadow &= !(write_val & access_mask);
+        // deactivating takes the SPI locks, which come after the zone's lock
+        drop(zone_w);
+        lr_deactivate_irqs(first_irq, write_val & access_mask);
+    }
+    Ok(())
+}
+
+// A guest changing the enable state of an SPI the storm breaker masked takes it over.
+fn vgicv3_dist_enable_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let reg_index = (mmio.address & 0x7f) / 4;
+    let write_val = mmio.value as u32;
+    restrict_bitmask_access(mmio, reg_index, 1, true, gicd_base)?;
+    if mmio.is_write {
+        let zone = this_zone();
+        let zone_r = zone.read();
+        let mask = write_val & zone_irq_mask(&zone_r, reg_index * 32);
+        zone_r.vgicv3.storm.lock().forget(reg_index * 32, mask);
+        drop(zone_r);
+        if reg_range(GICD_ISENABLER, 32, 4).contains(&mmio.address) {
+            replay_enabled_spis(reg_index * 32, mask);
+        }
+    }
+    Ok(())
+}
+
+// Inject the irqs of `mask`, relative to `first_irq`, which the zone's vcpus held while the
+// guest had them disabled and it just enabled.
+fn replay_enabled_spis(first_irq: usize, mask: u32) {
+    let cpu_set = this_zone().read().cpu_set;
+    for cpu in cpu_set.iter() {
+        replay_enabled_irqs(cpu, first_irq, mask);
+    }
+}
+
+// One bit for each of the 32 irqs starting at `first_irq`, a multiple of 32, that belongs to
+// `zone`: the word of its bitmaps holding them.
+fn zone_irq_mask(zone: &Zone, first_irq: usize) -> u32 {
+    debug_assert_eq!(first_irq % 32, 0);
+    match first_irq {
+        0..=1023 => zone_spi_bitmap(zone)[first_irq / 32],
+        4096..=5119 => zone.espi_bitmap[(first_irq - 4096) / 32],
+        _ => 0,
+    }
+}
+
+// The edge bit of each 2-bit ICFGR field of the irqs in register `reg_index` that belong to
+// `zone`, the low bit of the fields is RES0 for SPIs.
+fn zone_icfgr_mask(zone: &Zone, reg_index: usize) -> u32 {
+    let owned = zone_irq_mask(zone, reg_index / 2 * 32) >> (reg_index % 2 * 16);
+    (0..16)
+        .filter(|irq| owned & (1 << irq) != 0)
+        .fold(0, |mask, irq| mask | GICR_ICFGR_EDGE << (irq * 2))
+}
+
+// The guest reads back the trigger configuration it wrote from the shadow, not the physical
+// one, and other zones' irqs read as 0. GICD_ICFGR0 and 1 are RAZ/WI, with affinity routing
+// the SGIs and PPIs are configured in the redistributors.
+fn vgicv3_dist_icfgr_access(mmio: &mut MMIOAccess, reg_index: usize, gicd_base: usize) -> HvResult {
+    if reg_index < 2 {
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
+        return Ok(());
+    }
+    let zone = this_zone();
+    let mask = zone_icfgr_mask(&zone.read(), reg_index);
+    if !mmio.is_write {
+        mmio.value = (zone.read().vgicv3.icfgr_shadow[reg_index] & mask) as usize;
+        return Ok(());
+    }
+    let value = mmio.value as u32 & mask;
+    mmio.value = value as usize;
+    restrict_bitmask_access(mmio, reg_index, 2, false, gicd_base)?;
+    let shadow = &mut zone.write().vgicv3.icfgr_shadow[reg_index];
+    *shadow = (*shadow & !mask) | value;
+    Ok(())
+}
+
+// Group membership of the zone's SPIs only lives in the shadow, it selects the LR group bit.
+fn vgicv3_dist_group_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let reg_index = (mmio.address & 0x7f) / 4;
+    if reg_index == 0 {
+        // SGIs and PPIs are banked in the redistributors
+        return restrict_bitmask_access(mmio, reg_index, 1, false, gicd_base);
+    }
+    let first_irq = reg_index * 32;
+
+    let zone = this_zone();
+    let mut zone_w = zone.write();
+    let access_mask = zone_irq_mask(&zone_w, first_irq);
+
+    let shadow = &mut zone_w.vgicv3.igroup_shadow[reg_index];
+    if mmio.is_write {
+        *shadow = (*shadow & !access_mask) | (mmio.value as u32 & access_mask);
+    } else {
+        mmio.value = (*shadow & access_mask) as usize;
+    }
+    Ok(())
+}
+
+// The physical distributor is always enabled, only the guest's view of the group enable is kept.
+// The group 1 enable is bit 1 in both layouts of GICD_CTLR. With a single security state
+// (DS) bit 0 enables group 0 as well, with two it is RES0 for the non-secure world.
+fn vgicv3_dist_ctlr_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let zone = this_zone();
+    let grp0_mask = if host_single_security() {
+        GICD_CTLR_GRP0_ENA
+    } else {
+        0
+    };
+    if mmio.is_write {
+        let enable = mmio.value & GICD_CTLR_GRP1NS_ENA != 0;
+        zone.write().vgicv3.dist_grp0_enabled = mmio.value & grp0_mask != 0;
+        let was_enabled = core::mem::replace(&mut zone.write().vgicv3.dist_enabled, enable);
+        if enable != was_enabled {
+            // replays the queued irqs, or parks the pending ones of the list registers
+            let cpu_set = zone.read().cpu_set;
+            flush_pending_irqs_on(&cpu_set);
+        }
+        #[cfg(feature = "gicd_write_combine")]
+        if enable && !was_enabled {
+            super::gicd_wc::report();
+        }
+    } else {
+        mmio_perform_access(gicd_base, mmio);
+        mmio.value &= !(GICD_CTLR_GRP1NS_ENA | GICD_CTLR_GRP0_ENA);
+        let zone_r = zone.read();
+        if zone_r.vgicv3.dist_enabled {
+            mmio.value |= GICD_CTLR_GRP1NS_ENA;
+        }
+        if zone_r.vgicv3.dist_grp0_enabled {
+            mmio.value |= grp0_mask;
+        }
+        drop(zone_r);
+        // RWP is the hardware's, and set as long as the zone has writes buffered
+        #[cfg(feature = "gicd_write_combine")]
+        if super::gicd_wc::has_buffered_writes() {
+            mmio.value |= GICD_CTLR_RWP;
+            super::gicd_wc::flush();
+        }
+    }
+    Ok(())
+}
+
+// The guest only sees as many irq lines as needed for its highest SPI and as many cpus as the
+// zone has, the other fields are the hardware's.
+fn vgicv3_dist_typer_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    if mmio.is_write {
+        // read-only
+        return Ok(());
+    }
+    mmio_perform_access(gicd_base, mmio);
+
+    let zone = this_zone();
+    let zone_r = zone.read();
+    // ITLinesNumber N means 32 * (N + 1) irqs
+    let it_lines = zone_spi_bitmap(&zone_r)
+        .iter()
+        .rposition(|&word| word != 0)
+        .unwrap_or(0);
+    // CPUNumber only has room for 8 cpus
+    let cpu_num = zone_r.cpu_set.iter().count().clamp(1, 8);
+
+    mmio.value &= !(GICD_TYPER_ITLINES_MASK | GICD_TYPER_CPUNUM_MASK);
+    mmio.value |= it_lines & GICD_TYPER_ITLINES_MASK;
+    mmio.value |= ((cpu_num - 1) << GICD_TYPER_CPUNUM_SHIFT) & GICD_TYPER_CPUNUM_MASK;
+    Ok(())
+}
+
+// One byte per irq, the guest may only touch bytes of its own SPIs and target its own cpus.
+fn vgicv3_dist_itargets_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let first_irq = mmio.address - GICD_ITARGETSR;
+    let zone = this_zone();
+    let zone_r = zone.read();
+    // ITARGETSR can only name the first 8 cpus
+    let cpu_mask = (zone_r.cpu_set.bitmap & 0xff) as usize;
+
+    let mut access_mask: usize = 0;
+    for byte in 0..mmio.size {
+        let irq = first_irq + byte;
+        if is_spi(irq as _) && zone_r.irq_in_zone(irq as _) {
+            access_mask |= 0xff << (byte * 8);
+        }
+    }
+    drop(zone_r);
+
+    if !mmio.is_write {
+        mmio_perform_access(gicd_base, mmio);
+        mmio.value &= access_mask;
+        return Ok(());
+    }
+
+    let mut targets = mmio.value & access_mask;
+    for byte in 0..mmio.size {
+        let shift = byte * 8;
+        let byte_targets = (targets >> shift) & 0xff;
+        if byte_targets & !cpu_mask != 0 {
+            debug!(
+                "gicd-mmio: irq {} targets {:#x} outside the zone",
+                first_irq + byte,
+                byte_targets
+            );
+        }
+        targets &= !((byte_targets & !cpu_mask) << shift);
+    }
+
+    let _lock = GICD_LOCK.lock();
+    mmio.is_write = false;
+    mmio_perform_access(gicd_base, mmio);
+    mmio.is_write = true;
+    mmio.value = (mmio.value & !access_mask) | targets;
+    mmio_perform_access(gicd_base, mmio);
     Ok(())
 }
 
 fn vgicv3_dist_misc_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
     let reg = mmio.address;
+    if reg == GICD_CTLR {
+        return vgicv3_dist_ctlr_access(mmio, gicd_base);
+    }
+    if reg == GICD_TYPER {
+        return vgicv3_dist_typer_access(mmio, gicd_base);
+    }
     if reg_range(GICDV3_PIDR0, 4, 4).contains(&reg)
         || reg_range(GICDV3_PIDR4, 4, 4).contains(&reg)
         || reg_range(GICDV3_CIDR0, 4, 4).contains(&reg)
-        || reg == GICD_CTLR
-        || reg == GICD_TYPER
         || reg == GICD_IIDR
     {
         if !mmio.is_write {
@@ -174,8 +811,83 @@ fn vgicv3_dist_misc_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult
     Ok(())
 }
 
+/// Turn the logging of all GICD accesses of zone `zone_id` on or off, to follow the GIC setup
+/// of an unfamiliar guest. Reads of pages mapped through `gicd_read_passthrough` never trap
+/// and aren't logged.
+pub fn set_gicd_trace(zone_id: usize, enable: bool) -> HvResult {
+    let Some(zone) = find_zone(zone_id) else {
+        return hv_result_err!(ENOENT, format!("no zone {}", zone_id));
+    };
+    zone.read().vgicv3.gicd_trace.store(enable, Ordering::Relaxed);
+    info!("zone {}: gicd trace {}", zone_id, if enable { "on" } else { "off" });
+    Ok(())
+}
+
+// One line for each traced access, writes before they are handled and reads with the value
+// the guest gets.
+fn trace_gicd_access(mmio: &MMIOAccess) {
+    let (name, index) = gicd_reg_name(mmio.address).unwrap_or(("reserved", 0));
+    info!(
+        "gicd trace: cpu {} {} {}[{}] (+{:#x}) size {} value {:#x}",
+        this_cpu_id(),
+        if mmio.is_write { "write" } else { "read" },
+        name,
+        index,
+        mmio.address,
+        mmio.size,
+        mmio.value
+    );
+}
+
+// Accesses not aligned to their size may straddle two registers, which the architecture
+// leaves UNPREDICTABLE. Decoding them by their first byte would hit the wrong register, so they
+// read as zero and writes are ignored. Returns whether `mmio` was such an access.
+fn misaligned_raz_wi(mmio: &mut MMIOAccess) -> bool {
+    if mmio.address & (mmio.size - 1) == 0 {
+        return false;
+    }
+    warn!(
+        "gicd: misaligned {}-byte {} at {:#x}, RAZ/WI",
+        mmio.size,
+        if mmio.is_write { "write" } else { "read" },
+        mmio.address
+    );
+    if !mmio.is_write {
+        mmio.value = 0;
+    }
+    true
+}
+
 pub fn vgicv3_dist_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
     trace!("gicd mmio = {:#x?}", mmio);
+    let traced = this_zone().read().vgicv3.gicd_trace.load(Ordering::Relaxed);
+    if traced && mmio.is_write {
+        trace_gicd_access(mmio);
+    }
+    let ret = vgicv3_dist_dispatch(mmio);
+    if traced && !mmio.is_write {
+        trace_gicd_access(mmio);
+    }
+    ret
+}
+
+fn vgicv3_dist_dispatch(mmio: &mut MMIOAccess) -> HvResult {
+    if misaligned_raz_wi(mmio) {
+        return Ok(());
+    }
+    #[cfg(feature = "gicd_write_combine")]
+    {
+        if super::gicd_wc::buffer_write(mmio) {
+            return Ok(());
+        }
+        if mmio.address != GICD_CTLR || mmio.is_write {
+            super::gicd_wc::flush();
+        }
+    }
+    vgicv3_dist_access(mmio)
+}
+
+pub(super) fn vgicv3_dist_access(mmio: &mut MMIOAccess) -> HvResult {
     let gicd_base = host_gicd_base();
     let reg = mmio.address;
 
@@ -184,26 +896,239 @@ pub fn vgicv3_dist_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
             vgicv3_handle_irq_ops(mmio, (reg - GICD_IROUTER) as u32 / 8)
         }
         reg if reg_range(GICD_ITARGETSR, 1024, 1).contains(&reg) => {
-            vgicv3_handle_irq_ops(mmio, (reg - GICD_ITARGETSR) as u32)
+            vgicv3_dist_itargets_access(mmio, gicd_base)
         }
         reg if reg_range(GICD_ICENABLER, 32, 4).contains(&reg)
-            || reg_range(GICD_ISENABLER, 32, 4).contains(&reg)
-            || reg_range(GICD_ICPENDR, 32, 4).contains(&reg)
-            || reg_range(GICD_ISPENDR, 32, 4).contains(&reg)
-            || reg_range(GICD_ICACTIVER, 32, 4).contains(&reg)
-            || reg_range(GICD_ISACTIVER, 32, 4).contains(&reg) =>
+            || reg_range(GICD_ISENABLER, 32, 4).contains(&reg) =>
+        {
+            vgicv3_dist_enable_access(mmio, gicd_base)
+        }
+        reg if reg_range(GICD_ICPENDR, 32, 4).contains(&reg)
+            || reg_range(GICD_ISPENDR, 32, 4).contains(&reg) =>
         {
             restrict_bitmask_access(mmio, (reg & 0x7f) / 4, 1, true, gicd_base)
         }
+        reg if reg_range(GICD_ICACTIVER, 32, 4).contains(&reg)
+            || reg_range(GICD_ISACTIVER, 32, 4).contains(&reg) =>
+        {
+            vgicv3_dist_active_access(mmio, gicd_base)
+        }
         reg if reg_range(GICD_IGROUPR, 32, 4).contains(&reg) => {
-            restrict_bitmask_access(mmio, (reg & 0x7f) / 4, 1, false, gicd_base)
+            vgicv3_dist_group_access(mmio, gicd_base)
         }
         reg if reg_range(GICD_ICFGR, 64, 4).contains(&reg) => {
-            restrict_bitmask_access(mmio, (reg & 0xff) / 4, 2, false, gicd_base)
+            vgicv3_dist_icfgr_access(mmio, (reg & 0xff) / 4, gicd_base)
         }
         reg if reg_range(GICD_IPRIORITYR, 255, 4).contains(&reg) => {
-            restrict_bitmask_access(mmio, (reg & 0x3ff) / 4, 8, false, gicd_base)
+            vgicv3_dist_priority_access(mmio, (reg & 0x3ff) / 4, gicd_base)
+        }
+        reg if (GICD_IGROUPRNE..GICD_NSACRNE + 0x100).contains(&reg)
+            || (GICD_IROUTERNE..GICD_IROUTERNE + 1024 * 8).contains(&reg) =>
+        {
+            vgicv3_dist_espi_access(mmio, gicd_base)
         }
         _ => vgicv3_dist_misc_access(mmio, gicd_base),
     }
 }
+
+// One byte per irq in the 4 irqs of register `reg_index`. Pending LRs of the irqs keep their
+// old priority, unless the zone asked for them to follow.
+fn vgicv3_dist_priority_access(
+    mmio: &mut MMIOAccess,
+    reg_index: usize,
+    gicd_base: usize,
+) -> HvResult {
+    restrict_bitmask_access(mmio, reg_index, 8, false, gicd_base)?;
+    if !mmio.is_write {
+        return Ok(());
+    }
+    let zone = this_zone();
+    let zone_r = zone.read();
+    if !zone_r.vgicv3.update_pending_priority {
+        return Ok(());
+    }
+    let cpu_set = zone_r.cpu_set;
+    let mut irqs = [None; 4];
+    for (slot, irq) in irqs.iter_mut().zip(reg_index * 4..reg_index * 4 + 4) {
+        *slot = zone_r.irq_in_zone(irq as _).then_some(irq);
+    }
+    drop(zone_r);
+    for irq in irqs.into_iter().flatten() {
+        lr_update_priority_on(&cpu_set, irq);
+    }
+    Ok(())
+}
+
+// Extended SPI registers get the same ownership checks as the ones of the SPIs, without the
+// shadows: the zone sees the physical state and its extended SPIs always are group 1. They are
+// reserved, RAZ/WI, if the distributor has no extended SPIs.
+fn vgicv3_dist_espi_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
+    let reg = mmio.address;
+    let espi_num = host_espi_num();
+    // restrict_bitmask_access takes its first irq from the register index
+    let espi_reg = |block: usize, bits_per_irq: usize| {
+        let irqs_per_reg = 32 / bits_per_irq;
+        ((reg - block) / 4 < espi_num / irqs_per_reg)
+            .then_some(GICV3_ESPI_BASE as usize / irqs_per_reg + (reg - block) / 4)
+    };
+    let ignore = |mmio: &mut MMIOAccess| {
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
+        Ok(())
+    };
+    match reg {
+        reg if reg >= GICD_IROUTERNE => {
+            let espi = (reg - GICD_IROUTERNE) / 8;
+            if espi >= espi_num {
+                return ignore(mmio);
+            }
+            vgicv3_handle_irq_ops(mmio, GICV3_ESPI_BASE + espi as u32)
+        }
+        reg if reg >= GICD_IGRPMODRNE => ignore(mmio),
+        reg if reg >= GICD_ICFGRNE => match espi_reg(GICD_ICFGRNE, 2) {
+            Some(index) => restrict_bitmask_access(mmio, index, 2, false, gicd_base),
+            None => ignore(mmio),
+        },
+        reg if reg >= GICD_IPRIORITYRNE => match espi_reg(GICD_IPRIORITYRNE, 8) {
+            Some(index) => vgicv3_dist_priority_access(mmio, index, gicd_base),
+            None => ignore(mmio),
+        },
+        reg if reg < GICD_ISENABLERNE => {
+            // IGROUPRnE, group 1 for the zone's irqs
+            let Some(index) = espi_reg(GICD_IGROUPRNE, 1) else {
+                return ignore(mmio);
+            };
+            if !mmio.is_write {
+                let zone = this_zone();
+                mmio.value = zone_irq_mask(&zone.read(), index * 32) as usize;
+            }
+            Ok(())
+        }
+        _ => {
+            // the set/clear-enable, -pending and -active blocks are 0x200 apart
+            let block = GICD_ISENABLERNE + (reg - GICD_ISENABLERNE) / 0x200 * 0x200;
+            if block > GICD_ICACTIVERNE {
+                return ignore(mmio);
+            }
+            let Some(index) = espi_reg(block, 1) else {
+                return ignore(mmio);
+            };
+            let write_val = mmio.value as u32;
+            restrict_bitmask_access(mmio, index, 1, true, gicd_base)?;
+            if mmio.is_write && block == GICD_ISENABLERNE {
+                // the index counts the registers from irq 0
+                let first_irq = index * 32;
+                let mask = write_val & zone_irq_mask(&this_zone().read(), first_irq);
+                replay_enabled_spis(first_irq, mask);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{inject_irq, read_lr, testing, LR_GROUP1, LR_HW, LR_STATE_PENDING};
+    use super::*;
+
+    // A misaligned read gets 0 instead of bytes of two registers, a misaligned write reaches no
+    // register. Aligned accesses of any size go on to the register emulation.
+    #[test]
+    fn misaligned_access_is_raz_wi() {
+        let access = |address, size, is_write| MMIOAccess {
+            address,
+            size,
+            is_write,
+            value: 0xdead,
+        };
+        let mut read = access(GICD_IPRIORITYR + 2, 4, false);
+        assert!(misaligned_raz_wi(&mut read));
+        assert_eq!(read.value, 0);
+        let mut write = access(GICD_IPRIORITYR + 1, 2, true);
+        assert!(misaligned_raz_wi(&mut write));
+
+        for (address, size) in [(GICD_IPRIORITYR + 1, 1), (GICD_IPRIORITYR + 4, 4), (0, 8)] {
+            let mut aligned = access(address, size, false);
+            assert!(!misaligned_raz_wi(&mut aligned));
+            assert_eq!(aligned.value, 0xdead);
+        }
+    }
+
+    // A guest setting up its SPI before it sets GICD_CTLR.EnableGrp1 gets the irq raised
+    // meanwhile once it sets it, with the priority it configured, and not before.
+    #[test]
+    fn irq_configured_before_the_distributor_enable_waits_for_it() {
+        const SPI: usize = 40;
+        testing::boot(0);
+        let zone = testing::zone(0, &[0], &[SPI]);
+        let write = |address, value| {
+            let mut mmio = MMIOAccess {
+                address,
+                size: 4,
+                is_write: true,
+                value,
+            };
+            vgicv3_dist_handler(&mut mmio, 0).unwrap();
+        };
+        write(GICD_ISENABLER + SPI / 32 * 4, 1 << (SPI % 32));
+        write(GICD_IPRIORITYR + SPI / 4 * 4, 0xa0 << (SPI % 4 * 8));
+        assert!(!zone.read().vgicv3.dist_enabled);
+        inject_irq(SPI, true);
+        assert!((0..testing::LRS).all(|lr| read_lr(lr) == 0));
+
+        write(GICD_CTLR, GICD_CTLR_GRP1NS_ENA);
+        let lr = SPI as u64 | (SPI as u64) << 32 | 0xa0 << 48;
+        assert_eq!(read_lr(0), lr | LR_GROUP1 | LR_HW | LR_STATE_PENDING);
+        assert!((1..testing::LRS).all(|lr| read_lr(lr) == 0));
+    }
+
+    // The ownership of 32 irqs is a word of the zone's bitmaps, which GICD accesses apply as
+    // their mask: the guest only reaches the bits of its own irqs.
+    #[test]
+    fn ownership_masks_are_words_of_the_zone_bitmaps() {
+        testing::boot(0);
+        let espi = GICV3_ESPI_BASE as usize;
+        let zone = testing::zone(0, &[0], &[33, 63, 64, espi + 65]);
+        let zone_r = zone.read();
+        assert_eq!(zone_spi_bitmap(&zone_r)[1], 1 << 1 | 1 << 31);
+        assert_eq!(zone_irq_mask(&zone_r, 32), 1 << 1 | 1 << 31);
+        assert_eq!(zone_irq_mask(&zone_r, 64), 1);
+        assert_eq!(zone_irq_mask(&zone_r, espi + 64), 1 << 1);
+        assert_eq!(zone_irq_mask(&zone_r, 1024), 0);
+        // GICD_ICFGR3 configures irqs 48 to 63
+        assert_eq!(zone_icfgr_mask(&zone_r, 3), GICR_ICFGR_EDGE << 30);
+        drop(zone_r);
+
+        let mut mmio = MMIOAccess {
+            address: GICD_ISENABLER + 4,
+            size: 4,
+            is_write: true,
+            value: u32::MAX as usize,
+        };
+        vgicv3_dist_handler(&mut mmio, 0).unwrap();
+        let isenabler1 = unsafe { ((host_gicd_base() + GICD_ISENABLER + 4) as *const u32).read() };
+        assert_eq!(isenabler1, 1 << 1 | 1 << 31);
+    }
+
+    // An extended SPI the guest injects while it has it disabled is held, and injected once
+    // the guest enables it through GICD_ISENABLER<n>E.
+    #[test]
+    fn espi_held_disabled_is_injected_once_enabled() {
+        const ESPI: usize = GICV3_ESPI_BASE as usize + 37;
+        testing::boot(0);
+        let zone = testing::zone(0, &[0], &[ESPI]);
+        zone.write().vgicv3.dist_enabled = true;
+        inject_irq(ESPI, false);
+        assert_eq!(read_lr(0), 0);
+
+        let mut mmio = MMIOAccess {
+            address: GICD_ISENABLERNE + 4,
+            size: 4,
+            is_write: true,
+            value: 1 << 5,
+        };
+        vgicv3_dist_handler(&mut mmio, 0).unwrap();
+        assert_eq!(read_lr(0), ESPI as u64 | LR_GROUP1 | LR_STATE_PENDING);
+    }
+}
@@ -0,0 +1,367 @@
+//! Emulated ITS for zones that set up MSI routing through one. The command queue the guest
+//! points GITS_CBASER at is consumed each time it moves GITS_CWRITER: MAPD, MAPC, MAPTI/MAPI
+//! and DISCARD build the zone's translation of (DeviceID, EventID) pairs to LPIs, the other
+//! commands are only logged. No LPIs are injected yet, GICR_TYPER still tells the guest it
+//! has none.
+use alloc::collections::BTreeMap;
+
+use crate::{
+    arch::s2pt::Stage2PageTable,
+    error::HvResult,
+    memory::{addr::phys_to_virt, MMIOAccess, MemorySet},
+    percpu::this_zone,
+};
+
+/// The control frame and the translation frame with GITS_TRANSLATER.
+pub const GITS_FRAME_SIZE: usize = 0x20000;
+
+pub const GITS_CTLR: usize = 0x0000;
+pub const GITS_IIDR: usize = 0x0004;
+pub const GITS_TYPER: usize = 0x0008;
+pub const GITS_CBASER: usize = 0x0080;
+pub const GITS_CWRITER: usize = 0x0088;
+pub const GITS_CREADR: usize = 0x0090;
+pub const GITS_BASER: usize = 0x0100;
+pub const GITS_PIDR2: usize = 0xffe8;
+pub const GITS_TRANSLATER: usize = 0x10040;
+
+pub const GITS_CTLR_ENABLED: u32 = 1 << 0;
+pub const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+pub const GITS_CBASER_VALID: u64 = 1 << 63;
+const GITS_CBASER_PA_MASK: u64 = 0x000f_ffff_ffff_f000;
+const GITS_CBASER_SIZE_MASK: u64 = 0xff;
+// CWRITER and CREADR hold the offset of a command in the queue
+const GITS_CMD_OFFSET_MASK: u64 = 0xf_ffe0;
+const GITS_BASER_NUM: usize = 8;
+const GITS_BASER_TYPE_SHIFT: u64 = 56;
+const GITS_BASER_TYPE_DEVICE: u64 = 1;
+const GITS_BASER_TYPE_COLLECTION: u64 = 4;
+const GITS_BASER_ENTRY_SIZE_SHIFT: u64 = 48;
+// Type and Entry_Size are read-only, Indirect is RAZ/WI
+const GITS_BASER_RO_MASK: u64 = 0x7 << 56 | 0x1f << 48 | 1 << 62;
+
+// Physical LPIs, 8 byte ITT entries, 16 EventID and 16 DeviceID bits, collections targeted
+// by processor number.
+const GITS_TYPER_VALUE: u64 = 1 | 7 << 4 | 15 << 8 | 15 << 13;
+const GITS_PIDR2_ARCHREV_GICV3: u32 = 0x30;
+
+const ITS_CMD_SIZE: u64 = 32;
+const ITS_CMD_MOVI: u8 = 0x01;
+const ITS_CMD_INT: u8 = 0x03;
+const ITS_CMD_CLEAR: u8 = 0x04;
+const ITS_CMD_SYNC: u8 = 0x05;
+const ITS_CMD_MAPD: u8 = 0x08;
+const ITS_CMD_MAPC: u8 = 0x09;
+const ITS_CMD_MAPTI: u8 = 0x0a;
+const ITS_CMD_MAPI: u8 = 0x0b;
+const ITS_CMD_INV: u8 = 0x0c;
+const ITS_CMD_INVALL: u8 = 0x0d;
+const ITS_CMD_DISCARD: u8 = 0x0f;
+
+/// An ITS command as decoded from the four doublewords of its queue entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItsCommand {
+    Mapd { device: u32, event_bits: u32, valid: bool },
+    /// `target` is the vcpu id of the collection's redistributor.
+    Mapc { icid: u16, target: usize, valid: bool },
+    /// MAPI is a MAPTI with `lpi == event`.
+    Mapti { device: u32, event: u32, lpi: u32, icid: u16 },
+    Discard { device: u32, event: u32 },
+    Int { device: u32, event: u32 },
+    Clear { device: u32, event: u32 },
+    Movi { device: u32, event: u32, icid: u16 },
+    Inv { device: u32, event: u32 },
+    Invall { icid: u16 },
+    Sync { target: usize },
+    Unknown(u8),
+}
+
+impl ItsCommand {
+    pub fn decode(cmd: [u64; 4]) -> Self {
+        let device = (cmd[0] >> 32) as u32;
+        let event = cmd[1] as u32;
+        let icid = cmd[2] as u16;
+        match cmd[0] as u8 {
+            ITS_CMD_MAPD => Self::Mapd {
+                device,
+                event_bits: (cmd[1] & 0x1f) as u32 + 1,
+                valid: cmd[2] >> 63 != 0,
+            },
+            ITS_CMD_MAPC => Self::Mapc {
+                icid,
+                target: (cmd[2] >> 16 & 0xffff_ffff) as usize,
+                valid: cmd[2] >> 63 != 0,
+            },
+            ITS_CMD_MAPTI => Self::Mapti {
+                device,
+                event,
+                lpi: (cmd[1] >> 32) as u32,
+                icid,
+            },
+            ITS_CMD_MAPI => Self::Mapti {
+                device,
+                event,
+                lpi: event,
+                icid,
+            },
+            ITS_CMD_DISCARD => Self::Discard { device, event },
+            ITS_CMD_INT => Self::Int { device, event },
+            ITS_CMD_CLEAR => Self::Clear { device, event },
+            ITS_CMD_MOVI => Self::Movi {
+                device,
+                event,
+                icid,
+            },
+            ITS_CMD_INV => Self::Inv { device, event },
+            ITS_CMD_INVALL => Self::Invall { icid },
+            ITS_CMD_SYNC => Self::Sync {
+                target: (cmd[2] >> 16 & 0xffff_ffff) as usize,
+            },
+            opcode => Self::Unknown(opcode),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ItsDevice {
+    event_bits: u32,
+    // EventID -> (LPI, ICID)
+    events: BTreeMap<u32, (u32, u16)>,
+}
+
+/// Per-zone state of the emulated ITS.
+#[derive(Debug)]
+pub struct Vits {
+    ctlr: u32,
+    cbaser: u64,
+    cwriter: u64,
+    creadr: u64,
+    baser: [u64; GITS_BASER_NUM],
+    devices: BTreeMap<u32, ItsDevice>,
+    // ICID -> vcpu id of the target redistributor
+    collections: BTreeMap<u16, usize>,
+}
+
+impl Default for Vits {
+    fn default() -> Self {
+        let mut baser = [0; GITS_BASER_NUM];
+        baser[0] = GITS_BASER_TYPE_DEVICE << GITS_BASER_TYPE_SHIFT;
+        baser[1] = GITS_BASER_TYPE_COLLECTION << GITS_BASER_TYPE_SHIFT;
+        for baser in baser.iter_mut().take(2) {
+            *baser |= 7 << GITS_BASER_ENTRY_SIZE_SHIFT;
+        }
+        Self {
+            ctlr: 0,
+            cbaser: 0,
+            cwriter: 0,
+            creadr: 0,
+            baser,
+            devices: BTreeMap::new(),
+            collections: BTreeMap::new(),
+        }
+    }
+}
+
+impl Vits {
+    /// LPI and target vcpu id `event` of `device` is translated to, if the guest mapped both
+    /// the event and its collection.
+    pub fn translate(&self, device: u32, event: u32) -> Option<(u32, usize)> {
+        let &(lpi, icid) = self.devices.get(&device)?.events.get(&event)?;
+        Some((lpi, *self.collections.get(&icid)?))
+    }
+
+    fn execute(&mut self, zone_id: usize, cmd: ItsCommand) {
+        debug!("zone {} its: {:x?}", zone_id, cmd);
+        match cmd {
+            ItsCommand::Mapd {
+                device,
+                event_bits,
+                valid,
+            } => {
+                if valid {
+                    let events = BTreeMap::new();
+                    self.devices.insert(device, ItsDevice { event_bits, events });
+                } else {
+                    self.devices.remove(&device);
+                }
+            }
+            ItsCommand::Mapc {
+                icid,
+                target,
+                valid,
+            } => {
+                if valid {
+                    self.collections.insert(icid, target);
+                } else {
+                    self.collections.remove(&icid);
+                }
+            }
+            ItsCommand::Mapti {
+                device,
+                event,
+                lpi,
+                icid,
+            } => match self.devices.get_mut(&device) {
+                Some(dev) if event >> dev.event_bits == 0 => {
+                    dev.events.insert(event, (lpi, icid));
+                }
+                _ => warn!("zone {} its: {:x?} for an unmapped device or event", zone_id, cmd),
+            },
+            ItsCommand::Discard { device, event } => {
+                if let Some(dev) = self.devices.get_mut(&device) {
+                    dev.events.remove(&event);
+                }
+            }
+            ItsCommand::Movi {
+                device,
+                event,
+                icid,
+            } => {
+                if let Some(mapping) = self
+                    .devices
+                    .get_mut(&device)
+                    .and_then(|dev| dev.events.get_mut(&event))
+                {
+                    mapping.1 = icid;
+                }
+            }
+            ItsCommand::Int { device, event } => match self.translate(device, event) {
+                Some((lpi, target)) => {
+                    warn!("zone {} its: lpi {} for vcpu {} not injected", zone_id, lpi, target)
+                }
+                None => warn!("zone {} its: INT of untranslated event {:x?}", zone_id, cmd),
+            },
+            ItsCommand::Unknown(opcode) => {
+                warn!("zone {} its: unknown command {:#x}", zone_id, opcode)
+            }
+            // nothing is cached or pending without LPI injection
+            ItsCommand::Clear { .. }
+            | ItsCommand::Inv { .. }
+            | ItsCommand::Invall { .. }
+            | ItsCommand::Sync { .. } => {}
+        }
+    }
+
+    // Run the commands from CREADR up to CWRITER. A command that can't be read stops the
+    // queue, the guest sees it stuck at that command.
+    fn process_commands(&mut self, zone_id: usize, gpm: &MemorySet<Stage2PageTable>) {
+        if self.ctlr & GITS_CTLR_ENABLED == 0 || self.cbaser & GITS_CBASER_VALID == 0 {
+            return;
+        }
+        let base = (self.cbaser & GITS_CBASER_PA_MASK) as usize;
+        let size = ((self.cbaser & GITS_CBASER_SIZE_MASK) + 1) * 4096;
+        while self.creadr != self.cwriter {
+            if self.cwriter >= size {
+                warn!("zone {} its: cwriter {:#x} beyond the queue", zone_id, self.cwriter);
+                return;
+            }
+            let Some(cmd) = read_command(gpm, base + self.creadr as usize) else {
+                warn!("zone {} its: command queue at {:#x} not mapped", zone_id, base);
+                return;
+            };
+            self.execute(zone_id, ItsCommand::decode(cmd));
+            self.creadr = (self.creadr + ITS_CMD_SIZE) % size;
+        }
+    }
+}
+
+fn read_command(gpm: &MemorySet<Stage2PageTable>, gpa: usize) -> Option<[u64; 4]> {
+    let (hpa, _, _) = unsafe { gpm.page_table_query(gpa) }.ok()?;
+    let cmd = phys_to_virt(hpa) as *const [u64; 4];
+    Some(unsafe { cmd.read_volatile() })
+}
+
+// 64-bit registers can be accessed a word at a time, `offset` is the one of the register.
+fn read_reg64(mmio: &mut MMIOAccess, offset: usize, value: u64) {
+    mmio.value = match (mmio.address - offset, mmio.size) {
+        (0, 8) => value,
+        (0, _) => value & 0xffff_ffff,
+        _ => value >> 32,
+    } as usize;
+}
+
+fn write_reg64(mmio: &MMIOAccess, offset: usize, old: u64) -> u64 {
+    let value = mmio.value as u64;
+    match (mmio.address - offset, mmio.size) {
+        (0, 8) => value,
+        (0, _) => old & !0xffff_ffff | value & 0xffff_ffff,
+        _ => old & 0xffff_ffff | value << 32,
+    }
+}
+
+pub fn vgicv3_its_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
+    trace!("gits mmio = {:#x?}", mmio);
+    let zone = this_zone();
+    let mut zone_w = zone.write();
+    let zone_id = zone_w.id;
+    let zone_w = &mut *zone_w;
+    let its = &mut zone_w.vgicv3.its;
+    let reg = mmio.address & !7;
+    match mmio.address {
+        GITS_CTLR => {
+            if !mmio.is_write {
+                // commands are run synchronously, the ITS is always quiescent
+                mmio.value = (its.ctlr | GITS_CTLR_QUIESCENT) as usize;
+            } else {
+                its.ctlr = mmio.value as u32 & GITS_CTLR_ENABLED;
+                its.process_commands(zone_id, &zone_w.gpm);
+            }
+        }
+        GITS_IIDR => {
+            if !mmio.is_write {
+                mmio.value = 0;
+            }
+        }
+        GITS_PIDR2 => {
+            if !mmio.is_write {
+                mmio.value = GITS_PIDR2_ARCHREV_GICV3 as usize;
+            }
+        }
+        _ if reg == GITS_TYPER => {
+            if !mmio.is_write {
+                read_reg64(mmio, reg, GITS_TYPER_VALUE);
+            }
+        }
+        _ if reg == GITS_CBASER => {
+            if !mmio.is_write {
+                read_reg64(mmio, reg, its.cbaser);
+            } else if its.ctlr & GITS_CTLR_ENABLED == 0 {
+                its.cbaser = write_reg64(mmio, reg, its.cbaser);
+                its.creadr = 0;
+            }
+        }
+        _ if reg == GITS_CWRITER => {
+            if !mmio.is_write {
+                read_reg64(mmio, reg, its.cwriter);
+            } else {
+                its.cwriter = write_reg64(mmio, reg, its.cwriter) & GITS_CMD_OFFSET_MASK;
+                its.process_commands(zone_id, &zone_w.gpm);
+            }
+        }
+        _ if reg == GITS_CREADR => {
+            if !mmio.is_write {
+                read_reg64(mmio, reg, its.creadr);
+            }
+        }
+        _ if (GITS_BASER..GITS_BASER + GITS_BASER_NUM * 8).contains(&reg) => {
+            let baser = &mut its.baser[(reg - GITS_BASER) / 8];
+            if !mmio.is_write {
+                read_reg64(mmio, reg, *baser);
+            } else if *baser & GITS_BASER_RO_MASK != 0 {
+                // unimplemented tables stay all zero
+                let value = write_reg64(mmio, reg, *baser);
+                *baser = value & !GITS_BASER_RO_MASK | *baser & GITS_BASER_RO_MASK & !(1 << 62);
+            }
+        }
+        GITS_TRANSLATER => {
+            // MSIs come from devices, the DeviceID of a cpu write is unknown
+            debug!("zone {} its: ignoring cpu write to GITS_TRANSLATER", zone_id);
+        }
+        _ => {
+            if !mmio.is_write {
+                mmio.value = 0;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Watchdog for vcpus which stop leaving the guest. Every exit arms the EL2 physical timer
+//! (CNTHP) with the zone's timeout again, so it only fires once a vcpu ran that long without
+//! one. It then injects the zone's watchdog irq as a group 0 irq, which the guest takes as an
+//! FIQ even with its irqs masked, to get it into its diagnostic handler.
+//!
+//! The timeout is taken from the zone when the vcpu is reset and kept in the cpu's
+//! `PerCpuGic`, so an exit doesn't take the zone lock for it and one of a zone without a
+//! watchdog doesn't touch the timer.
+//!
+//! The timer also fires when a storm cooldown of this cpu ends, see `storm`. The irq exit it
+//! causes polls the cooldowns like any other one.
+//!
+//! A vcpu idling in WFI doesn't exit either, the timeout has to be longer than the guest's
+//! longest idle period.
+use core::sync::atomic::Ordering;
+
+use super::{
+    cpu_state::this_gic, gicr::enable_hv_ppi, inject_irq_to_group, storm::us_to_ticks, IrqGroup,
+    HV_TIMER_IRQ,
+};
+use crate::{
+    arch::aarch64::{
+        cpu::this_cpu_id,
+        sysreg::{read_sysreg, write_sysreg},
+    },
+    percpu::this_cpu_data,
+};
+
+const CNTHP_CTL_ENABLE: u64 = 1 << 0;
+const CNTHP_CTL_IMASK: u64 = 1 << 1;
+// above the guests' irqs, so a timeout isn't held back by an irq the zone handles
+const HV_TIMER_PRIORITY: u8 = 0x10;
+
+/// Watchdog settings of a zone, `timeout_us` 0 turns it off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WatchdogConfig {
+    pub timeout_us: u64,
+    /// Virtual irq injected on a timeout.
+    pub irq: usize,
+}
+
+fn zone_watchdog() -> Option<WatchdogConfig> {
+    let config = this_cpu_data().zone.as_ref()?.read().vgicv3.watchdog;
+    (config.timeout_us != 0).then_some(config)
+}
+
+/// Route this cpu's CNTHP irq to the hypervisor, masked at the timer until `kick` arms it.
+pub fn cpu_init() {
+    write_sysreg!(cnthp_ctl_el2, CNTHP_CTL_IMASK);
+    enable_hv_ppi(HV_TIMER_IRQ, HV_TIMER_PRIORITY);
+}
+
+/// Take the watchdog timeout of the zone this cpu's vcpu now runs in.
+pub fn vcpu_init() {
+    let ticks = zone_watchdog().map_or(0, |config| us_to_ticks(config.timeout_us));
+    this_gic().watchdog_ticks.store(ticks, Ordering::Relaxed);
+    this_gic().watchdog_deadline.store(0, Ordering::Relaxed);
+    if ticks == 0 {
+        write_sysreg!(cnthp_ctl_el2, CNTHP_CTL_IMASK);
+    }
+}
+
+/// Arm the watchdog of this vcpu for another timeout, right before it returns to the guest,
+/// or the first storm cooldown of this cpu ends if that is earlier.
+pub fn kick() {
+    let gic = this_gic();
+    let ticks = gic.watchdog_ticks.load(Ordering::Relaxed);
+    let storm = gic.storm_deadline.load(Ordering::Relaxed);
+    if ticks == 0 && storm == 0 {
+        return;
+    }
+    let mut deadline = u64::MAX;
+    if ticks != 0 {
+        deadline = read_sysreg!(cntpct_el0) + ticks;
+        gic.watchdog_deadline.store(deadline, Ordering::Relaxed);
+    }
+    if storm != 0 {
+        deadline = deadline.min(storm);
+    }
+    write_sysreg!(cnthp_cval_el2, deadline);
+    write_sysreg!(cnthp_ctl_el2, CNTHP_CTL_ENABLE);
+}
+
+/// Handle the CNTHP irq, injecting the watchdog irq if the vcpu ran out its timeout.
+pub fn timer_irq() {
+    // the exit this irq caused arms it again
+    write_sysreg!(cnthp_ctl_el2, CNTHP_CTL_IMASK);
+    let deadline = this_gic().watchdog_deadline.load(Ordering::Relaxed);
+    if deadline == 0 || read_sysreg!(cntpct_el0) < deadline {
+        return;
+    }
+    let Some(config) = zone_watchdog() else {
+        return;
+    };
+    warn!(
+        "watchdog: vcpu on cpu {} ran {} us without exit, injecting irq {}",
+        this_cpu_id(),
+        config.timeout_us,
+        config.irq
+    );
+    inject_irq_to_group(config.irq, false, IrqGroup::Group0);
+}
@@ -1,4 +1,5 @@
 pub mod common;
 pub mod irqchip;
 pub mod uart;
+#[cfg(feature = "virtio")]
 pub mod virtio_trampoline;
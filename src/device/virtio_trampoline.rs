@@ -9,7 +9,7 @@ use spin::Mutex;
 use crate::arch::cpu::this_cpu_id;
 use crate::device::irqchip::gicv3::inject_irq;
 use crate::event::send_event;
-use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
+use crate::event::{IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP_VIRTIO_DEVICE};
 use crate::hypercall::SGI_IPI_ID;
 use crate::zone::root_zone;
 use crate::zone::this_zone_id;
@@ -86,6 +86,12 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     Ok(())
 }
 
+/// Tell `target_cpu` that virtio results are waiting in `VIRTIO_IRQS` for it: an
+/// `IPI_EVENT_VIRTIO_INJECT_IRQ` event signaled with the hypervisor's SGI.
+pub fn notify_virtio_result(target_cpu: usize) {
+    send_event(target_cpu, SGI_IPI_ID as _, IPI_EVENT_VIRTIO_INJECT_IRQ);
+}
+
 /// When virtio req type is notify, root zone will send sgi to non root, \
 /// and non root will call this function.
 pub fn handle_virtio_irq() {
@@ -1,18 +1,24 @@
 use crate::{
     arch::ipi::arch_send_event,
-    device::{
-        irqchip::gicv3::inject_irq,
-        virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
-    },
+    device::irqchip::gicv3::{flush_pending_irqs, gicv3_reset_vcpu},
     percpu::this_cpu_data,
+    work::drain_work,
+};
+#[cfg(feature = "virtio")]
+use crate::device::{
+    irqchip::gicv3::inject_irq,
+    virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
 };
 use alloc::{collections::VecDeque, vec::Vec};
 use spin::{Mutex, Once};
 
 pub const IPI_EVENT_WAKEUP: usize = 0;
 pub const IPI_EVENT_SHUTDOWN: usize = 1;
+#[cfg(feature = "virtio")]
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
+#[cfg(feature = "virtio")]
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
+pub const IPI_EVENT_FLUSH_PENDING_IRQS: usize = 4;
 static EVENT_MANAGER: Once<EventManager> = Once::new();
 
 struct EventManager {
@@ -65,22 +71,32 @@ pub fn init(max_cpus: usize) {
 
 pub fn check_events() -> bool {
     let cpu_data = this_cpu_data();
+    // work items share the event SGI, kicks for them come without an event
+    let worked = drain_work(cpu_data.id) != 0;
     match fetch_event(cpu_data.id) {
         Some(IPI_EVENT_WAKEUP) => {
             cpu_data.arch_cpu.run();
         }
         Some(IPI_EVENT_SHUTDOWN) => {
+            // don't leak the old zone's interrupts to the next one
+            gicv3_reset_vcpu(cpu_data.id);
             cpu_data.arch_cpu.idle();
         }
+        #[cfg(feature = "virtio")]
         Some(IPI_EVENT_VIRTIO_INJECT_IRQ) => {
             handle_virtio_irq();
             true
         }
+        #[cfg(feature = "virtio")]
         Some(IPI_EVENT_WAKEUP_VIRTIO_DEVICE) => {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
-        _ => false,
+        Some(IPI_EVENT_FLUSH_PENDING_IRQS) => {
+            flush_pending_irqs();
+            true
+        }
+        _ => worked,
     }
 }
 
@@ -1,13 +1,25 @@
 #![allow(dead_code)]
 use crate::config::HvZoneConfig;
-use crate::consts::{INVALID_ADDRESS, PAGE_SIZE};
-use crate::device::virtio_trampoline::{VIRTIO_BRIDGE, MAX_DEVS, MAX_REQ, VIRTIO_IRQS};
+use crate::consts::INVALID_ADDRESS;
+#[cfg(feature = "virtio")]
+use crate::consts::PAGE_SIZE;
+#[cfg(feature = "virtio")]
+use crate::device::virtio_trampoline::{
+    notify_virtio_result, MAX_DEVS, MAX_REQ, VIRTIO_BRIDGE, VIRTIO_IRQS,
+};
+use crate::device::irqchip::gicv3::{
+    audit, lr_count, pv,
+    summary::{irq_summary, zone_irqs},
+    vgic::set_gicd_trace,
+};
 use crate::error::HvResult;
+use crate::logging::set_zone_filter;
 use crate::percpu::{get_cpu_data, PerCpu};
 use crate::zone::{find_zone, is_this_root_zone, remove_zone, zone_create};
 
-use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP};
 use core::convert::TryFrom;
+#[cfg(feature = "virtio")]
 use core::sync::atomic::{fence, Ordering};
 
 use numeric_enum_macro::numeric_enum;
@@ -20,6 +32,13 @@ numeric_enum! {
         HvVirtioInjectIrq = 1,
         HvZoneStart = 2,
         HvZoneShutdown = 3,
+        HvIrqSummary = 4,
+        HvGicLrCount = 5,
+        HvIrqAudit = 6,
+        HvInspectZoneIrqs = 7,
+        HvPvIrqRegister = 8,
+        HvGicdTrace = 9,
+        HvLogZone = 10,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
@@ -35,7 +54,7 @@ impl<'a> HyperCall<'a> {
         Self { cpu_data }
     }
 
-    pub fn hypercall(&mut self, code: u64, arg0: u64, _arg1: u64) -> HyperCallResult {
+    pub fn hypercall(&mut self, code: u64, arg0: u64, arg1: u64) -> HyperCallResult {
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
@@ -45,15 +64,29 @@ impl<'a> HyperCall<'a> {
         };
         unsafe {
             match code {
+                #[cfg(feature = "virtio")]
                 HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
+                #[cfg(feature = "virtio")]
                 HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
+                #[cfg(not(feature = "virtio"))]
+                HyperCallCode::HvVirtioInit | HyperCallCode::HvVirtioInjectIrq => {
+                    hv_result_err!(ENOSYS, "virtio support is not built in")
+                }
                 HyperCallCode::HvZoneStart => self.hv_zone_start(&*(arg0 as *const HvZoneConfig)),
                 HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
+                HyperCallCode::HvIrqSummary => self.hv_irq_summary(arg0, arg1),
+                HyperCallCode::HvGicLrCount => self.hv_gic_lr_count(),
+                HyperCallCode::HvIrqAudit => self.hv_irq_audit(arg0, arg1),
+                HyperCallCode::HvInspectZoneIrqs => self.hv_inspect_zone_irqs(arg0, arg1),
+                HyperCallCode::HvPvIrqRegister => self.hv_pv_irq_register(arg0),
+                HyperCallCode::HvGicdTrace => self.hv_gicd_trace(arg0, arg1),
+                HyperCallCode::HvLogZone => self.hv_log_zone(arg0, arg1),
             }
         }
     }
 
     // only root zone calls the function and set virtio shared region between el1 and el2.
+    #[cfg(feature = "virtio")]
     fn hv_virtio_init(&mut self, shared_region_addr: u64) -> HyperCallResult {
         info!(
             "handle hvc init virtio, shared_region_addr = {:#x?}",
@@ -82,6 +115,7 @@ impl<'a> HyperCall<'a> {
     }
 
     // Inject virtio device's irq to non root when a virtio device finishes one IO request. Only root zone calls.
+    #[cfg(feature = "virtio")]
     fn hv_virtio_inject_irq(&self) -> HyperCallResult {
         if !is_this_root_zone() {
             return hv_result_err!(
@@ -109,11 +143,7 @@ impl<'a> HyperCall<'a> {
                 assert!(len + 1 < MAX_DEVS);
                 irq_list[len + 1] = irq_id;
                 irq_list[0] += 1;
-                send_event(
-                    target_cpu as _,
-                    SGI_IPI_ID as _,
-                    IPI_EVENT_VIRTIO_INJECT_IRQ,
-                );
+                notify_virtio_result(target_cpu);
             }
 
             fence(Ordering::SeqCst);
@@ -169,6 +199,7 @@ impl<'a> HyperCall<'a> {
         zone_r.cpu_set.iter().for_each(|cpu_id| {
             let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
             get_cpu_data(cpu_id).zone = None;
+            get_cpu_data(cpu_id).zone_id = None;
             get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
             send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
         });
@@ -181,4 +212,70 @@ impl<'a> HyperCall<'a> {
 
         HyperCallResult::Ok(0)
     }
+
+    // Copy a summary of the interrupt configuration to the root zone's buffer of `size` bytes
+    // at guest physical `buf`.
+    fn hv_irq_summary(&self, buf: u64, size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Irq summary operation over non-root zones: unsupported!"
+            );
+        }
+        irq_summary(buf as _, size as _)
+    }
+
+    // Number of list registers behind the calling vcpu's virtual cpu interface, open to all
+    // zones so guests can size their interrupt batching.
+    fn hv_gic_lr_count(&self) -> HyperCallResult {
+        HyperCallResult::Ok(lr_count())
+    }
+
+    // Move the calling zone's irq audit ring to its buffer of `size` bytes at guest physical
+    // `buf`, for zones which enabled the audit.
+    fn hv_irq_audit(&self, buf: u64, size: u64) -> HyperCallResult {
+        audit::fetch_and_clear(buf as _, size as _)
+    }
+
+    // Copy the SPI states of zone `zone_id` to the buffer at `buf`. Only the root zone, which
+    // manages the others, may inspect them.
+    fn hv_inspect_zone_irqs(&self, zone_id: u64, buf: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Inspecting zone irqs over non-root zones: unsupported!");
+        }
+        zone_irqs(zone_id as _, buf as _)
+    }
+
+    // Register the page at guest physical `gpa` for the calling vcpu's irq delivery notices,
+    // 0 unregisters it.
+    fn hv_pv_irq_register(&self, gpa: u64) -> HyperCallResult {
+        pv::register(gpa as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Log all GICD accesses of zone `zone_id` while `enable` is non-zero, a debug aid the root
+    // zone switches on for one guest at a time.
+    fn hv_gicd_trace(&self, zone_id: u64, enable: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Gicd trace over non-root zones: unsupported!");
+        }
+        set_gicd_trace(zone_id as _, enable != 0)?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Keep only the log records of zone `zone_id` while `enable` is non-zero, those of all
+    // zones otherwise.
+    fn hv_log_zone(&self, zone_id: u64, enable: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Log filter over non-root zones: unsupported!");
+        }
+        if enable == 0 {
+            set_zone_filter(None);
+        } else if find_zone(zone_id as _).is_some() {
+            set_zone_filter(Some(zone_id as _));
+        } else {
+            return hv_result_err!(ENOENT, format!("no zone {}", zone_id));
+        }
+        HyperCallResult::Ok(0)
+    }
 }
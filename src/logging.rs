@@ -1,7 +1,8 @@
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use log::{self, Level, LevelFilter, Log, Metadata, Record};
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 use crate::device::uart;
 
@@ -73,6 +74,8 @@ enum ColorCode {
 pub fn init() {
     static LOGGER: SimpleLogger = SimpleLogger;
     log::set_logger(&LOGGER).unwrap();
+    // LOG_ZONE=<id> starts with the records of only that zone, see `set_zone_filter`
+    set_zone_filter(option_env!("LOG_ZONE").and_then(|zone_id| zone_id.parse().ok()));
     log::set_max_level(match option_env!("LOG") {
         Some("error") => LevelFilter::Error,
         Some("warn") => LevelFilter::Warn,
@@ -83,22 +86,20 @@ pub fn init() {
     });
 }
 
-struct SimpleLogger;
+/// Where the logger writes its records to, along with the cpu and the zone, if any, they
+/// were logged on. `ConsoleSink` is the one of a boot, see `set_sink`.
+pub trait LogSink: Sync {
+    fn write(&self, record: &Record, cpu_id: usize, zone_id: Option<usize>);
+}
 
-impl Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
-    }
-
-    fn log(&self, record: &Record) {
-        if !self.enabled(record.metadata()) {
-            return;
-        }
+/// Prints records on the hypervisor console.
+pub struct ConsoleSink;
 
+impl LogSink for ConsoleSink {
+    fn write(&self, record: &Record, cpu_id: usize, zone_id: Option<usize>) {
         let level = record.level();
         let line = record.line().unwrap_or(0);
         let target = record.target();
-        let cpu_id = crate::percpu::this_cpu_data().id;
         let level_color = match level {
             Level::Error => ColorCode::BrightRed,
             Level::Warn => ColorCode::BrightYellow,
@@ -115,13 +116,106 @@ impl Log for SimpleLogger {
         };
         print(with_color!(
             ColorCode::White,
-            "[{} {}] {} {}\n",
+            "[{} {}:{}] {} {}\n",
             with_color!(level_color, "{:<5}", level),
             with_color!(ColorCode::White, "{}", cpu_id),
+            with_color!(ColorCode::White, "{}", ZoneId(zone_id)),
             with_color!(ColorCode::White, "({}:{})", target, line),
             with_color!(args_color, "{}", record.args()),
         ));
     }
+}
+
+static SINK: RwLock<&'static dyn LogSink> = RwLock::new(&ConsoleSink);
+
+/// Send all further records to `sink`, to collect them somewhere else than on the console.
+#[allow(dead_code)]
+pub fn set_sink(sink: &'static dyn LogSink) {
+    *SINK.write() = sink;
+}
+
+const ALL_ZONES: usize = usize::MAX;
+static ZONE_FILTER: AtomicUsize = AtomicUsize::new(ALL_ZONES);
+
+/// Keep only the records of zone `zone_id`, and those logged without zone context, or those
+/// of all zones again with `None`. The root zone sets it through `HvLogZone` while it debugs
+/// the irqs of one guest.
+pub fn set_zone_filter(zone_id: Option<usize>) {
+    ZONE_FILTER.store(zone_id.unwrap_or(ALL_ZONES), Ordering::Relaxed);
+    info!("log filter: zone {}", ZoneId(zone_id));
+}
+
+// Whether a record of a cpu in zone `zone_id` passes the zone filter.
+fn zone_filter_keeps(zone_id: Option<usize>) -> bool {
+    let filter = ZONE_FILTER.load(Ordering::Relaxed);
+    filter == ALL_ZONES || zone_id.map_or(true, |zone_id| zone_id == filter)
+}
+
+struct SimpleLogger;
+
+// zone context of a record, `-` when the cpu doesn't belong to a zone
+struct ZoneId(Option<usize>);
+
+impl fmt::Display for ZoneId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "{}", id),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+impl Log for SimpleLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let cpu_data = crate::percpu::this_cpu_data();
+        if zone_filter_keeps(cpu_data.zone_id) {
+            SINK.read().write(record, cpu_data.id, cpu_data.zone_id);
+        }
+    }
 
     fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::irqchip::gicv3::testing;
+    use alloc::vec::Vec;
+
+    // A sink keeping the zone context of the records it gets.
+    struct ZoneSink(Mutex<Vec<Option<usize>>>);
+
+    impl LogSink for ZoneSink {
+        fn write(&self, _record: &Record, _cpu_id: usize, zone_id: Option<usize>) {
+            self.0.lock().push(zone_id);
+        }
+    }
+
+    // With the filter on zone 1 the sink gets the records of zone 1 and those of cpus without
+    // a zone, once the filter is off it gets the records of all zones again.
+    #[test]
+    fn zone_filter_keeps_the_records_of_its_zone() {
+        static RECORDS: ZoneSink = ZoneSink(Mutex::new(Vec::new()));
+        set_sink(&RECORDS);
+        testing::boot(0);
+        testing::zone(1, &[1], &[]);
+        testing::zone(2, &[2], &[]);
+        let log_on = |cpu| {
+            testing::run_on(cpu);
+            SimpleLogger.log(&Record::builder().args(format_args!("irq 40")).build());
+        };
+        set_zone_filter(Some(1));
+        (0..3).for_each(log_on);
+        set_zone_filter(None);
+        (0..3).for_each(log_on);
+        assert_eq!(*RECORDS.0.lock(), [None, Some(1), None, Some(1), Some(2)]);
+    }
+}
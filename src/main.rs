@@ -9,7 +9,9 @@
 //! - [`arch`]: Architecture's related
 
 #![no_std] // 禁用标准库链接
-#![no_main]
+#![cfg_attr(not(test), no_main)]
+// the unit tests don't reach most of the hypervisor from their entry point
+#![cfg_attr(test, allow(dead_code))]
 // 不使用main入口，使用自己定义实际入口_start，因为我们还没有初始化堆栈指针
 #![feature(asm_const)]
 #![feature(naked_functions)] //  surpport naked function
@@ -18,6 +20,34 @@
 // #![deny(warnings, missing_docs)] // 将warnings作为error
 #[macro_use]
 extern crate alloc;
+// the unit tests run as a program of an aarch64 Linux host, see `arch::sysreg::mock`
+#[cfg(test)]
+extern crate std;
+
+/// State of the machine the hypervisor runs on: `fn $name() -> &'static $ty`, a `static`
+/// initialized with `$init`. Under `cargo test` every test thread has its own, as it has its
+/// own registers, so the tests can boot machines side by side.
+macro_rules! machine_static {
+    ($(#[$meta:meta])* $vis:vis fn $name:ident() -> $ty:ty = $init:expr;) => {
+        $(#[$meta])*
+        $vis fn $name() -> &'static $ty {
+            #[cfg(not(test))]
+            {
+                static VALUE: $ty = $init;
+                &VALUE
+            }
+            #[cfg(test)]
+            {
+                std::thread_local! {
+                    static VALUE: &'static $ty =
+                        alloc::boxed::Box::leak(alloc::boxed::Box::new($init));
+                }
+                VALUE.with(|value| *value)
+            }
+        }
+    };
+}
+
 extern crate buddy_system_allocator;
 #[macro_use]
 mod error;
@@ -33,9 +63,11 @@ mod device;
 mod event;
 mod hypercall;
 mod memory;
+#[cfg(not(test))]
 mod panic;
 mod percpu;
 mod platform;
+mod work;
 mod zone;
 mod config;
 
@@ -99,6 +131,7 @@ fn primary_init_early() {
     memory::frame::init();
     memory::frame::test();
     event::init(MAX_CPU_NUM);
+    work::init(MAX_CPU_NUM);
 
     device::irqchip::primary_init_early();
     // crate::arch::mm::init_hv_page_table().unwrap();
@@ -176,7 +209,7 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     }
 
     per_cpu_init(cpu);
-    device::irqchip::percpu_init();
+    let irqchip_ok = device::irqchip::percpu_init(is_primary).is_ok();
 
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
     wait_for_counter(&INITED_CPUS, MAX_CPU_NUM as _);
@@ -187,5 +220,11 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
         wait_for_counter(&INIT_LATE_OK, 1);
     }
 
+    // a cpu without its interrupt controller can't run a vcpu
+    if !irqchip_ok {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
     cpu.run_vm();
 }
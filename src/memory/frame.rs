@@ -5,7 +5,9 @@ use bitmap_allocator::BitAlloc;
 
 use spin::Mutex;
 
-use super::addr::{align_down, align_up, is_aligned, PhysAddr};
+#[cfg(not(test))]
+use super::addr::align_down;
+use super::addr::{align_up, is_aligned, PhysAddr};
 use crate::consts::PAGE_SIZE;
 use crate::error::HvResult;
 
@@ -225,6 +227,7 @@ impl Drop for Frame {
 }
 
 /// Initialize the physical frame allocator.
+#[cfg(not(test))]
 pub fn init() {
     let mem_pool_start = crate::consts::mem_pool_start();
     let mem_pool_end = align_down(crate::consts::hv_end());
@@ -237,6 +240,19 @@ pub fn init() {
     );
 }
 
+/// Initialize the physical frame allocator of the unit tests, once for all test threads. Its
+/// frames are taken from the heap, which they can use as is with `PHYS_VIRT_OFFSET` at 0.
+#[cfg(test)]
+pub fn init() {
+    const POOL_SIZE: usize = 1024 * PAGE_SIZE;
+    static POOL: std::sync::Once = std::sync::Once::new();
+    POOL.call_once(|| {
+        let layout = core::alloc::Layout::from_size_align(POOL_SIZE, PAGE_SIZE).unwrap();
+        let pool_start = unsafe { alloc::alloc::alloc(layout) } as PhysAddr;
+        FRAME_ALLOCATOR.lock().init(pool_start, POOL_SIZE);
+    });
+}
+
 pub fn test() {
     let mut v: Vec<Frame> = Vec::new();
     for _ in 0..5 {
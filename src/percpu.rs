@@ -3,6 +3,8 @@ use spin::{Mutex, RwLock};
 
 use crate::arch::cpu::{this_cpu_id, ArchCpu};
 use crate::consts::{INVALID_ADDRESS, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
+#[cfg(target_arch = "aarch64")]
+use crate::device::irqchip::gicv3::cpu_state::PerCpuGic;
 use crate::memory::addr::VirtAddr;
 use crate::zone::Zone;
 use crate::ENTERED_CPUS;
@@ -18,8 +20,12 @@ pub struct PerCpu {
     pub dtb_ipa: usize,
     pub arch_cpu: ArchCpu,
     pub zone: Option<Arc<RwLock<Zone>>>,
+    /// Id of `zone`, readable without taking the zone lock (e.g. by the logger).
+    pub zone_id: Option<usize>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    #[cfg(target_arch = "aarch64")]
+    pub gic: PerCpuGic,
     // percpu stack
 }
 
@@ -27,17 +33,7 @@ impl PerCpu {
     pub fn new<'a>(cpu_id: usize) -> &'static mut PerCpu {
         let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
         let ret = vaddr as *mut Self;
-        unsafe {
-            ret.write_volatile(PerCpu {
-                id: cpu_id,
-                cpu_on_entry: INVALID_ADDRESS,
-                dtb_ipa: INVALID_ADDRESS,
-                arch_cpu: ArchCpu::new(cpu_id),
-                zone: None,
-                ctrl_lock: Mutex::new(()),
-                boot_cpu: false,
-            })
-        };
+        unsafe { ret.write_volatile(Self::empty(cpu_id)) };
         #[cfg(target_arch = "riscv64")]
         {
             use crate::arch::csr::CSR_SSCRATCH;
@@ -46,6 +42,22 @@ impl PerCpu {
         unsafe { ret.as_mut().unwrap() }
     }
 
+    // cpu `cpu_id` before it is initialized
+    fn empty(cpu_id: usize) -> Self {
+        PerCpu {
+            id: cpu_id,
+            cpu_on_entry: INVALID_ADDRESS,
+            dtb_ipa: INVALID_ADDRESS,
+            arch_cpu: ArchCpu::new(cpu_id),
+            zone: None,
+            zone_id: None,
+            ctrl_lock: Mutex::new(()),
+            boot_cpu: false,
+            #[cfg(target_arch = "aarch64")]
+            gic: PerCpuGic::new(),
+        }
+    }
+
     pub fn run_vm(&mut self) {
         if !self.boot_cpu {
             info!("CPU{}: Idling the CPU before starting VM...", self.id);
@@ -66,11 +78,26 @@ impl PerCpu {
     }
 }
 
+#[cfg(not(test))]
 pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
     let cpu_data: usize = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
     unsafe { &mut *(cpu_data as *mut PerCpu) }
 }
 
+// The cpus of a test thread's machine (see `machine_static!`) live on the heap, from their
+// first use on, as they are before they boot.
+#[cfg(test)]
+pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
+    use crate::consts::MAX_CPU_NUM;
+    use alloc::vec::Vec;
+    std::thread_local! {
+        static CPUS: *mut PerCpu =
+            Vec::leak((0..MAX_CPU_NUM).map(PerCpu::empty).collect()).as_mut_ptr();
+    }
+    assert!(cpu_id < MAX_CPU_NUM);
+    unsafe { &mut *CPUS.with(|cpus| *cpus).add(cpu_id) }
+}
+
 pub fn this_cpu_data<'a>() -> &'a mut PerCpu {
     get_cpu_data(this_cpu_id())
 }
@@ -37,8 +37,25 @@ pub const ROOT_ZONE_IRQS: [u32; 19] = [
 ];
 
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
+    version: HvArchZoneConfig::VERSION,
+    size: core::mem::size_of::<HvArchZoneConfig>() as _,
     gicd_base: 0x38800000,
     gicd_size: 0x10000,
     gicr_base: 0x38880000,
     gicr_size: 0xc0000,
+    irq_depth_limit: 0,
+    gicd_read_passthrough: 0,
+    irq_storm_limit: 0,
+    irq_storm_window_us: 0,
+    irq_storm_cooldown_us: 0,
+    hw_unmapped_irqs: [0; 32],
+    default_spi_priority: 0xa0,
+    gits_base: 0,
+    watchdog_timeout_us: 0,
+    watchdog_irq: 0,
+    virtual_pri_bits: 0,
+    update_pending_priority: 0,
+    irq_audit_entries: 0,
+    offline_irq_policy: 0,
+    trap_group_regs: 0,
 };
@@ -0,0 +1,137 @@
+//! Per-cpu rings of deferred work. Any cpu, in any context, queues an item for a target cpu
+//! and kicks it with the event SGI, the target runs its items from `check_events`.
+//!
+//! Each ring is a bounded multi-producer single-consumer queue after D. Vyukov: producers
+//! claim a slot by advancing `tail`, every slot has a sequence number telling whether it is
+//! free for the producer of a lap or filled for the consumer. Nothing takes a lock.
+#![allow(dead_code)]
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Once;
+
+use crate::{arch::ipi::arch_send_event, hypercall::SGI_IPI_ID};
+
+/// Items one ring holds, a power of two.
+const RING_SIZE: usize = 64;
+
+/// An action the hypervisor runs on another cpu.
+#[derive(Debug, Clone, Copy)]
+pub enum Work {
+    /// Inject a virtual irq into the zone running on the cpu.
+    InjectIrq { irq_id: usize, is_hardware: bool },
+    /// Move the cpu's deferred virtual irqs into its list registers.
+    FlushPendingIrqs,
+    /// Call a function with an argument.
+    Call(fn(usize), usize),
+}
+
+impl Work {
+    fn run(self) {
+        match self {
+            Work::InjectIrq {
+                irq_id,
+                is_hardware,
+            } => crate::device::irqchip::gicv3::inject_irq(irq_id, is_hardware),
+            Work::FlushPendingIrqs => crate::device::irqchip::gicv3::flush_pending_irqs(),
+            Work::Call(f, arg) => f(arg),
+        }
+    }
+}
+
+struct Slot {
+    seq: AtomicUsize,
+    work: UnsafeCell<MaybeUninit<Work>>,
+}
+
+struct WorkRing {
+    slots: Vec<Slot>,
+    tail: AtomicUsize,
+    // only advanced by the cpu owning the ring
+    head: AtomicUsize,
+}
+
+// a slot's work is only accessed by the one producer or the consumer its seq hands it to
+unsafe impl Sync for WorkRing {}
+
+impl WorkRing {
+    fn new() -> Self {
+        Self {
+            slots: (0..RING_SIZE)
+                .map(|i| Slot {
+                    seq: AtomicUsize::new(i),
+                    work: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, work: Work) -> Result<(), Work> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % RING_SIZE];
+            match slot.seq.load(Ordering::Acquire).cmp(&pos) {
+                core::cmp::Ordering::Equal => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.work.get()).write(work) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // the consumer hasn't freed the slot of the last lap yet
+                core::cmp::Ordering::Less => return Err(work),
+                // another producer took the slot
+                core::cmp::Ordering::Greater => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<Work> {
+        let pos = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % RING_SIZE];
+        if slot.seq.load(Ordering::Acquire) != pos + 1 {
+            return None;
+        }
+        let work = unsafe { (*slot.work.get()).assume_init_read() };
+        self.head.store(pos + 1, Ordering::Relaxed);
+        slot.seq.store(pos + RING_SIZE, Ordering::Release);
+        Some(work)
+    }
+}
+
+machine_static! {
+    fn work_rings() -> Once<Vec<WorkRing>> = Once::new();
+}
+
+pub fn init(max_cpus: usize) {
+    work_rings().call_once(|| (0..max_cpus).map(|_| WorkRing::new()).collect());
+}
+
+/// Queue `work` for `cpu` and kick it. Gives the item back if the ring of `cpu` is full.
+pub fn queue_work(cpu: usize, work: Work) -> Result<(), Work> {
+    work_rings().get().unwrap()[cpu].push(work)?;
+    arch_send_event(cpu as _, SGI_IPI_ID);
+    Ok(())
+}
+
+/// Run the work queued for `cpu`, which must be this cpu. Returns how many items ran.
+pub fn drain_work(cpu: usize) -> usize {
+    let ring = &work_rings().get().unwrap()[cpu];
+    let mut done = 0;
+    while let Some(work) = ring.pop() {
+        work.run();
+        done += 1;
+    }
+    done
+}
@@ -7,6 +7,7 @@ use crate::arch::mm::new_s2_memory_set;
 use crate::arch::s2pt::Stage2PageTable;
 use crate::config::HvZoneConfig;
 use crate::consts::MAX_CPU_NUM;
+use crate::device::irqchip::gicv3::vgic::Vgicv3;
 
 use crate::error::HvResult;
 use crate::memory::addr::GuestPhysAddr;
@@ -19,7 +20,10 @@ pub struct Zone {
     pub mmio: Vec<MMIOConfig>,
     pub cpu_set: CpuSet,
     pub irq_bitmap: [u32; 1024 / 32],
+    /// Extended SPIs of the zone, the first bit is INTID 4096.
+    pub espi_bitmap: [u32; 1024 / 32],
     pub gpm: MemorySet<Stage2PageTable>,
+    pub vgicv3: Vgicv3,
 }
 
 impl Zone {
@@ -30,6 +34,8 @@ impl Zone {
             cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
+            espi_bitmap: [0; 1024 / 32],
+            vgicv3: Vgicv3::default(),
         }
     }
 
@@ -102,14 +108,40 @@ impl Zone {
     }
     /// If irq_id belongs to this zone
     pub fn irq_in_zone(&self, irq_id: u32) -> bool {
+        let (bitmap, irq_id) = match irq_id {
+            0..=1023 => (&self.irq_bitmap, irq_id),
+            4096..=5119 => (&self.espi_bitmap, irq_id - 4096),
+            _ => return false,
+        };
         let idx = (irq_id / 32) as usize;
         let bit_pos = (irq_id % 32) as usize;
-        (self.irq_bitmap[idx] & (1 << bit_pos)) != 0
+        (bitmap[idx] & (1 << bit_pos)) != 0
     }
 }
 
+/// Bitmap of the irqs below 1024 `zone` owns, one bit per irq. It is built from the zone config
+/// in `zone_create`, so an ownership check is a single bit test and the ownership of 32 irqs a
+/// single word.
+pub fn zone_spi_bitmap(zone: &Zone) -> &[u32] {
+    &zone.irq_bitmap
+}
+
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
 
+/// Callbacks of a subsystem keeping per-zone state, `on_start` runs once a zone is set up and
+/// before any of its cpus runs, `on_stop` right before the zone is removed.
+#[derive(Clone, Copy)]
+pub struct ZoneHooks {
+    pub on_start: fn(&mut Zone),
+    pub on_stop: fn(&Zone),
+}
+
+static ZONE_HOOKS: RwLock<Vec<ZoneHooks>> = RwLock::new(vec![]);
+
+pub fn register_zone_hooks(hooks: ZoneHooks) {
+    ZONE_HOOKS.write().push(hooks);
+}
+
 pub fn root_zone() -> Arc<RwLock<Zone>> {
     ZONE_LIST.read().get(0).cloned().unwrap()
 }
@@ -132,6 +164,9 @@ pub fn remove_zone(zone_id: usize) {
         .find(|(_, zone)| zone.read().id == zone_id)
         .unwrap();
     let removed_zone = zone_list.remove(idx);
+    for hooks in ZONE_HOOKS.read().iter() {
+        (hooks.on_stop)(&removed_zone.read());
+    }
     assert_eq!(Arc::strong_count(&removed_zone), 1);
 }
 
@@ -143,6 +178,16 @@ pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
         .cloned()
 }
 
+/// Id of the zone `irq_id` belongs to.
+pub fn find_irq_owner(irq_id: u32) -> Option<usize> {
+    ZONE_LIST
+        .read()
+        .iter()
+        .map(|zone| zone.read())
+        .find(|zone| zone.irq_in_zone(irq_id))
+        .map(|zone| zone.id)
+}
+
 pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
@@ -168,11 +213,13 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     if find_zone(zone_id).is_some() {
         return hv_result_err!(EEXIST);
     }
+    config.arch.validate()?;
 
     let mut zone = Zone::new(zone_id);
     zone.pt_init(config.memory_regions()).unwrap();
     zone.mmio_init(&config.arch);
     zone.irq_bitmap_init(config.interrupts());
+    zone.arch_irqchip_init(&config.arch);
 
     config.cpus().iter().for_each(|cpu_id| {
         zone.cpu_set.set_bit(*cpu_id as _);
@@ -195,12 +242,16 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     }
     info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
     let cpu_set = zone.cpu_set;
+    for hooks in ZONE_HOOKS.read().iter() {
+        (hooks.on_start)(&mut zone);
+    }
 
     let new_zone_pointer = Arc::new(RwLock::new(zone));
     {
         cpu_set.iter().for_each(|cpuid| {
             let cpu_data = get_cpu_data(cpuid);
             cpu_data.zone = Some(new_zone_pointer.clone());
+            cpu_data.zone_id = Some(zone_id);
             //chose boot cpu
             if cpuid == cpu_set.first_cpu().unwrap() {
                 cpu_data.boot_cpu = true;